@@ -5,6 +5,7 @@ use holochain_client::ZomeCallTarget;
 use holochain_runtime::*;
 use holochain_types::prelude::*;
 use roles_types::Properties;
+use safehold_types::SafeholdError;
 use setup::setup;
 use std::{fs, path::PathBuf};
 use utils::with_retries;
@@ -146,6 +147,12 @@ impl SafeholdServiceClient {
     }
 }
 
+/// Returns the `SafeholdError` that made a request to the safehold service fail, if any,
+/// to decide whether to retry it
+pub fn safehold_error(error: &anyhow::Error) -> Option<SafeholdError> {
+    SafeholdError::from_error_message(&format!("{error:?}"))
+}
+
 pub async fn read_from_file(happ_bundle_path: &PathBuf) -> Result<AppBundle> {
     let bytes = fs::read(happ_bundle_path)?;
     Ok(AppBundle::decode(bytes.as_slice())?)
@@ -39,7 +39,8 @@
         '';
 
         dnas = {
-          services = inputs'.service-providers.packages.services_dna;
+          # Includes the safehold gateway to receive the notifications for new messages
+          services = self'.packages.services_dna_with_safehold_gateway;
           example = self'.packages.example_dna;
         };
       }).meta.debug;
@@ -5,25 +5,52 @@ use holochain_client::{AdminWebsocket, AppWebsocket};
 use holochain_runtime::*;
 use holochain_types::prelude::*;
 use safehold_clones::reconcile_safehold_clones;
+use safehold_types::{ProviderDelegation, SafeholdQuotas};
 use setup::setup;
-use std::{fs, path::PathBuf, time::Duration};
+use std::{fs, path::PathBuf, sync::Arc, time::Duration};
 use utils::with_retries;
 
-mod safehold_clones;
+pub mod migration;
+pub mod safehold_clones;
 mod setup;
 mod utils;
 
 pub const SERVICES_ROLE_NAME: &'static str = "services";
 
+const RECONCILE_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Period at the beginning of each epoch during which the safehold cell for the previous epoch is kept
+pub const DEFAULT_EPOCH_OVERLAP: Duration = Duration::from_secs(60 * 2); // 2 minutes
+
+/// Issues the delegation, signed by a progenitor, that authorizes the given agent of this provider
+/// to remove the acknowledged messages from the safehold DHT, for the given proxy DNA of the providers
+pub type DelegationIssuer =
+    Arc<dyn Fn(&AgentPubKey, &DnaHash) -> anyhow::Result<ProviderDelegation> + Send + Sync>;
+
 pub async fn run(
     data_dir: PathBuf,
     network_config: NetworkConfig,
     app_id: String,
     safehold_service_provider_happ_path: PathBuf,
     progenitors: Vec<AgentPubKey>,
+    quotas: SafeholdQuotas,
+    epoch_length: Duration,
+    epoch_overlap: Duration,
+    delegation_issuer: Option<DelegationIssuer>,
     mdns_discovery: bool,
     admin_port: Option<u16>,
 ) -> anyhow::Result<()> {
+    if epoch_length.is_zero() {
+        return Err(anyhow!("The epoch length must be at least 1s."));
+    }
+    if epoch_overlap >= epoch_length {
+        return Err(anyhow!(
+            "The epoch overlap ({}s) must be shorter than the epoch length ({}s).",
+            epoch_overlap.as_secs(),
+            epoch_length.as_secs()
+        ));
+    }
+
     let mut config = HolochainRuntimeConfig::new(data_dir.clone(), network_config);
     config.mdns_discovery = mdns_discovery;
     config.admin_port = admin_port;
@@ -31,9 +58,14 @@ pub async fn run(
     let runtime = HolochainRuntime::launch(vec_to_locked(vec![]), config).await?;
     setup(
         &runtime,
+        &data_dir,
         &app_id,
         &safehold_service_provider_happ_path,
         progenitors.clone(),
+        quotas.clone(),
+        epoch_length,
+        epoch_overlap,
+        delegation_issuer.clone(),
     )
     .await?;
 
@@ -64,6 +96,9 @@ pub async fn run(
 
     let r = runtime.clone();
 
+    // Short epochs need to be checked more often to not skip any of them
+    let reconcile_interval = RECONCILE_INTERVAL.min(epoch_length / 2);
+
     let abort_handle = tokio::spawn(async move {
         loop {
             let Ok(app_ws) = runtime
@@ -87,13 +122,22 @@ pub async fn run(
             {
                 log::error!("Failed to reconcile cloned services: {err}");
             }
-            if let Err(err) =
-                reconcile_safehold_clones(&admin_ws, &app_ws, progenitors.clone()).await
+            if let Err(err) = reconcile_safehold_clones(
+                &data_dir,
+                &admin_ws,
+                &app_ws,
+                progenitors.clone(),
+                quotas.clone(),
+                epoch_length,
+                epoch_overlap,
+                delegation_issuer.clone(),
+            )
+            .await
             {
                 log::error!("Failed to reconcile safehold clones: {err}");
             }
 
-            std::thread::sleep(Duration::from_secs(30));
+            std::thread::sleep(reconcile_interval);
         }
     })
     .abort_handle();
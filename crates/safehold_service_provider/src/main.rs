@@ -2,12 +2,17 @@ use anyhow::{anyhow, Result};
 use clap::Parser;
 use env_logger::Builder;
 use holochain::core::AgentPubKeyB64;
+use holochain::prelude::{SerializedBytes, Timestamp, UnsafeBytes};
 use holochain_client::InstalledAppId;
 use holochain_runtime::NetworkConfig;
 use log::Level;
+use safehold_service_provider::{DelegationIssuer, DEFAULT_EPOCH_OVERLAP};
+use safehold_types::{ProviderDelegation, SafeholdQuotas, DEFAULT_EPOCH_LENGTH};
 use std::io::Write;
 use std::path::PathBuf;
 use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
 
 #[derive(Parser, Debug)]
 #[command(version, about, long_about = None)]
@@ -27,6 +32,34 @@ struct Args {
     #[arg(long, required = true, num_args = 1)]
     progenitors: Vec<AgentPubKeyB64>,
 
+    /// Overrides the default quota of messages that one sender can store in each epoch,
+    /// set in the properties of the safehold DNA like the other quotas, so it must be the same for all the providers
+    #[arg(long)]
+    max_messages_per_sender_per_epoch: Option<usize>,
+
+    /// Overrides the default quota of bytes of pending messages for each recipient
+    #[arg(long)]
+    max_mailbox_bytes: Option<usize>,
+
+    /// Overrides the default quota of recipients for each message
+    #[arg(long)]
+    max_recipients_per_message: Option<usize>,
+
+    /// Length in seconds of the epochs after which the providers move over to a new safehold DHT,
+    /// it must be the same for all the providers
+    #[arg(long, default_value_t = DEFAULT_EPOCH_LENGTH.as_secs())]
+    epoch_length_secs: u64,
+
+    /// Seconds at the beginning of each epoch during which the safehold cell for the previous epoch
+    /// is still read from, before its remaining messages are migrated and it is deleted
+    #[arg(long, default_value_t = DEFAULT_EPOCH_OVERLAP.as_secs())]
+    epoch_overlap_secs: u64,
+
+    /// File with the msgpack encoded delegation, signed by a progenitor, for the agent and the proxy DNA
+    /// of this provider, which must be replaced with a new one before it expires
+    #[arg(long)]
+    provider_delegation: Option<PathBuf>,
+
     #[arg(long)]
     bootstrap_url: Option<String>,
 
@@ -55,6 +88,28 @@ fn network_config(bootstrap_url: Option<String>, signal_url: Option<String>) ->
     network_config
 }
 
+fn read_delegation_issuer(path: PathBuf) -> Result<DelegationIssuer> {
+    let bytes = std::fs::read(path)?;
+    let delegation = ProviderDelegation::try_from(SerializedBytes::from(UnsafeBytes::from(bytes)))?;
+
+    Ok(Arc::new(move |agent, proxy_dna_hash| {
+        if delegation.delegate.ne(agent) {
+            return Err(anyhow!(
+                "The given provider delegation is not for this provider's agent {agent}."
+            ));
+        }
+        if delegation.proxy_dna_hash.ne(proxy_dna_hash) {
+            return Err(anyhow!(
+                "The given provider delegation is not for this provider's proxy DNA {proxy_dna_hash}."
+            ));
+        }
+        if delegation.is_expired(Timestamp::now()) {
+            return Err(anyhow!("The given provider delegation has expired."));
+        }
+        Ok(delegation.clone())
+    }))
+}
+
 fn log_level() -> Level {
     match std::env::var("RUST_LOG") {
         Ok(s) => Level::from_str(s.as_str()).expect("Invalid RUST_LOG level"),
@@ -94,12 +149,32 @@ async fn main() -> Result<()> {
         std::fs::create_dir_all(data_dir.clone())?;
     }
 
+    let mut quotas = SafeholdQuotas::default();
+    if let Some(max) = args.max_messages_per_sender_per_epoch {
+        quotas.max_messages_per_sender_per_epoch = max;
+    }
+    if let Some(max) = args.max_mailbox_bytes {
+        quotas.max_mailbox_bytes = max;
+    }
+    if let Some(max) = args.max_recipients_per_message {
+        quotas.max_recipients_per_message = max;
+    }
+
+    let delegation_issuer = match args.provider_delegation {
+        Some(path) => Some(read_delegation_issuer(path)?),
+        None => None,
+    };
+
     safehold_service_provider::run(
         data_dir,
         network_config(args.bootstrap_url, args.signal_url),
         args.app_id,
         args.safehold_service_provider_happ,
         args.progenitors.into_iter().map(|p| p.into()).collect(),
+        quotas,
+        Duration::from_secs(args.epoch_length_secs),
+        Duration::from_secs(args.epoch_overlap_secs),
+        delegation_issuer,
         args.mdns_discovery,
         args.admin_port
     )
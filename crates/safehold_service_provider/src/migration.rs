@@ -0,0 +1,284 @@
+use std::path::{Path, PathBuf};
+
+use holochain::prelude::{AgentPubKey, DnaHash, EntryHash};
+use holochain_client::{AppWebsocket, ClonedCell, ExternIO, ZomeCallTarget};
+use safehold_types::{
+    ExportMessagesCursor, ExportMessagesInput, ExportMessagesPage, ExportedDeliveryReceipts,
+    MigrationCounts,
+};
+use serde::{Deserialize, Serialize};
+
+/// Maximum number of messages exported and imported in each zome call of the migration
+pub const MIGRATION_BATCH_SIZE: usize = 100;
+
+/// Number of mailbox shards whose delivery receipts are exported and imported in each zome call
+pub const RECEIPTS_MIGRATION_SHARDS_BATCH_SIZE: usize = 16;
+
+const MIGRATION_PROGRESS_FILE: &'static str = "safehold_migration_progress.json";
+
+/// Progress of the migration that is being done, persisted after every batch
+/// so that it can be resumed after the provider restarts
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct MigrationProgress {
+    pub from_dna: DnaHash,
+    pub to_dna: DnaHash,
+    /// The shards being migrated by this provider, or all of them if `None`
+    pub shards: Option<Vec<EntryHash>>,
+    pub cursor: Option<ExportMessagesCursor>,
+    pub counts: MigrationCounts,
+    pub expired: usize,
+}
+
+pub fn migration_progress_path(data_dir: &Path) -> PathBuf {
+    data_dir.join(MIGRATION_PROGRESS_FILE)
+}
+
+pub fn read_migration_progress(path: &Path) -> anyhow::Result<Option<MigrationProgress>> {
+    if !path.exists() {
+        return Ok(None);
+    }
+    let bytes = std::fs::read(path)?;
+    Ok(Some(serde_json::from_slice(&bytes)?))
+}
+
+pub fn write_migration_progress(path: &Path, progress: &MigrationProgress) -> anyhow::Result<()> {
+    // Write to a temporary file first so that a crash can't leave a truncated file behind
+    let tmp_path = path.with_extension("tmp");
+    std::fs::write(&tmp_path, serde_json::to_vec(progress)?)?;
+    std::fs::rename(tmp_path, path)?;
+    Ok(())
+}
+
+pub fn clear_migration_progress(path: &Path) -> anyhow::Result<()> {
+    if path.exists() {
+        std::fs::remove_file(path)?;
+    }
+    Ok(())
+}
+
+/// Each mailbox shard of the previous epoch is migrated by only one provider:
+/// the one whose public key is the closest to the hash of the shard in XOR distance
+pub fn responsible_provider<'a>(
+    shard: &EntryHash,
+    providers: &'a [AgentPubKey],
+) -> Option<&'a AgentPubKey> {
+    providers.iter().min_by_key(|provider| {
+        provider
+            .get_raw_32()
+            .iter()
+            .zip(shard.get_raw_32())
+            .map(|(a, b)| a ^ b)
+            .collect::<Vec<u8>>()
+    })
+}
+
+/// Returns the mailbox shards in the given cell that this provider is responsible for migrating,
+/// split among the given providers, which must only be the ones that are still live
+pub async fn responsible_shards(
+    app_ws: &AppWebsocket,
+    cell: &ClonedCell,
+    providers: &[AgentPubKey],
+    my_pub_key: &AgentPubKey,
+) -> anyhow::Result<Vec<EntryHash>> {
+    let shards: Vec<EntryHash> = app_ws
+        .call_zome(
+            ZomeCallTarget::CellId(cell.cell_id.clone()),
+            "safehold".into(),
+            "get_mailbox_shards".into(),
+            ExternIO::encode(())?,
+        )
+        .await?
+        .decode()?;
+    let shards_count = shards.len();
+
+    let my_shards = filter_responsible_shards(shards, providers, my_pub_key);
+
+    log::info!(
+        "This provider is responsible for migrating {} of the {} mailbox shards, split among {} providers.",
+        my_shards.len(),
+        shards_count,
+        providers.len()
+    );
+
+    Ok(my_shards)
+}
+
+/// Keeps only the shards for which the given provider is the responsible one among all the providers
+pub fn filter_responsible_shards(
+    shards: Vec<EntryHash>,
+    providers: &[AgentPubKey],
+    my_pub_key: &AgentPubKey,
+) -> Vec<EntryHash> {
+    shards
+        .into_iter()
+        .filter(|shard| responsible_provider(shard, providers) == Some(my_pub_key))
+        .collect()
+}
+
+/// Migrates the undeleted messages from one cell to the other in batches,
+/// only for the given shards or for all of them if `None`
+///
+/// If an interrupted migration with the same parameters is found in the progress file,
+/// it's resumed from its last cursor
+pub async fn migrate_messages(
+    app_ws: &AppWebsocket,
+    progress_path: &Path,
+    from_cell: &ClonedCell,
+    to_cell: &ClonedCell,
+    shards: Option<Vec<EntryHash>>,
+) -> anyhow::Result<MigrationCounts> {
+    let new_progress = MigrationProgress {
+        from_dna: from_cell.cell_id.dna_hash().clone(),
+        to_dna: to_cell.cell_id.dna_hash().clone(),
+        shards,
+        cursor: None,
+        counts: MigrationCounts::default(),
+        expired: 0,
+    };
+
+    let mut progress = match read_migration_progress(progress_path) {
+        Ok(Some(saved))
+            if saved.from_dna == new_progress.from_dna
+                && saved.to_dna == new_progress.to_dna
+                && saved.shards == new_progress.shards =>
+        {
+            log::info!(
+                "Resuming the interrupted migration of messages, {} already migrated.",
+                saved.counts.migrated
+            );
+            saved
+        }
+        Ok(_) => new_progress,
+        Err(err) => {
+            log::warn!("Failed to read the migration progress, starting over: {err:?}");
+            new_progress
+        }
+    };
+    write_migration_progress(progress_path, &progress)?;
+
+    loop {
+        let page: ExportMessagesPage = app_ws
+            .call_zome(
+                ZomeCallTarget::CellId(from_cell.cell_id.clone()),
+                "safehold".into(),
+                "export_undeleted_messages_page".into(),
+                ExternIO::encode(ExportMessagesInput {
+                    shards: progress.shards.clone(),
+                    cursor: progress.cursor.clone(),
+                    max_count: MIGRATION_BATCH_SIZE,
+                })?,
+            )
+            .await?
+            .decode()?;
+
+        // The expired messages are already left out of the page
+        let messages = page.messages;
+        progress.expired += page.expired;
+
+        if !messages.is_empty() {
+            let counts: MigrationCounts = app_ws
+                .call_zome(
+                    ZomeCallTarget::CellId(to_cell.cell_id.clone()),
+                    "safehold".into(),
+                    "import_messages".into(),
+                    ExternIO::encode(messages)?,
+                )
+                .await?
+                .decode()?;
+            progress.counts.add(&counts);
+        }
+
+        progress.cursor = page.next_cursor;
+
+        if progress.cursor.is_none() {
+            break;
+        }
+
+        write_migration_progress(progress_path, &progress)?;
+        log::info!(
+            "Migration in progress: {} migrated, {} skipped as duplicates, {} failed, {} expired so far.",
+            progress.counts.migrated,
+            progress.counts.skipped_duplicates,
+            progress.counts.failed,
+            progress.expired
+        );
+    }
+
+    let receipts_counts =
+        migrate_delivery_receipts(app_ws, from_cell, to_cell, progress.shards.clone()).await?;
+    log_migration_counts("Migrated the delivery receipts", &receipts_counts);
+
+    clear_migration_progress(progress_path)?;
+
+    log::info!(
+        "Dropped {} expired messages from the old cell.",
+        progress.expired
+    );
+
+    Ok(progress.counts)
+}
+
+/// Migrates the delivery receipts of the messages sent to the given shards, or to all of them if `None`,
+/// since the senders may fetch them after the old cell is deleted
+pub async fn migrate_delivery_receipts(
+    app_ws: &AppWebsocket,
+    from_cell: &ClonedCell,
+    to_cell: &ClonedCell,
+    shards: Option<Vec<EntryHash>>,
+) -> anyhow::Result<MigrationCounts> {
+    let shards = match shards {
+        Some(shards) => shards,
+        None => app_ws
+            .call_zome(
+                ZomeCallTarget::CellId(from_cell.cell_id.clone()),
+                "safehold".into(),
+                "get_mailbox_shards".into(),
+                ExternIO::encode(())?,
+            )
+            .await?
+            .decode()?,
+    };
+
+    let mut counts = MigrationCounts::default();
+
+    for batch in shards.chunks(RECEIPTS_MIGRATION_SHARDS_BATCH_SIZE) {
+        let exported: Vec<ExportedDeliveryReceipts> = app_ws
+            .call_zome(
+                ZomeCallTarget::CellId(from_cell.cell_id.clone()),
+                "safehold".into(),
+                "export_delivery_receipts".into(),
+                ExternIO::encode(batch.to_vec())?,
+            )
+            .await?
+            .decode()?;
+
+        if exported.is_empty() {
+            continue;
+        }
+
+        let batch_counts: MigrationCounts = app_ws
+            .call_zome(
+                ZomeCallTarget::CellId(to_cell.cell_id.clone()),
+                "safehold".into(),
+                "import_delivery_receipts".into(),
+                ExternIO::encode(exported)?,
+            )
+            .await?
+            .decode()?;
+        counts.add(&batch_counts);
+    }
+
+    Ok(counts)
+}
+
+pub fn log_migration_counts(context: &str, counts: &MigrationCounts) {
+    let message = format!(
+        "{context}: {} migrated, {} skipped as duplicates, {} failed.",
+        counts.migrated, counts.skipped_duplicates, counts.failed
+    );
+    if counts.failed > 0 {
+        log::warn!("{message}");
+    } else {
+        log::info!("{message}");
+    }
+}
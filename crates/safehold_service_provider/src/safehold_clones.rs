@@ -1,25 +1,94 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+
 use anyhow::anyhow;
 use holochain::prelude::{
     AgentPubKey, CloneCellId, CreateCloneCellPayload, DeleteCloneCellPayload,
-    DisableCloneCellPayload, DnaModifiersOpt, RoleName, YamlProperties,
+    DisableCloneCellPayload, DnaHash, DnaModifiersOpt, RoleName, SerializedBytes, YamlProperties,
 };
 use holochain_client::{
-    AdminWebsocket, AppWebsocket, CellInfo, ClonedCell, ExternIO, Timestamp, ZomeCallTarget,
+    AdminWebsocket, AppInfo, AppWebsocket, CellInfo, ClonedCell, ExternIO, Timestamp,
+    ZomeCallTarget,
+};
+use safehold_types::{
+    time_epoch, ProviderDelegation, ProviderSettings, SafeholdProperties, SafeholdQuotas,
+};
+
+use crate::migration::{
+    clear_migration_progress, log_migration_counts, migrate_messages, migration_progress_path,
+    read_migration_progress, responsible_shards,
 };
-use roles_types::Properties;
-use safehold_types::MessageWithProvenance;
+use crate::DelegationIssuer;
+
+pub fn safehold_dna_modifiers(
+    progenitors: Vec<AgentPubKey>,
+    epoch_length: Duration,
+    quotas: SafeholdQuotas,
+    proxy_dna_hash: DnaHash,
+    network_seed: String,
+) -> DnaModifiersOpt<YamlProperties> {
+    let safehold_properties =
+        SafeholdProperties::new(progenitors, epoch_length, quotas, proxy_dna_hash);
+    let value = serde_yaml::to_value(safehold_properties).unwrap();
+    let properties_bytes = YamlProperties::new(value);
+
+    DnaModifiersOpt {
+        properties: Some(properties_bytes),
+        network_seed: Some(network_seed),
+    }
+}
+
+/// The provider delegations are scoped to the proxy DNA, which is the same for all the providers
+pub fn proxy_dna_hash(app_info: &AppInfo) -> anyhow::Result<DnaHash> {
+    app_info
+        .cell_info
+        .get("proxy")
+        .and_then(|cells| {
+            cells.iter().find_map(|c| match c {
+                CellInfo::Provisioned(provisioned) => Some(provisioned.cell_id.dna_hash().clone()),
+                _ => None,
+            })
+        })
+        .ok_or(anyhow!("No proxy cell found"))
+}
 
 pub async fn reconcile_safehold_clones(
+    data_dir: &Path,
     admin_ws: &AdminWebsocket,
     app_ws: &AppWebsocket,
     progenitors: Vec<AgentPubKey>,
+    quotas: SafeholdQuotas,
+    epoch_length: Duration,
+    epoch_overlap: Duration,
+    delegation_issuer: Option<DelegationIssuer>,
 ) -> anyhow::Result<()> {
-    let current_network_seed = get_current_time_epoch();
-
     let Some(app_info) = app_ws.app_info().await? else {
         return Err(anyhow!("app_info() returned None"));
     };
 
+    let proxy_dna_hash = proxy_dna_hash(&app_info)?;
+    let delegation = match &delegation_issuer {
+        Some(issue_delegation) => Some(issue_delegation(&app_info.agent_pub_key, &proxy_dna_hash)?),
+        None => None,
+    };
+    let peers = check_provider_settings(
+        app_ws,
+        &app_info.agent_pub_key,
+        &progenitors,
+        epoch_length,
+        &quotas,
+        delegation.clone(),
+    )
+    .await?;
+    let progress_path = migration_progress_path(data_dir);
+
+    let current_network_seed = get_current_time_epoch(epoch_length);
+
+    let mut providers = peers;
+    providers.push(app_info.agent_pub_key.clone());
+
     let safehold_cells = app_info
         .cell_info
         .get("safehold")
@@ -34,82 +103,93 @@ pub async fn reconcile_safehold_clones(
         })
         .collect();
 
-    let already_exists = existing_cloned_cells
+    let existing_current_cell = existing_cloned_cells
         .iter()
         .find(|c| c.enabled && c.dna_modifiers.network_seed.eq(&current_network_seed))
-        .is_some();
-
-    if !already_exists {
-        log::info!("New epoch time reached: deleting the current safehold cell if it exists and creating a new one.");
-
-        let roles_properties = Properties {
-            progenitors: progenitors.clone().into_iter().map(|p| p.into()).collect(),
-        };
-        let value = serde_yaml::to_value(roles_properties).unwrap();
-        let properties_bytes = YamlProperties::new(value);
-
-        let cloned_cell = app_ws
-            .create_clone_cell(CreateCloneCellPayload {
-                role_name: RoleName::from("safehold"),
-                modifiers: DnaModifiersOpt {
-                    properties: Some(properties_bytes.clone()),
-                    network_seed: Some(current_network_seed.clone()),
-                },
-                membrane_proof: None,
-                name: None,
-            })
-            .await?;
+        .cloned();
 
-        app_ws
-            .call_zome(
-                ZomeCallTarget::RoleName("proxy".into()),
-                "proxy".into(),
-                "create_proxied_dna".into(),
-                ExternIO::encode(cloned_cell.cell_id.dna_hash().clone())?,
+    let current_cell = match existing_current_cell {
+        Some(current_cell) => {
+            resume_interrupted_migration(
+                app_ws,
+                &progress_path,
+                &existing_cloned_cells,
+                &current_cell,
             )
             .await?;
+            current_cell
+        }
+        None => {
+            log::info!("New epoch time reached: creating a new safehold cell, the previous one will be deleted after the overlap period.");
 
-        let previous_cell = existing_cloned_cells
-            .iter()
-            .filter(|c| c.enabled)
-            .max_by_key(|c| c.clone_id.as_clone_index());
-        if let Some(previous_cell) = previous_cell {
-            let messages: Vec<MessageWithProvenance> = app_ws
+            let membrane_proof = match delegation {
+                Some(delegation) => Some(Arc::new(SerializedBytes::try_from(delegation)?)),
+                None => {
+                    log::warn!("No delegation for this provider: acknowledged messages won't be removed from the safehold DHT.");
+                    None
+                }
+            };
+
+            let cloned_cell = app_ws
+                .create_clone_cell(CreateCloneCellPayload {
+                    role_name: RoleName::from("safehold"),
+                    modifiers: safehold_dna_modifiers(
+                        progenitors.clone(),
+                        epoch_length,
+                        quotas,
+                        proxy_dna_hash,
+                        current_network_seed.clone(),
+                    ),
+                    membrane_proof,
+                    name: None,
+                })
+                .await?;
+
+            app_ws
                 .call_zome(
-                    ZomeCallTarget::CellId(previous_cell.cell_id.clone()),
-                    "safehold".into(),
-                    "export_undeleted_messages".into(),
-                    ExternIO::encode(())?,
+                    ZomeCallTarget::RoleName("proxy".into()),
+                    "proxy".into(),
+                    "create_proxied_dna".into(),
+                    ExternIO::encode(cloned_cell.cell_id.dna_hash().clone())?,
                 )
-                .await?
-                .decode()?;
+                .await?;
+
+            let previous_cell = existing_cloned_cells
+                .iter()
+                .filter(|c| c.enabled)
+                .max_by_key(|c| c.clone_id.as_clone_index());
+            if let Some(previous_cell) = previous_cell {
+                let shards =
+                    responsible_shards(app_ws, previous_cell, &providers, &app_info.agent_pub_key)
+                        .await?;
+                let counts = migrate_messages(
+                    app_ws,
+                    &progress_path,
+                    previous_cell,
+                    &cloned_cell,
+                    Some(shards),
+                )
+                .await?;
+                log_migration_counts("Migrated the messages for our shards", &counts);
+            }
 
             log::info!(
-                "Migrating {} messages from the old cell to the new one.",
-                messages.len()
+                "Successfully advanced safehold clone epoch, new clone: {}",
+                cloned_cell.clone_id
             );
-
-            let _r: () = app_ws
-                .call_zome(
-                    ZomeCallTarget::CellId(cloned_cell.cell_id.clone()),
-                    "safehold".into(),
-                    "create_messages".into(),
-                    ExternIO::encode(messages)?,
-                )
-                .await?
-                .decode()?;
+            cloned_cell
         }
+    };
 
-        log::info!(
-            "Successfully advanced safehold clone epoch, new clone: {}",
-            cloned_cell.clone_id
-        );
-    }
-
-    // Clean up remaining clones
+    // Clean up the clones for previous epochs once the overlap period is over
     // Without this, whenever there is an error disabling or deleting the clone cell,
     // dangling clones will persist and never get cleaned up
 
+    let now = Timestamp::now();
+    if is_in_epoch_overlap(now, epoch_length, epoch_overlap) {
+        return Ok(());
+    }
+
     let dangling_cells: Vec<ClonedCell> = existing_cloned_cells
         .into_iter()
         .filter(|c| c.enabled && c.dna_modifiers.network_seed.ne(&current_network_seed))
@@ -120,6 +200,34 @@ pub async fn reconcile_safehold_clones(
             "Deleting the safehold clone cell for a previous epoch: {}.",
             dangling_cell.clone_id
         );
+
+        // Final sweep for the messages stored in the old epoch after the first migration
+        // The shards are split again among the providers that are still live,
+        // so that the shards of the providers that went offline are also covered
+        let shards =
+            responsible_shards(app_ws, &dangling_cell, &providers, &app_info.agent_pub_key).await?;
+        let counts = migrate_messages(
+            app_ws,
+            &progress_path,
+            &dangling_cell,
+            &current_cell,
+            Some(shards),
+        )
+        .await?;
+        log_migration_counts(
+            "Final sweep of the messages from the previous epoch",
+            &counts,
+        );
+
+        app_ws
+            .call_zome(
+                ZomeCallTarget::RoleName("proxy".into()),
+                "proxy".into(),
+                "retire_proxied_dna".into(),
+                ExternIO::encode(dangling_cell.cell_id.dna_hash().clone())?,
+            )
+            .await?;
+
         app_ws
             .disable_clone_cell(DisableCloneCellPayload {
                 clone_cell_id: CloneCellId::CloneId(dangling_cell.clone_id.clone()),
@@ -134,16 +242,231 @@ pub async fn reconcile_safehold_clones(
             .await?;
     }
 
+    retire_stale_proxied_dnas(app_ws, &current_cell).await?;
+
     Ok(())
 }
 
-const TIME_EPOCH_MINUTES: i64 = 10; // Every 10 minutes go over to another DHT
+/// Whether the given time is in the period at the beginning of an epoch
+/// during which the cell for the previous epoch is still active
+pub fn is_in_epoch_overlap(
+    now: Timestamp,
+    epoch_length: Duration,
+    epoch_overlap: Duration,
+) -> bool {
+    let epoch_length_millis = (epoch_length.as_millis() as i64).max(1);
+    let time_in_epoch = now.as_millis() % epoch_length_millis;
+    time_in_epoch < epoch_overlap.as_millis() as i64
+}
+
+/// Finishes the migration to the current cell that was interrupted by a restart of the provider,
+/// if the cell it was migrating from still exists
+async fn resume_interrupted_migration(
+    app_ws: &AppWebsocket,
+    progress_path: &Path,
+    existing_cloned_cells: &[ClonedCell],
+    current_cell: &ClonedCell,
+) -> anyhow::Result<()> {
+    let Some(progress) = read_migration_progress(progress_path)? else {
+        return Ok(());
+    };
 
-pub fn get_current_time_epoch() -> String {
-    let timestamp = Timestamp::now();
-    let minutes = timestamp.as_millis() / 1000 / 60;
+    let from_cell = existing_cloned_cells
+        .iter()
+        .find(|c| c.enabled && c.cell_id.dna_hash().eq(&progress.from_dna));
+
+    let Some(from_cell) = from_cell else {
+        clear_migration_progress(progress_path)?;
+        return Ok(());
+    };
+    if progress.to_dna.ne(current_cell.cell_id.dna_hash()) {
+        clear_migration_progress(progress_path)?;
+        return Ok(());
+    }
+
+    let counts = migrate_messages(
+        app_ws,
+        progress_path,
+        from_cell,
+        current_cell,
+        progress.shards,
+    )
+    .await?;
+    log_migration_counts("Resumed the interrupted migration", &counts);
+
+    Ok(())
+}
+
+/// Retires the proxied DNAs whose safehold cells have already been deleted
+async fn retire_stale_proxied_dnas(
+    app_ws: &AppWebsocket,
+    current_cell: &ClonedCell,
+) -> anyhow::Result<()> {
+    let proxied_dnas: Vec<DnaHash> = app_ws
+        .call_zome(
+            ZomeCallTarget::RoleName("proxy".into()),
+            "proxy".into(),
+            "query_proxied_dnas".into(),
+            ExternIO::encode(())?,
+        )
+        .await?
+        .decode()?;
+
+    for proxied_dna in proxied_dnas {
+        if proxied_dna.eq(current_cell.cell_id.dna_hash()) {
+            continue;
+        }
+        app_ws
+            .call_zome(
+                ZomeCallTarget::RoleName("proxy".into()),
+                "proxy".into(),
+                "retire_proxied_dna".into(),
+                ExternIO::encode(proxied_dna)?,
+            )
+            .await?;
+    }
+
+    Ok(())
+}
+
+/// Publishes the epoch length of this provider, and fails if the other providers agreed on a different one,
+/// since they would be moving over to different safehold DHTs
+///
+/// The epoch length of the progenitors that run as providers prevails, and otherwise the one of most providers,
+/// so that a single misconfigured provider doesn't stop the rest of them from moving over to new epochs
+///
+/// Returns the other live providers that use the same epoch length
+async fn check_provider_settings(
+    app_ws: &AppWebsocket,
+    my_pub_key: &AgentPubKey,
+    progenitors: &[AgentPubKey],
+    epoch_length: Duration,
+    quotas: &SafeholdQuotas,
+    delegation: Option<ProviderDelegation>,
+) -> anyhow::Result<Vec<AgentPubKey>> {
+    let provider_settings = ProviderSettings {
+        epoch_length_secs: epoch_length.as_secs(),
+        quotas: quotas.clone(),
+        delegation,
+    };
+    let is_progenitor = progenitors.contains(my_pub_key);
+
+    // Only progenitors and delegated providers can publish their settings
+    let peers_settings: BTreeMap<AgentPubKey, ProviderSettings> =
+        if provider_settings.delegation.is_some() || is_progenitor {
+            app_ws
+                .call_zome(
+                    ZomeCallTarget::RoleName("proxy".into()),
+                    "proxy".into(),
+                    "announce_provider_settings".into(),
+                    ExternIO::encode(provider_settings)?,
+                )
+                .await?
+                .decode()?
+        } else {
+            app_ws
+                .call_zome(
+                    ZomeCallTarget::RoleName("proxy".into()),
+                    "proxy".into(),
+                    "get_providers_settings".into(),
+                    ExternIO::encode(())?,
+                )
+                .await?
+                .decode()?
+        };
+
+    let mut epoch_lengths: Vec<(AgentPubKey, u64)> = vec![];
+    let mut peers_quotas: Vec<(AgentPubKey, SafeholdQuotas)> = vec![];
+    for (peer, settings) in &peers_settings {
+        epoch_lengths.push((peer.clone(), settings.epoch_length_secs));
+        peers_quotas.push((peer.clone(), settings.quotas.clone()));
+    }
+    epoch_lengths.push((my_pub_key.clone(), epoch_length.as_secs()));
+    peers_quotas.push((my_pub_key.clone(), quotas.clone()));
+
+    let agreed_epoch_length_secs = agreed_settings(&epoch_lengths, progenitors);
+
+    if !agreed_epoch_length_secs.contains(&epoch_length.as_secs()) {
+        return Err(anyhow!(
+            "The epoch length of this provider ({}s) doesn't match the epoch length that the other providers agreed on ({}s): restart it with the same epoch length as its peers.",
+            epoch_length.as_secs(),
+            agreed_epoch_length_secs
+                .iter()
+                .map(|secs| secs.to_string())
+                .collect::<Vec<String>>()
+                .join("s or ")
+        ));
+    }
+
+    // The quotas are part of the safehold DNA hash, so providers with other quotas are in another safehold DHT
+    let agreed_quotas = agreed_settings(&peers_quotas, progenitors);
+
+    if !agreed_quotas.contains(quotas) {
+        return Err(anyhow!(
+            "The quotas of this provider ({quotas:?}) don't match the quotas that the other providers agreed on ({agreed_quotas:?}): restart it with the same quotas as its peers."
+        ));
+    }
+
+    let mut peers: Vec<AgentPubKey> = vec![];
+    for (peer, settings) in peers_settings {
+        if peer.eq(my_pub_key) {
+            continue;
+        }
+        if settings.epoch_length_secs != epoch_length.as_secs() {
+            log::warn!(
+                "Provider {peer} uses a different epoch length ({}s) than the one agreed on ({}s): ignoring it.",
+                settings.epoch_length_secs,
+                epoch_length.as_secs()
+            );
+            continue;
+        }
+        if settings.quotas.ne(quotas) {
+            log::warn!(
+                "Provider {peer} uses different quotas ({:?}) than the ones agreed on ({quotas:?}): ignoring it.",
+                settings.quotas
+            );
+            continue;
+        }
+        peers.push(peer);
+    }
+
+    Ok(peers)
+}
+
+/// Returns the settings used by the most progenitors, or by the most providers if no progenitor
+/// is running as a provider, more than one if they are tied
+pub fn agreed_settings<T: Ord + Clone>(
+    settings: &[(AgentPubKey, T)],
+    progenitors: &[AgentPubKey],
+) -> BTreeSet<T> {
+    let progenitors_settings: Vec<T> = settings
+        .iter()
+        .filter(|(provider, _)| progenitors.contains(provider))
+        .map(|(_, setting)| setting.clone())
+        .collect();
+    let votes: Vec<T> = match progenitors_settings.is_empty() {
+        true => settings
+            .iter()
+            .map(|(_, setting)| setting.clone())
+            .collect(),
+        false => progenitors_settings,
+    };
+
+    let mut counts: BTreeMap<T, usize> = BTreeMap::new();
+    for setting in votes {
+        *counts.entry(setting).or_default() += 1;
+    }
+    let max_count = counts.values().max().cloned().unwrap_or_default();
+
+    counts
+        .into_iter()
+        .filter(|(_, count)| *count == max_count)
+        .map(|(setting, _)| setting)
+        .collect()
+}
 
-    let epoch = minutes / TIME_EPOCH_MINUTES;
+pub fn get_current_time_epoch(epoch_length: Duration) -> String {
+    let epoch = time_epoch(Timestamp::now(), epoch_length);
 
     format!("{epoch}")
 }
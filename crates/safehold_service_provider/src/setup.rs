@@ -1,17 +1,28 @@
 use std::path::PathBuf;
+use std::time::Duration;
 
 use holochain::prelude::{DnaModifiersOpt, RoleSettings, RoleSettingsMap, YamlProperties};
 use holochain_client::{AgentPubKey, ExternIO, ZomeCallTarget};
 use holochain_runtime::HolochainRuntime;
 use roles_types::Properties;
+use safehold_types::SafeholdQuotas;
 
-use crate::{read_from_file, safehold_clones::reconcile_safehold_clones, SERVICES_ROLE_NAME};
+use crate::{
+    read_from_file,
+    safehold_clones::{proxy_dna_hash, reconcile_safehold_clones},
+    DelegationIssuer, SERVICES_ROLE_NAME,
+};
 
 pub async fn setup(
     runtime: &HolochainRuntime,
+    data_dir: &PathBuf,
     app_id: &String,
     safehold_service_provider_happ_path: &PathBuf,
     progenitors: Vec<AgentPubKey>,
+    quotas: SafeholdQuotas,
+    epoch_length: Duration,
+    epoch_overlap: Duration,
+    delegation_issuer: Option<DelegationIssuer>,
 ) -> anyhow::Result<()> {
     let admin_ws = runtime.admin_websocket().await?;
     let installed_apps = admin_ws.list_apps(None).await?;
@@ -91,9 +102,25 @@ pub async fn setup(
             )
             .await?;
 
-        reconcile_safehold_clones(&admin_ws, &app_ws, progenitors).await?;
-
         log::info!("Installed app {app_info:?}");
+
+        log::info!(
+            "Safehold provider agent, to be delegated by a progenitor: {} in the proxy DNA {}",
+            app_info.agent_pub_key,
+            proxy_dna_hash(&app_info)?
+        );
+
+        reconcile_safehold_clones(
+            data_dir,
+            &admin_ws,
+            &app_ws,
+            progenitors,
+            quotas,
+            epoch_length,
+            epoch_overlap,
+            delegation_issuer,
+        )
+        .await?;
     }
 
     Ok(())
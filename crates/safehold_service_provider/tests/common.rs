@@ -1,16 +1,24 @@
 use std::path::PathBuf;
+use std::sync::Arc;
 use std::{io::Write, time::Duration};
 
 use anyhow::anyhow;
+use ed25519_dalek::{Signer, SigningKey};
 use env_logger::Builder;
-use holo_hash::fixt::AgentPubKeyFixturator;
-use holochain::prelude::{DnaModifiersOpt, RoleSettings, RoleSettingsMap, YamlProperties};
-use holochain_client::{AgentPubKey, AppWebsocket};
+use holochain::prelude::{
+    DnaHash, DnaModifiersOpt, RoleSettings, RoleSettingsMap, SerializedBytes, Signature, Timestamp,
+    YamlProperties,
+};
+use holochain_client::{AgentPubKey, AppWebsocket, CellInfo, ExternIO};
 use holochain_runtime::{vec_to_locked, HolochainRuntime, HolochainRuntimeConfig, NetworkConfig};
 use kitsune2_bootstrap_srv::BootstrapSrv;
 use log::Level;
 use roles_types::Properties;
-use safehold_service_provider::read_from_file;
+use safehold_service_provider::{read_from_file, DelegationIssuer, DEFAULT_EPOCH_OVERLAP};
+use safehold_types::{
+    Message, MessageWithProvenance, ProviderDelegation, SafeholdQuotas, SenderDelegation,
+    DEFAULT_EPOCH_LENGTH,
+};
 use url2::url2;
 
 pub fn service_provider_happ_path() -> PathBuf {
@@ -109,6 +117,105 @@ pub async fn launch(
     (app_ws, runtime)
 }
 
+/// Progenitor whose private key is held by the test, to be able to issue delegations
+#[derive(Clone)]
+pub struct Progenitor {
+    signing_key: SigningKey,
+}
+
+impl Progenitor {
+    pub fn new() -> Self {
+        Self {
+            signing_key: SigningKey::generate(&mut rand::rngs::OsRng),
+        }
+    }
+
+    pub fn agent_pub_key(&self) -> AgentPubKey {
+        AgentPubKey::from_raw_32(self.signing_key.verifying_key().to_bytes().to_vec())
+    }
+
+    fn sign(&self, data: &[u8]) -> Signature {
+        Signature(self.signing_key.sign(data).to_bytes())
+    }
+
+    pub fn delegate_provider(
+        &self,
+        delegate: &AgentPubKey,
+        proxy_dna_hash: &DnaHash,
+    ) -> ProviderDelegation {
+        let expires_at = delegation_expiry();
+        ProviderDelegation {
+            delegator: self.agent_pub_key(),
+            delegate: delegate.clone(),
+            proxy_dna_hash: proxy_dna_hash.clone(),
+            expires_at,
+            signature: self.sign(&ProviderDelegation::signed_data(
+                delegate,
+                proxy_dna_hash,
+                expires_at,
+            )),
+        }
+    }
+
+    pub fn delegate_sender(
+        &self,
+        delegate: &AgentPubKey,
+        services_dna_hash: &DnaHash,
+    ) -> SenderDelegation {
+        let expires_at = delegation_expiry();
+        SenderDelegation {
+            delegator: self.agent_pub_key(),
+            delegate: delegate.clone(),
+            services_dna_hash: services_dna_hash.clone(),
+            expires_at,
+            signature: self.sign(&SenderDelegation::signed_data(
+                delegate,
+                services_dna_hash,
+                expires_at,
+            )),
+        }
+    }
+
+    /// Signs the message as its provenance, the same way as the example zome does
+    pub fn sign_message(&self, message: Message) -> MessageWithProvenance {
+        let bytes = SerializedBytes::try_from(message.clone()).unwrap();
+        let hash = holo_hash::encode::blake2b_256(bytes.bytes());
+        let signature = self.sign(&ExternIO::encode(hash).unwrap().into_vec());
+        MessageWithProvenance {
+            provenance: self.agent_pub_key(),
+            signature,
+            message,
+            delegation: None,
+        }
+    }
+
+    pub fn delegation_issuer(&self) -> DelegationIssuer {
+        let progenitor = self.clone();
+        Arc::new(move |agent, proxy_dna_hash| {
+            Ok(progenitor.delegate_provider(agent, proxy_dna_hash))
+        })
+    }
+}
+
+pub fn delegation_expiry() -> Timestamp {
+    (Timestamp::now() + Duration::from_secs(60 * 60 * 24)).unwrap()
+}
+
+/// Hash of the DNA of the provisioned cell for the given role
+pub async fn provisioned_dna_hash(app_ws: &AppWebsocket, role: &str) -> DnaHash {
+    let app_info = app_ws.app_info().await.unwrap().unwrap();
+    app_info
+        .cell_info
+        .get(role)
+        .unwrap()
+        .iter()
+        .find_map(|cell| match cell {
+            CellInfo::Provisioned(provisioned) => Some(provisioned.cell_id.dna_hash().clone()),
+            _ => None,
+        })
+        .unwrap()
+}
+
 pub struct Scenario {
     pub alice: (AppWebsocket, HolochainRuntime),
     pub bob: (AppWebsocket, HolochainRuntime),
@@ -119,6 +226,18 @@ pub struct Scenario {
 }
 
 pub async fn setup() -> Scenario {
+    setup_with_quotas(SafeholdQuotas::default()).await
+}
+
+pub async fn setup_with_quotas(quotas: SafeholdQuotas) -> Scenario {
+    setup_with_config(quotas, DEFAULT_EPOCH_LENGTH, DEFAULT_EPOCH_OVERLAP).await
+}
+
+pub async fn setup_with_config(
+    quotas: SafeholdQuotas,
+    epoch_length: Duration,
+    epoch_overlap: Duration,
+) -> Scenario {
     let _ = rustls::crypto::aws_lc_rs::default_provider().install_default();
 
     let _ = Builder::new()
@@ -136,8 +255,11 @@ pub async fn setup() -> Scenario {
     let bootstrap_srv = run_bootstrap_server().await;
     let network_config = network_config(&bootstrap_srv);
 
-    let infra_provider_pubkey = fixt::fixt!(AgentPubKey);
+    let progenitor = Progenitor::new();
+    let infra_provider_pubkey = progenitor.agent_pub_key();
     let pubkey = infra_provider_pubkey.clone();
+    let delegation_issuer = progenitor.delegation_issuer();
+    let provider_quotas = quotas.clone();
 
     let tmp = tempdir::TempDir::new("test").unwrap();
     let path = tmp.into_path();
@@ -150,6 +272,10 @@ pub async fn setup() -> Scenario {
             String::from("test-app"),
             service_provider_happ_path(),
             vec![pubkey.clone()],
+            provider_quotas,
+            epoch_length,
+            epoch_overlap,
+            Some(delegation_issuer),
             false,
             None
         )
@@ -160,6 +286,7 @@ pub async fn setup() -> Scenario {
     let tmp = tempdir::TempDir::new("test2").unwrap();
     let path = tmp.into_path();
     let pubkey = infra_provider_pubkey.clone();
+    let delegation_issuer = progenitor.delegation_issuer();
     let nc = network_config.clone();
     tokio::spawn(async move {
         safehold_service_provider::run(
@@ -168,6 +295,10 @@ pub async fn setup() -> Scenario {
             String::from("test-app"),
             service_provider_happ_path(),
             vec![pubkey.clone()],
+            quotas,
+            epoch_length,
+            epoch_overlap,
+            Some(delegation_issuer),
             false,
             None
         )
@@ -1,15 +1,33 @@
-use std::time::Duration;
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
 mod common;
 use anyhow::anyhow;
 use common::*;
+use holochain::prelude::{
+    ActionHash, CreateCloneCellPayload, DnaHash, EntryHash, Signal, Timestamp, X25519PubKey,
+};
 use holochain_client::{AgentPubKey, AppWebsocket, ExternIO, ZomeCallTarget};
-use safehold_service_client::SafeholdServiceClient;
+use safehold_service_client::{safehold_error, SafeholdServiceClient};
+use safehold_service_provider::migration::{
+    filter_responsible_shards, migrate_messages, migration_progress_path, read_migration_progress,
+    write_migration_progress, MigrationProgress,
+};
+use safehold_service_provider::safehold_clones::{
+    agreed_settings, get_current_time_epoch, safehold_dna_modifiers,
+};
 use safehold_service_provider::SERVICES_ROLE_NAME;
-use safehold_service_trait::MessageOutput;
+use safehold_service_trait::{GetMessagesPageInput, MessageOutput, MessagesPage};
 use safehold_types::{
-    DecryptedMessageOutput, EncryptMessageInput, MessageContents, MessageWithProvenance,
+    epoch_start, time_epoch, AckMessagesInput, CreateGroupInput, DecryptedMessageOutput,
+    EncryptGroupMessageInput, EncryptMessageInput, ExportMessagesInput, ExportMessagesPage,
+    ExportedMessage, GetDeliveredRecipientsInput, IncompleteMessageOutput, MessageContents,
+    MessageWithProvenance, MigrationCounts, QuotaExceeded, SafeholdError, SafeholdNotification,
+    SafeholdQuotas, SenderDelegation, SignedDeliveryReceipt, UpdateGroupMembersInput,
+    DEFAULT_EPOCH_LENGTH,
 };
+use serde::{Deserialize, Serialize};
 use serial_test::serial;
 use service_providers_utils::make_service_request;
 use tempdir::TempDir;
@@ -41,6 +59,9 @@ async fn store_and_get_messages() {
     client.create_clone_request(network_seed).await.unwrap();
 
     wait_for_providers(&alice.0).await.unwrap();
+    wait_for_pre_keys(&alice.0, vec![&bob.0, &carol.0])
+        .await
+        .unwrap();
 
     let message_content: Vec<u8> = vec![0; 10];
     let messages: Vec<MessageWithProvenance> = send_message(
@@ -51,7 +72,8 @@ async fn store_and_get_messages() {
     .await
     .unwrap();
 
-    assert_eq!(messages.len(), 2);
+    // Only one message is necessary because the recipients have published their pre keys
+    assert_eq!(messages.len(), 1);
 
     wait_for_providers(&bob.0).await.unwrap();
 
@@ -76,7 +98,7 @@ async fn store_and_get_messages() {
     .await
     .unwrap();
 
-    assert_eq!(messages.len(), 2);
+    assert_eq!(messages.len(), 1);
 
     std::thread::sleep(Duration::from_secs(2));
 
@@ -93,7 +115,6 @@ async fn store_and_get_messages() {
     .await
     .unwrap();
 
-    // Now only one message is necessary because players exchanged X25519 keys
     assert_eq!(messages.len(), 1);
 }
 
@@ -122,6 +143,7 @@ async fn wait_for_providers(app_ws: &AppWebsocket) -> anyhow::Result<()> {
     .await
 }
 
+/// Small chunk size for the tests to split messages into several chunks
 const CHUNK_SIZE: usize = 2_000;
 
 #[tokio::test(flavor = "multi_thread")]
@@ -151,6 +173,9 @@ async fn store_and_get_big_messages_in_chunks() {
     client.create_clone_request(network_seed).await.unwrap();
 
     wait_for_providers(&alice.0).await.unwrap();
+    wait_for_pre_keys(&alice.0, vec![&bob.0, &carol.0])
+        .await
+        .unwrap();
 
     let message_content: Vec<u8> = vec![0; CHUNK_SIZE * 2];
     let messages: Vec<MessageWithProvenance> = send_message(
@@ -161,7 +186,8 @@ async fn store_and_get_big_messages_in_chunks() {
     .await
     .unwrap();
 
-    assert_eq!(messages.len(), 4);
+    // One message per chunk, shared by all the recipients
+    assert_eq!(messages.len(), 2);
 
     std::thread::sleep(Duration::from_secs(4));
 
@@ -186,7 +212,7 @@ async fn store_and_get_big_messages_in_chunks() {
     .await
     .unwrap();
 
-    assert_eq!(messages.len(), 4);
+    assert_eq!(messages.len(), 2);
 
     std::thread::sleep(Duration::from_secs(5));
 
@@ -203,60 +229,2657 @@ async fn store_and_get_big_messages_in_chunks() {
     .await
     .unwrap();
 
-    // Now only two messages are necessary because players exchanged X25519 keys
     assert_eq!(messages.len(), 2);
-}
 
-async fn send_message(
-    app_ws: &AppWebsocket,
-    recipients: Vec<AgentPubKey>,
-    message: MessageContents,
-) -> anyhow::Result<Vec<MessageWithProvenance>> {
-    let safehold_service_trait_service_id = safehold_service_trait::SAFEHOLD_SERVICE_HASH.to_vec();
-    let messages: Vec<MessageWithProvenance> = app_ws
+    // Benchmark a bigger message, whose secret is exported only once for each recipient
+    let bob_session = get_peer_session(&alice.0, &bob.0.my_pub_key)
+        .await
+        .unwrap()
+        .unwrap();
+    let carol_session = get_peer_session(&alice.0, &carol.0.my_pub_key)
+        .await
+        .unwrap()
+        .unwrap();
+
+    let chunks_count = 100;
+    let big_message_content: Vec<u8> = vec![1; CHUNK_SIZE * chunks_count];
+
+    let start = Instant::now();
+    let messages = encrypt_message(
+        &alice.0,
+        vec![bob.0.my_pub_key.clone(), carol.0.my_pub_key.clone()],
+        big_message_content.clone(),
+    )
+    .await
+    .unwrap();
+    let encrypt_duration = start.elapsed();
+    assert_eq!(messages.len(), chunks_count);
+
+    // Each session advanced once for the whole message, and all of them announce the same new key
+    let new_bob_session = get_peer_session(&alice.0, &bob.0.my_pub_key)
+        .await
+        .unwrap()
+        .unwrap();
+    let new_carol_session = get_peer_session(&alice.0, &carol.0.my_pub_key)
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(new_bob_session.sent_messages, bob_session.sent_messages + 1);
+    assert_eq!(
+        new_carol_session.sent_messages,
+        carol_session.sent_messages + 1
+    );
+    assert!(new_bob_session.my_current_key.is_some());
+    assert_eq!(
+        new_bob_session.my_current_key,
+        new_carol_session.my_current_key
+    );
+
+    // Only the first chunk carries the exported secrets
+    assert!(messages[0]
+        .message
+        .recipients
+        .values()
+        .all(|contents| !contents.is_empty()));
+    assert!(messages[1..].iter().all(|m| m
+        .message
+        .recipients
+        .values()
+        .all(|contents| contents.is_empty())));
+
+    let start = Instant::now();
+    let _response: () = make_service_request(
+        &alice.0,
+        safehold_service_trait::SAFEHOLD_SERVICE_HASH.to_vec(),
+        "store_messages".into(),
+        messages,
+    )
+    .await
+    .unwrap();
+    let store_duration = start.elapsed();
+
+    let start = Instant::now();
+    let decrypted_messages = with_retries(
+        async || {
+            let decrypted_messages = receive_messages(&bob.0).await?;
+            if decrypted_messages.is_empty() {
+                return Err(anyhow!("Not all chunks were received yet"));
+            }
+            Ok(decrypted_messages)
+        },
+        30,
+    )
+    .await
+    .unwrap();
+    let receive_duration = start.elapsed();
+    assert_eq!(decrypted_messages.len(), 1);
+    assert_eq!(decrypted_messages[0].contents, big_message_content);
+
+    println!(
+        "Message of {} bytes in {chunks_count} chunks for 2 recipients: encrypted in {encrypt_duration:?}, stored in {store_duration:?}, received and decrypted in {receive_duration:?}.",
+        big_message_content.len()
+    );
+
+    // With the default chunk size the same message takes as few messages as the maximum message size allows
+    let start = Instant::now();
+    let messages: Vec<MessageWithProvenance> = alice
+        .0
         .call_zome(
             ZomeCallTarget::RoleName("example".into()),
             "encrypted_messages".into(),
             "encrypt_message".into(),
             ExternIO::encode(EncryptMessageInput {
-                recipients,
-                message,
+                recipients: vec![bob.0.my_pub_key.clone(), carol.0.my_pub_key.clone()],
+                message: big_message_content.clone(),
+                ttl: None,
+                delivery_receipt: false,
+                chunk_size: None,
+                group_mode: false,
+                sealed_sender: false,
             })
             .unwrap(),
         )
-        .await?
-        .decode()?;
+        .await
+        .unwrap()
+        .decode()
+        .unwrap();
+    let encrypt_duration = start.elapsed();
+    let max_chunk_size: usize = alice
+        .0
+        .call_zome(
+            ZomeCallTarget::RoleName("example".into()),
+            "encrypted_messages".into(),
+            "get_max_chunk_size".into(),
+            ExternIO::encode(2_usize).unwrap(),
+        )
+        .await
+        .unwrap()
+        .decode()
+        .unwrap();
+    assert_eq!(
+        messages.len(),
+        big_message_content.len().div_ceil(max_chunk_size)
+    );
 
-    let _response: () = make_service_request(
-        &app_ws,
-        safehold_service_trait_service_id.clone(),
-        "store_messages".into(),
-        messages.clone(),
-    )
-    .await?;
+    let bob_session = get_peer_session(&alice.0, &bob.0.my_pub_key)
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(bob_session.sent_messages, new_bob_session.sent_messages + 1);
 
-    Ok(messages)
+    println!("Same message with the default chunk size: encrypted in {encrypt_duration:?}.");
 }
 
-async fn receive_messages(app_ws: &AppWebsocket) -> anyhow::Result<Vec<DecryptedMessageOutput>> {
+#[tokio::test(flavor = "multi_thread")]
+#[serial]
+async fn redelivered_messages_are_decrypted_once() {
+    let Scenario {
+        network_seed,
+        progenitor,
+        alice,
+        bob,
+        carol: _carol,
+        bootstrap_srv,
+    } = setup().await;
+
+    let client = SafeholdServiceClient::create(
+        TempDir::new("safehold-service-test").unwrap().into_path(),
+        network_config(&bootstrap_srv),
+        "client-happ".into(),
+        client_happ_path(),
+        vec![progenitor.clone()],
+        false
+    )
+    .await
+    .unwrap();
+
+    client.create_clone_request(network_seed).await.unwrap();
+
+    wait_for_providers(&alice.0).await.unwrap();
+
+    let message_content: Vec<u8> = vec![0; CHUNK_SIZE * 2];
+    send_message(&alice.0, vec![bob.0.my_pub_key.clone()], message_content.clone())
+        .await
+        .unwrap();
+
+    wait_for_providers(&bob.0).await.unwrap();
+
     let safehold_service_trait_service_id = safehold_service_trait::SAFEHOLD_SERVICE_HASH.to_vec();
-    let messages_outputs: Vec<MessageOutput> = make_service_request(
-        app_ws,
-        safehold_service_trait_service_id.clone(),
-        "get_messages".into(),
-        (),
+    let messages_outputs: Vec<MessageOutput> = with_retries(
+        async || {
+            let messages_outputs: Vec<MessageOutput> = make_service_request(
+                &bob.0,
+                safehold_service_trait_service_id.clone(),
+                "get_messages".into(),
+                (),
+            )
+            .await?;
+            if messages_outputs.len() != 2 {
+                return Err(anyhow!("Not all chunks are stored yet"));
+            }
+            Ok(messages_outputs)
+        },
+        10,
     )
-    .await?;
+    .await
+    .unwrap();
 
-    let decrypted_messages: Vec<DecryptedMessageOutput> = app_ws
+    // The same batch delivered twice, and again in a later call
+    let batch: Vec<MessageOutput> = messages_outputs
+        .iter()
+        .chain(messages_outputs.iter())
+        .cloned()
+        .collect();
+
+    let decrypted_messages: Vec<DecryptedMessageOutput> = bob
+        .0
         .call_zome(
             ZomeCallTarget::RoleName("example".into()),
             "encrypted_messages".into(),
             "decrypt_messages".into(),
-            ExternIO::encode(messages_outputs).unwrap(),
+            ExternIO::encode(batch.clone()).unwrap(),
         )
-        .await?
-        .decode()?;
+        .await
+        .unwrap()
+        .decode()
+        .unwrap();
+    assert_eq!(decrypted_messages.len(), 1);
+    assert_eq!(decrypted_messages[0].contents, message_content);
 
-    Ok(decrypted_messages)
+    let decrypted_messages: Vec<DecryptedMessageOutput> = bob
+        .0
+        .call_zome(
+            ZomeCallTarget::RoleName("example".into()),
+            "encrypted_messages".into(),
+            "decrypt_messages".into(),
+            ExternIO::encode(batch).unwrap(),
+        )
+        .await
+        .unwrap()
+        .decode()
+        .unwrap();
+    assert_eq!(decrypted_messages.len(), 0);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+#[serial]
+async fn ratchet_sessions_handle_out_of_order_and_missing_messages() {
+    let Scenario {
+        alice,
+        bob,
+        bootstrap_srv: _bootstrap_srv,
+        ..
+    } = setup().await;
+
+    wait_for_pre_keys(&alice.0, vec![&bob.0]).await.unwrap();
+
+    let mut outputs: Vec<Vec<MessageOutput>> = vec![];
+    for i in 0..3 {
+        let messages = encrypt_message(&alice.0, vec![bob.0.my_pub_key.clone()], vec![i; 10])
+            .await
+            .unwrap();
+        outputs.push(
+            outputs_for_recipient(&alice.0, messages, &bob.0.my_pub_key)
+                .await
+                .unwrap(),
+        );
+    }
+
+    // The key that alice announced in her latest message
+    let alice_session = get_peer_session(&alice.0, &bob.0.my_pub_key)
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(alice_session.sent_messages, 3);
+    let alice_latest_key = alice_session.my_current_key.unwrap();
+
+    let bob_pre_key = get_pre_key(&bob.0, bob.0.my_pub_key.clone()).await.unwrap();
+
+    // The third message arrives first, and the second one is missing
+    let decrypted_messages = decrypt_messages(&bob.0, outputs[2].clone()).await.unwrap();
+    assert_eq!(decrypted_messages.len(), 1);
+    assert_eq!(decrypted_messages[0].contents, vec![2; 10]);
+
+    // The pre key that started the session is replaced
+    with_retries(
+        async || {
+            let pre_key = get_pre_key(&bob.0, bob.0.my_pub_key.clone()).await?;
+            if pre_key.is_none() || pre_key.eq(&bob_pre_key) {
+                return Err(anyhow!("The pre key was not replaced yet"));
+            }
+            Ok(())
+        },
+        10,
+    )
+    .await
+    .unwrap();
+
+    let bob_session = get_peer_session(&bob.0, &alice.0.my_pub_key)
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(bob_session.received_messages, 3);
+    assert_eq!(bob_session.their_current_key, Some(alice_latest_key));
+
+    // The first message arrives late, and doesn't take the session back to the key it announced
+    let decrypted_messages = decrypt_messages(&bob.0, outputs[0].clone()).await.unwrap();
+    assert_eq!(decrypted_messages.len(), 1);
+    assert_eq!(decrypted_messages[0].contents, vec![0; 10]);
+
+    let bob_session = get_peer_session(&bob.0, &alice.0.my_pub_key)
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(bob_session.received_messages, 3);
+    assert_eq!(bob_session.their_current_key, Some(alice_latest_key));
+
+    // So the reply is encrypted to the key announced in the latest message, not in the first one
+    let reply = encrypt_message(&bob.0, vec![alice.0.my_pub_key.clone()], vec![3; 10])
+        .await
+        .unwrap();
+    let reply_outputs = outputs_for_recipient(&bob.0, reply, &alice.0.my_pub_key)
+        .await
+        .unwrap();
+    let decrypted_messages = decrypt_messages(&alice.0, reply_outputs).await.unwrap();
+    assert_eq!(decrypted_messages.len(), 1);
+    assert_eq!(decrypted_messages[0].contents, vec![3; 10]);
+
+    let bob_session = get_peer_session(&bob.0, &alice.0.my_pub_key)
+        .await
+        .unwrap()
+        .unwrap();
+    let alice_session = get_peer_session(&alice.0, &bob.0.my_pub_key)
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(bob_session.sent_messages, 1);
+    assert_eq!(alice_session.received_messages, 1);
+    assert_eq!(alice_session.their_current_key, bob_session.my_current_key);
+
+    // The missing message can still be decrypted when it finally arrives, without changing the session
+    let decrypted_messages = decrypt_messages(&bob.0, outputs[1].clone()).await.unwrap();
+    assert_eq!(decrypted_messages.len(), 1);
+    assert_eq!(decrypted_messages[0].contents, vec![1; 10]);
+
+    let bob_session_after = get_peer_session(&bob.0, &alice.0.my_pub_key)
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(bob_session_after, bob_session);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+#[serial]
+async fn group_messages_are_stored_once_for_all_members() {
+    let Scenario {
+        network_seed,
+        progenitor,
+        alice,
+        bob,
+        carol,
+        bootstrap_srv,
+    } = setup().await;
+
+    let client = SafeholdServiceClient::create(
+        TempDir::new("safehold-service-test").unwrap().into_path(),
+        network_config(&bootstrap_srv),
+        "client-happ".into(),
+        client_happ_path(),
+        vec![progenitor.clone()],
+        false,
+    )
+    .await
+    .unwrap();
+
+    client.create_clone_request(network_seed).await.unwrap();
+
+    wait_for_providers(&alice.0).await.unwrap();
+    wait_for_providers(&bob.0).await.unwrap();
+    wait_for_providers(&carol.0).await.unwrap();
+
+    wait_for_pre_keys(&alice.0, vec![&bob.0, &carol.0])
+        .await
+        .unwrap();
+    wait_for_pre_keys(&bob.0, vec![&alice.0, &carol.0])
+        .await
+        .unwrap();
+
+    let group_hash: ActionHash = alice
+        .0
+        .call_zome(
+            ZomeCallTarget::RoleName("example".into()),
+            "encrypted_messages".into(),
+            "create_group".into(),
+            ExternIO::encode(CreateGroupInput {
+                name: "friends".into(),
+                members: vec![bob.0.my_pub_key.clone(), carol.0.my_pub_key.clone()],
+            })
+            .unwrap(),
+        )
+        .await
+        .unwrap()
+        .decode()
+        .unwrap();
+
+    // Another group with the same name doesn't get in the way of the first one
+    let other_group_hash: ActionHash = carol
+        .0
+        .call_zome(
+            ZomeCallTarget::RoleName("example".into()),
+            "encrypted_messages".into(),
+            "create_group".into(),
+            ExternIO::encode(CreateGroupInput {
+                name: "friends".into(),
+                members: vec![alice.0.my_pub_key.clone()],
+            })
+            .unwrap(),
+        )
+        .await
+        .unwrap()
+        .decode()
+        .unwrap();
+
+    let messages = send_group_message(&alice.0, &group_hash, vec![0, 1, 2])
+        .await
+        .unwrap();
+    assert_eq!(messages.len(), 1);
+    assert_eq!(messages[0].message.recipients.len(), 2);
+
+    for recipient in [&bob.0, &carol.0] {
+        let decrypted_messages = with_retries(
+            async || {
+                let decrypted_messages = receive_messages(recipient).await?;
+                if decrypted_messages.is_empty() {
+                    return Err(anyhow!("No messages yet"));
+                }
+                Ok(decrypted_messages)
+            },
+            30,
+        )
+        .await
+        .unwrap();
+        assert_eq!(decrypted_messages.len(), 1);
+        assert_eq!(decrypted_messages[0].contents, vec![0, 1, 2]);
+    }
+
+    // The groups to which alice was added are ignored until she accepts them
+    let alice_groups = get_groups(&alice.0).await.unwrap();
+    assert_eq!(alice_groups.keys().collect::<Vec<_>>(), vec![&group_hash]);
+    let result = send_group_message(&alice.0, &other_group_hash, vec![0]).await;
+    assert!(result.is_err());
+
+    // The other members can send messages to the group once they accept it
+    let invitations: BTreeMap<ActionHash, Group> = with_retries(
+        async || {
+            let invitations: BTreeMap<ActionHash, Group> = bob
+                .0
+                .call_zome(
+                    ZomeCallTarget::RoleName("example".into()),
+                    "encrypted_messages".into(),
+                    "get_group_invitations".into(),
+                    ExternIO::encode(())?,
+                )
+                .await?
+                .decode()?;
+            if invitations.is_empty() {
+                return Err(anyhow!("The group is not visible yet"));
+            }
+            Ok(invitations)
+        },
+        30,
+    )
+    .await
+    .unwrap();
+    assert_eq!(invitations.keys().collect::<Vec<_>>(), vec![&group_hash]);
+    assert_eq!(invitations[&group_hash].name, "friends");
+    assert!(invitations[&group_hash].members.contains(&bob.0.my_pub_key));
+    assert!(send_group_message(&bob.0, &group_hash, vec![3, 4, 5])
+        .await
+        .is_err());
+
+    let _response: () = bob
+        .0
+        .call_zome(
+            ZomeCallTarget::RoleName("example".into()),
+            "encrypted_messages".into(),
+            "accept_group".into(),
+            ExternIO::encode(group_hash.clone()).unwrap(),
+        )
+        .await
+        .unwrap()
+        .decode()
+        .unwrap();
+
+    let messages = send_group_message(&bob.0, &group_hash, vec![3, 4, 5])
+        .await
+        .unwrap();
+    let mut other_members = vec![&alice.0.my_pub_key, &carol.0.my_pub_key];
+    other_members.sort();
+    assert_eq!(messages.len(), 1);
+    assert_eq!(
+        messages[0].message.recipients.keys().collect::<Vec<_>>(),
+        other_members
+    );
+
+    let _response: () = alice
+        .0
+        .call_zome(
+            ZomeCallTarget::RoleName("example".into()),
+            "encrypted_messages".into(),
+            "update_group_members".into(),
+            ExternIO::encode(UpdateGroupMembersInput {
+                group_hash: group_hash.clone(),
+                members: vec![bob.0.my_pub_key.clone()],
+            })
+            .unwrap(),
+        )
+        .await
+        .unwrap()
+        .decode()
+        .unwrap();
+
+    let messages = send_group_message(&alice.0, &group_hash, vec![6, 7, 8])
+        .await
+        .unwrap();
+    assert_eq!(messages.len(), 1);
+    assert_eq!(
+        messages[0].message.recipients.keys().collect::<Vec<_>>(),
+        vec![&bob.0.my_pub_key]
+    );
+}
+
+#[tokio::test(flavor = "multi_thread")]
+#[serial]
+async fn sealed_sender_messages_hide_their_sender() {
+    let Scenario {
+        network_seed,
+        progenitor,
+        alice,
+        bob,
+        bootstrap_srv,
+        ..
+    } = setup().await;
+
+    let client = SafeholdServiceClient::create(
+        TempDir::new("safehold-service-test").unwrap().into_path(),
+        network_config(&bootstrap_srv),
+        "client-happ".into(),
+        client_happ_path(),
+        vec![progenitor.clone()],
+        false,
+    )
+    .await
+    .unwrap();
+
+    client.create_clone_request(network_seed).await.unwrap();
+
+    wait_for_providers(&alice.0).await.unwrap();
+    wait_for_providers(&bob.0).await.unwrap();
+
+    wait_for_pre_keys(&alice.0, vec![&bob.0]).await.unwrap();
+
+    let safehold_service_trait_service_id = safehold_service_trait::SAFEHOLD_SERVICE_HASH.to_vec();
+    let message_content: Vec<u8> = vec![1; CHUNK_SIZE * 2];
+    let messages: Vec<MessageWithProvenance> = alice
+        .0
+        .call_zome(
+            ZomeCallTarget::RoleName("example".into()),
+            "encrypted_messages".into(),
+            "encrypt_message".into(),
+            ExternIO::encode(EncryptMessageInput {
+                recipients: vec![bob.0.my_pub_key.clone()],
+                message: message_content.clone(),
+                ttl: None,
+                delivery_receipt: false,
+                chunk_size: Some(CHUNK_SIZE),
+                group_mode: false,
+                sealed_sender: true,
+            })
+            .unwrap(),
+        )
+        .await
+        .unwrap()
+        .decode()
+        .unwrap();
+
+    // All the chunks are signed by the same ephemeral key instead of by alice
+    assert_eq!(messages.len(), 2);
+    assert!(messages.iter().all(|message| message.message.sealed_sender));
+    assert_ne!(messages[0].provenance, alice.0.my_pub_key);
+    assert_eq!(messages[0].provenance, messages[1].provenance);
+
+    // The gateway checks the signature of sealed messages, since it can't check their provenance
+    let mut forged_message = messages[0].clone();
+    forged_message.message.contents = vec![0; 10];
+    let result: anyhow::Result<()> = make_service_request(
+        &alice.0,
+        safehold_service_trait_service_id.clone(),
+        "store_messages".into(),
+        vec![forged_message],
+    )
+    .await;
+    assert_eq!(
+        safehold_error(&result.unwrap_err()),
+        Some(SafeholdError::InvalidSignature)
+    );
+
+    let _response: () = make_service_request(
+        &alice.0,
+        safehold_service_trait_service_id.clone(),
+        "store_messages".into(),
+        messages,
+    )
+    .await
+    .unwrap();
+
+    let messages_outputs: Vec<MessageOutput> = with_retries(
+        async || {
+            let messages_outputs: Vec<MessageOutput> = make_service_request(
+                &bob.0,
+                safehold_service_trait_service_id.clone(),
+                "get_messages".into(),
+                (),
+            )
+            .await?;
+            if messages_outputs.len() != 2 {
+                return Err(anyhow!("Not all chunks are stored yet"));
+            }
+            Ok(messages_outputs)
+        },
+        30,
+    )
+    .await
+    .unwrap();
+
+    // Bob authenticates alice inside the sealed header
+    let decrypted_messages = decrypt_messages(&bob.0, messages_outputs.clone())
+        .await
+        .unwrap();
+    assert_eq!(decrypted_messages.len(), 1);
+    assert_eq!(decrypted_messages[0].provenance, alice.0.my_pub_key);
+    assert_eq!(decrypted_messages[0].contents, message_content);
+
+    // Redelivered sealed messages are recognized by their real sender
+    let decrypted_messages = decrypt_messages(&bob.0, messages_outputs).await.unwrap();
+    assert_eq!(decrypted_messages.len(), 0);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+#[serial]
+async fn sealed_messages_of_the_max_chunk_size_fit_for_many_recipients() {
+    let Scenario {
+        network_seed,
+        progenitor,
+        alice,
+        bob,
+        carol,
+        bootstrap_srv,
+    } = setup().await;
+
+    let client = SafeholdServiceClient::create(
+        TempDir::new("safehold-service-test").unwrap().into_path(),
+        network_config(&bootstrap_srv),
+        "client-happ".into(),
+        client_happ_path(),
+        vec![progenitor.clone()],
+        false,
+    )
+    .await
+    .unwrap();
+
+    client
+        .create_clone_request(network_seed.clone())
+        .await
+        .unwrap();
+
+    let mut others = vec![];
+    for _ in 0..6 {
+        others.push(
+            launch(
+                progenitor.clone(),
+                vec![String::from("services")],
+                end_user_happ_path(),
+                network_seed.clone(),
+                network_config(&bootstrap_srv),
+            )
+            .await,
+        );
+    }
+    let recipients_app_ws: Vec<&AppWebsocket> = vec![&bob.0, &carol.0]
+        .into_iter()
+        .chain(others.iter().map(|(app_ws, _)| app_ws))
+        .collect();
+    let recipients: Vec<AgentPubKey> = recipients_app_ws
+        .iter()
+        .map(|app_ws| app_ws.my_pub_key.clone())
+        .collect();
+
+    wait_for_providers(&alice.0).await.unwrap();
+    wait_for_providers(&bob.0).await.unwrap();
+    wait_for_pre_keys(&alice.0, recipients_app_ws)
+        .await
+        .unwrap();
+
+    let max_chunk_size: usize = alice
+        .0
+        .call_zome(
+            ZomeCallTarget::RoleName("example".into()),
+            "encrypted_messages".into(),
+            "get_max_chunk_size".into(),
+            ExternIO::encode(recipients.len()).unwrap(),
+        )
+        .await
+        .unwrap()
+        .decode()
+        .unwrap();
+
+    // The bytes that serialize to the most bytes, so that the first chunk is as big as it can get
+    let message_content: Vec<u8> = vec![0xFF; max_chunk_size + 1];
+    let messages: Vec<MessageWithProvenance> = alice
+        .0
+        .call_zome(
+            ZomeCallTarget::RoleName("example".into()),
+            "encrypted_messages".into(),
+            "encrypt_message".into(),
+            ExternIO::encode(EncryptMessageInput {
+                recipients: recipients.clone(),
+                message: message_content.clone(),
+                ttl: None,
+                delivery_receipt: false,
+                chunk_size: None,
+                group_mode: true,
+                sealed_sender: true,
+            })
+            .unwrap(),
+        )
+        .await
+        .unwrap()
+        .decode()
+        .unwrap();
+    assert_eq!(messages.len(), 2);
+    assert_eq!(messages[0].message.recipients.len(), recipients.len());
+
+    // The first chunk carries the sealed headers of all the recipients and is still accepted
+    let _response: () = make_service_request(
+        &alice.0,
+        safehold_service_trait::SAFEHOLD_SERVICE_HASH.to_vec(),
+        "store_messages".into(),
+        messages,
+    )
+    .await
+    .unwrap();
+
+    let decrypted_messages = with_retries(
+        async || {
+            let decrypted_messages = receive_messages(&bob.0).await?;
+            if decrypted_messages.is_empty() {
+                return Err(anyhow!("Not all chunks were received yet"));
+            }
+            Ok(decrypted_messages)
+        },
+        30,
+    )
+    .await
+    .unwrap();
+    assert_eq!(decrypted_messages.len(), 1);
+    assert_eq!(decrypted_messages[0].provenance, alice.0.my_pub_key);
+    assert_eq!(decrypted_messages[0].contents, message_content);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+#[serial]
+async fn delegated_senders_store_messages_for_their_delegator() {
+    let Scenario {
+        network_seed,
+        progenitor,
+        alice,
+        bob,
+        carol,
+        bootstrap_srv,
+    } = setup().await;
+
+    let client = SafeholdServiceClient::create(
+        TempDir::new("safehold-service-test").unwrap().into_path(),
+        network_config(&bootstrap_srv),
+        "client-happ".into(),
+        client_happ_path(),
+        vec![progenitor.clone()],
+        false,
+    )
+    .await
+    .unwrap();
+
+    client.create_clone_request(network_seed).await.unwrap();
+
+    wait_for_providers(&alice.0).await.unwrap();
+    wait_for_providers(&bob.0).await.unwrap();
+    wait_for_providers(&carol.0).await.unwrap();
+
+    let safehold_service_trait_service_id = safehold_service_trait::SAFEHOLD_SERVICE_HASH.to_vec();
+    let message_content: Vec<u8> = vec![1; 10];
+    let messages = encrypt_message(
+        &alice.0,
+        vec![bob.0.my_pub_key.clone()],
+        message_content.clone(),
+    )
+    .await
+    .unwrap();
+
+    // The gateway checks the signature of every message
+    let mut forged_messages = messages.clone();
+    forged_messages[0].message.contents = vec![0; 10];
+    let result: anyhow::Result<()> = make_service_request(
+        &alice.0,
+        safehold_service_trait_service_id.clone(),
+        "store_messages".into(),
+        forged_messages,
+    )
+    .await;
+    assert_eq!(
+        safehold_error(&result.unwrap_err()),
+        Some(SafeholdError::InvalidSignature)
+    );
+
+    // Carol can't store the messages of alice without a delegation from her
+    let result: anyhow::Result<()> = make_service_request(
+        &carol.0,
+        safehold_service_trait_service_id.clone(),
+        "store_messages".into(),
+        messages.clone(),
+    )
+    .await;
+    assert_eq!(
+        safehold_error(&result.unwrap_err()),
+        Some(SafeholdError::ProvenanceMismatch)
+    );
+
+    // Nor with a delegation that she signed herself
+    let services_dna_hash = provisioned_dna_hash(&carol.0, SERVICES_ROLE_NAME).await;
+    let mut forged_delegation = create_sender_delegation(
+        &carol.0,
+        &carol.0.my_pub_key,
+        &services_dna_hash,
+        delegation_expiry(),
+    )
+    .await;
+    forged_delegation.delegator = alice.0.my_pub_key.clone();
+    let result: anyhow::Result<()> = make_service_request(
+        &carol.0,
+        safehold_service_trait_service_id.clone(),
+        "store_messages".into(),
+        with_delegation(&messages, forged_delegation),
+    )
+    .await;
+    assert_eq!(
+        safehold_error(&result.unwrap_err()),
+        Some(SafeholdError::InvalidDelegation)
+    );
+
+    // Nor with one that has expired, or that is for another services DNA
+    let expired_delegation = create_sender_delegation(
+        &alice.0,
+        &carol.0.my_pub_key,
+        &services_dna_hash,
+        Timestamp::now(),
+    )
+    .await;
+    let other_network_delegation = create_sender_delegation(
+        &alice.0,
+        &carol.0.my_pub_key,
+        &provisioned_dna_hash(&alice.0, "example").await,
+        delegation_expiry(),
+    )
+    .await;
+    for delegation in [expired_delegation, other_network_delegation] {
+        let result: anyhow::Result<()> = make_service_request(
+            &carol.0,
+            safehold_service_trait_service_id.clone(),
+            "store_messages".into(),
+            with_delegation(&messages, delegation),
+        )
+        .await;
+        assert_eq!(
+            safehold_error(&result.unwrap_err()),
+            Some(SafeholdError::InvalidDelegation)
+        );
+    }
+
+    let delegation = create_sender_delegation(
+        &alice.0,
+        &carol.0.my_pub_key,
+        &services_dna_hash,
+        delegation_expiry(),
+    )
+    .await;
+
+    // The delegation only authorizes its delegate
+    let result: anyhow::Result<()> = make_service_request(
+        &bob.0,
+        safehold_service_trait_service_id.clone(),
+        "store_messages".into(),
+        with_delegation(&messages, delegation.clone()),
+    )
+    .await;
+    assert_eq!(
+        safehold_error(&result.unwrap_err()),
+        Some(SafeholdError::InvalidDelegation)
+    );
+
+    let _response: () = make_service_request(
+        &carol.0,
+        safehold_service_trait_service_id.clone(),
+        "store_messages".into(),
+        with_delegation(&messages, delegation),
+    )
+    .await
+    .unwrap();
+
+    // The messages are stored as alice signed them
+    let decrypted_messages = with_retries(
+        async || {
+            let decrypted_messages = receive_messages(&bob.0).await?;
+            if decrypted_messages.is_empty() {
+                return Err(anyhow!("No messages yet"));
+            }
+            Ok(decrypted_messages)
+        },
+        30,
+    )
+    .await
+    .unwrap();
+    assert_eq!(decrypted_messages.len(), 1);
+    assert_eq!(decrypted_messages[0].provenance, alice.0.my_pub_key);
+    assert_eq!(decrypted_messages[0].contents, message_content);
+
+    // A provider delegation doesn't authorize its delegate to store the messages signed by its delegator,
+    // even with the same fields as a valid sender delegation
+    let delegator = Progenitor::new();
+    let delegator_messages: Vec<MessageWithProvenance> = messages
+        .iter()
+        .map(|message| delegator.sign_message(message.message.clone()))
+        .collect();
+    let provider_delegation = delegator.delegate_provider(&carol.0.my_pub_key, &services_dna_hash);
+    let provider_delegation_as_sender = SenderDelegation {
+        delegator: provider_delegation.delegator,
+        delegate: provider_delegation.delegate,
+        services_dna_hash: provider_delegation.proxy_dna_hash,
+        expires_at: provider_delegation.expires_at,
+        signature: provider_delegation.signature,
+    };
+    let result: anyhow::Result<()> = make_service_request(
+        &carol.0,
+        safehold_service_trait_service_id.clone(),
+        "store_messages".into(),
+        with_delegation(&delegator_messages, provider_delegation_as_sender),
+    )
+    .await;
+    assert_eq!(
+        safehold_error(&result.unwrap_err()),
+        Some(SafeholdError::InvalidDelegation)
+    );
+
+    let sender_delegation = delegator.delegate_sender(&carol.0.my_pub_key, &services_dna_hash);
+    let _response: () = make_service_request(
+        &carol.0,
+        safehold_service_trait_service_id.clone(),
+        "store_messages".into(),
+        with_delegation(&delegator_messages, sender_delegation),
+    )
+    .await
+    .unwrap();
+}
+
+#[derive(Serialize, Debug)]
+struct CreateSenderDelegationInput {
+    delegate: AgentPubKey,
+    services_dna_hash: DnaHash,
+    expires_at: Timestamp,
+}
+
+async fn create_sender_delegation(
+    app_ws: &AppWebsocket,
+    delegate: &AgentPubKey,
+    services_dna_hash: &DnaHash,
+    expires_at: Timestamp,
+) -> SenderDelegation {
+    app_ws
+        .call_zome(
+            ZomeCallTarget::RoleName("example".into()),
+            "encrypted_messages".into(),
+            "create_sender_delegation".into(),
+            ExternIO::encode(CreateSenderDelegationInput {
+                delegate: delegate.clone(),
+                services_dna_hash: services_dna_hash.clone(),
+                expires_at,
+            })
+            .unwrap(),
+        )
+        .await
+        .unwrap()
+        .decode()
+        .unwrap()
+}
+
+fn with_delegation(
+    messages: &[MessageWithProvenance],
+    delegation: SenderDelegation,
+) -> Vec<MessageWithProvenance> {
+    messages
+        .iter()
+        .cloned()
+        .map(|mut message| {
+            message.delegation = Some(delegation.clone());
+            message
+        })
+        .collect()
+}
+
+#[tokio::test(flavor = "multi_thread")]
+#[serial]
+async fn incomplete_messages_report_their_progress() {
+    let Scenario {
+        network_seed,
+        progenitor,
+        alice,
+        bob,
+        carol: _carol,
+        bootstrap_srv,
+    } = setup().await;
+
+    let client = SafeholdServiceClient::create(
+        TempDir::new("safehold-service-test").unwrap().into_path(),
+        network_config(&bootstrap_srv),
+        "client-happ".into(),
+        client_happ_path(),
+        vec![progenitor.clone()],
+        false
+    )
+    .await
+    .unwrap();
+
+    client.create_clone_request(network_seed).await.unwrap();
+
+    wait_for_providers(&alice.0).await.unwrap();
+    wait_for_pre_keys(&alice.0, vec![&bob.0]).await.unwrap();
+
+    let message_content: Vec<u8> = vec![0; CHUNK_SIZE * 2];
+    send_message(&alice.0, vec![bob.0.my_pub_key.clone()], message_content.clone())
+        .await
+        .unwrap();
+
+    wait_for_providers(&bob.0).await.unwrap();
+
+    let safehold_service_trait_service_id = safehold_service_trait::SAFEHOLD_SERVICE_HASH.to_vec();
+    let mut messages_outputs: Vec<MessageOutput> = with_retries(
+        async || {
+            let messages_outputs: Vec<MessageOutput> = make_service_request(
+                &bob.0,
+                safehold_service_trait_service_id.clone(),
+                "get_messages".into(),
+                (),
+            )
+            .await?;
+            if messages_outputs.len() != 2 {
+                return Err(anyhow!("Not all chunks are stored yet"));
+            }
+            Ok(messages_outputs)
+        },
+        10,
+    )
+    .await
+    .unwrap();
+
+    // Deliver first the chunk that only carries its ciphertext, before the secret of the message
+    messages_outputs.sort_by_key(|m| !m.agent_specific_contents.is_empty());
+
+    for (i, message_output) in messages_outputs.into_iter().enumerate() {
+        let decrypted_messages: Vec<DecryptedMessageOutput> = bob
+            .0
+            .call_zome(
+                ZomeCallTarget::RoleName("example".into()),
+                "encrypted_messages".into(),
+                "decrypt_messages".into(),
+                ExternIO::encode(vec![message_output]).unwrap(),
+            )
+            .await
+            .unwrap()
+            .decode()
+            .unwrap();
+
+        let incomplete_messages: Vec<IncompleteMessageOutput> = bob
+            .0
+            .call_zome(
+                ZomeCallTarget::RoleName("example".into()),
+                "encrypted_messages".into(),
+                "get_incomplete_messages".into(),
+                ExternIO::encode(()).unwrap(),
+            )
+            .await
+            .unwrap()
+            .decode()
+            .unwrap();
+
+        if i == 0 {
+            assert_eq!(decrypted_messages.len(), 0);
+            assert_eq!(incomplete_messages.len(), 1);
+            assert_eq!(incomplete_messages[0].provenance, alice.0.my_pub_key);
+            assert_eq!(incomplete_messages[0].received_chunks, 1);
+            // Unknown until the secret of the message is received
+            assert_eq!(incomplete_messages[0].total_chunks, None);
+        } else {
+            assert_eq!(decrypted_messages.len(), 1);
+            assert_eq!(decrypted_messages[0].contents, message_content);
+            assert_eq!(incomplete_messages.len(), 0);
+        }
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+#[serial]
+async fn get_messages_in_pages() {
+    let Scenario {
+        network_seed,
+        progenitor,
+        alice,
+        bob,
+        bootstrap_srv,
+        ..
+    } = setup().await;
+
+    let client = SafeholdServiceClient::create(
+        TempDir::new("safehold-service-test").unwrap().into_path(),
+        network_config(&bootstrap_srv),
+        "client-happ".into(),
+        client_happ_path(),
+        vec![progenitor.clone()],
+        false
+    )
+    .await
+    .unwrap();
+
+    client.create_clone_request(network_seed).await.unwrap();
+
+    wait_for_providers(&alice.0).await.unwrap();
+
+    for i in 0..3 {
+        send_message(&alice.0, vec![bob.0.my_pub_key.clone()], vec![i; 10])
+            .await
+            .unwrap();
+    }
+
+    wait_for_providers(&bob.0).await.unwrap();
+
+    std::thread::sleep(Duration::from_secs(10));
+
+    let safehold_service_trait_service_id = safehold_service_trait::SAFEHOLD_SERVICE_HASH.to_vec();
+    let mut cursor = None;
+    let mut pages: Vec<MessagesPage> = vec![];
+
+    loop {
+        let page: MessagesPage = make_service_request(
+            &bob.0,
+            safehold_service_trait_service_id.clone(),
+            "get_messages_page".into(),
+            GetMessagesPageInput {
+                cursor: cursor.clone(),
+                max_count: 1,
+                max_bytes: 1_000_000,
+            },
+        )
+        .await
+        .unwrap();
+        cursor = page.next_cursor.clone();
+        pages.push(page);
+        if cursor.is_none() {
+            break;
+        }
+    }
+
+    assert_eq!(pages.len(), 3);
+    assert!(pages.iter().all(|page| page.messages.len() == 1));
+
+    let decrypted_messages = receive_messages(&bob.0).await.unwrap();
+    assert_eq!(decrypted_messages.len(), 3);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+#[serial]
+async fn malicious_deleter_is_rejected() {
+    let Scenario {
+        network_seed,
+        progenitor,
+        alice,
+        bob,
+        bootstrap_srv,
+        ..
+    } = setup().await;
+
+    let client = SafeholdServiceClient::create(
+        TempDir::new("safehold-service-test").unwrap().into_path(),
+        network_config(&bootstrap_srv),
+        "client-happ".into(),
+        client_happ_path(),
+        vec![progenitor.clone()],
+        false
+    )
+    .await
+    .unwrap();
+
+    client.create_clone_request(network_seed.clone()).await.unwrap();
+
+    // Joins the current safehold DHT without a delegation from the progenitor
+    let (malicious, _malicious_runtime) = launch(
+        progenitor.clone(),
+        vec![],
+        service_provider_happ_path(),
+        network_seed,
+        network_config(&bootstrap_srv),
+    )
+    .await;
+    let malicious_cell = malicious
+        .create_clone_cell(CreateCloneCellPayload {
+            role_name: "safehold".into(),
+            modifiers: safehold_dna_modifiers(
+                vec![progenitor.clone()],
+                DEFAULT_EPOCH_LENGTH,
+                SafeholdQuotas::default(),
+                provisioned_dna_hash(&malicious, "proxy").await,
+                get_current_time_epoch(DEFAULT_EPOCH_LENGTH),
+            ),
+            membrane_proof: None,
+            name: None,
+        })
+        .await
+        .unwrap();
+
+    wait_for_providers(&alice.0).await.unwrap();
+
+    send_message(&alice.0, vec![bob.0.my_pub_key.clone()], vec![0; 10])
+        .await
+        .unwrap();
+
+    let bob_messages: Vec<MessageOutput> = with_retries(
+        async || {
+            let messages: Vec<MessageOutput> = malicious
+                .call_zome(
+                    ZomeCallTarget::CellId(malicious_cell.cell_id.clone()),
+                    "safehold".into(),
+                    "get_messages_for_recipient".into(),
+                    ExternIO::encode(bob.0.my_pub_key.clone())?,
+                )
+                .await?
+                .decode()?;
+            if messages.is_empty() {
+                return Err(anyhow!("Message not found yet"));
+            }
+            Ok(messages)
+        },
+        100,
+    )
+    .await
+    .unwrap();
+
+    let result = malicious
+        .call_zome(
+            ZomeCallTarget::CellId(malicious_cell.cell_id.clone()),
+            "safehold".into(),
+            "ack_messages_for_recipient".into(),
+            ExternIO::encode(AckMessagesInput {
+                recipient: bob.0.my_pub_key.clone(),
+                message_hashes: bob_messages.into_iter().map(|m| m.message_hash).collect(),
+            })
+            .unwrap(),
+        )
+        .await;
+    assert!(result.is_err());
+
+    wait_for_providers(&bob.0).await.unwrap();
+
+    let decrypted_messages = receive_messages(&bob.0).await.unwrap();
+    assert_eq!(decrypted_messages.len(), 1);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+#[serial]
+async fn quotas_are_enforced() {
+    let Scenario {
+        network_seed,
+        progenitor,
+        alice,
+        bob,
+        carol,
+        bootstrap_srv,
+    } = setup_with_quotas(SafeholdQuotas {
+        max_messages_per_sender_per_epoch: 4,
+        max_mailbox_bytes: 5_000,
+        max_recipients_per_message: 1,
+    })
+    .await;
+
+    let client = SafeholdServiceClient::create(
+        TempDir::new("safehold-service-test").unwrap().into_path(),
+        network_config(&bootstrap_srv),
+        "client-happ".into(),
+        client_happ_path(),
+        vec![progenitor.clone()],
+        false
+    )
+    .await
+    .unwrap();
+
+    client.create_clone_request(network_seed).await.unwrap();
+
+    wait_for_providers(&alice.0).await.unwrap();
+
+    let error = send_message(
+        &alice.0,
+        vec![bob.0.my_pub_key.clone(), carol.0.my_pub_key.clone()],
+        vec![0; 10],
+    )
+    .await
+    .unwrap_err();
+    assert_eq!(
+        safehold_error(&error),
+        Some(SafeholdError::QuotaExceeded(
+            QuotaExceeded::TooManyRecipients {
+                max: 1,
+                recipients: 2
+            }
+        ))
+    );
+    assert!(!safehold_error(&error).unwrap().is_retryable());
+
+    // The example zome gets the same error when it stores the messages itself
+    let error = send_message_from_zome(
+        &alice.0,
+        vec![bob.0.my_pub_key.clone(), carol.0.my_pub_key.clone()],
+        vec![0; 10],
+    )
+    .await
+    .unwrap_err();
+    assert_eq!(
+        safehold_error(&error),
+        Some(SafeholdError::QuotaExceeded(
+            QuotaExceeded::TooManyRecipients {
+                max: 1,
+                recipients: 2
+            }
+        ))
+    );
+
+    send_message(&alice.0, vec![bob.0.my_pub_key.clone()], vec![0; 10])
+        .await
+        .unwrap();
+
+    std::thread::sleep(Duration::from_secs(4));
+
+    // Three chunks of 2000 bytes don't fit in the 5000 bytes of bob's mailbox
+    let error = send_message(&alice.0, vec![bob.0.my_pub_key.clone()], vec![0; CHUNK_SIZE * 3])
+        .await
+        .unwrap_err();
+    assert_eq!(
+        safehold_error(&error),
+        Some(SafeholdError::QuotaExceeded(QuotaExceeded::MailboxFull {
+            recipient: bob.0.my_pub_key.clone(),
+            max_bytes: 5000
+        }))
+    );
+
+    send_message(&alice.0, vec![carol.0.my_pub_key.clone()], vec![0; 10])
+        .await
+        .unwrap();
+    std::thread::sleep(Duration::from_secs(4));
+
+    wait_for_pre_keys(&alice.0, vec![&carol.0]).await.unwrap();
+
+    // Sealed messages are signed by an ephemeral key, but they still count towards
+    // the quota of alice in the calls that come after them
+    for _ in 0..2 {
+        send_message_with_options(
+            &alice.0,
+            vec![carol.0.my_pub_key.clone()],
+            vec![0; 10],
+            false,
+            true,
+        )
+        .await
+        .unwrap();
+        std::thread::sleep(Duration::from_secs(4));
+    }
+
+    // The client computes the same epoch as the provider
+    let next_epoch = time_epoch(Timestamp::now(), DEFAULT_EPOCH_LENGTH) + 1;
+    let too_many_messages = Some(SafeholdError::QuotaExceeded(
+        QuotaExceeded::TooManyMessagesInEpoch {
+            max: 4,
+            retry_at: epoch_start(next_epoch, DEFAULT_EPOCH_LENGTH),
+        },
+    ));
+
+    let error = send_message_with_options(
+        &alice.0,
+        vec![carol.0.my_pub_key.clone()],
+        vec![0; 10],
+        false,
+        true,
+    )
+    .await
+    .unwrap_err();
+    assert_eq!(safehold_error(&error), too_many_messages);
+
+    let error = send_message(&alice.0, vec![carol.0.my_pub_key.clone()], vec![0; 10])
+        .await
+        .unwrap_err();
+    assert_eq!(safehold_error(&error), too_many_messages);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+#[serial]
+async fn delivery_receipts_are_visible_to_sender() {
+    let Scenario {
+        network_seed,
+        progenitor,
+        alice,
+        bob,
+        carol,
+        bootstrap_srv,
+    } = setup().await;
+
+    let client = SafeholdServiceClient::create(
+        TempDir::new("safehold-service-test").unwrap().into_path(),
+        network_config(&bootstrap_srv),
+        "client-happ".into(),
+        client_happ_path(),
+        vec![progenitor.clone()],
+        false
+    )
+    .await
+    .unwrap();
+
+    client.create_clone_request(network_seed).await.unwrap();
+
+    wait_for_providers(&alice.0).await.unwrap();
+
+    let messages = send_message_with_options(
+        &alice.0,
+        vec![bob.0.my_pub_key.clone(), carol.0.my_pub_key.clone()],
+        vec![0; 10],
+        true,
+        false,
+    )
+    .await
+    .unwrap();
+
+    let message_hashes: Vec<EntryHash> = alice
+        .0
+        .call_zome(
+            ZomeCallTarget::RoleName("example".into()),
+            "encrypted_messages".into(),
+            "get_message_hashes".into(),
+            ExternIO::encode(messages.clone()).unwrap(),
+        )
+        .await
+        .unwrap()
+        .decode()
+        .unwrap();
+
+    wait_for_providers(&bob.0).await.unwrap();
+
+    std::thread::sleep(Duration::from_secs(4));
+
+    let decrypted_messages = receive_messages(&bob.0).await.unwrap();
+    assert_eq!(decrypted_messages.len(), 1);
+
+    std::thread::sleep(Duration::from_secs(4));
+
+    let safehold_service_trait_service_id = safehold_service_trait::SAFEHOLD_SERVICE_HASH.to_vec();
+
+    // Only the sender of the messages can get their receipts
+    let receipts: Vec<SignedDeliveryReceipt> = make_service_request(
+        &bob.0,
+        safehold_service_trait_service_id.clone(),
+        "get_delivery_receipts".into(),
+        message_hashes.clone(),
+    )
+    .await
+    .unwrap();
+    assert!(receipts.is_empty());
+
+    let receipts: Vec<SignedDeliveryReceipt> = make_service_request(
+        &alice.0,
+        safehold_service_trait_service_id.clone(),
+        "get_delivery_receipts".into(),
+        message_hashes,
+    )
+    .await
+    .unwrap();
+    assert!(!receipts.is_empty());
+    assert!(receipts
+        .iter()
+        .all(|r| r.receipt.recipient.eq(&bob.0.my_pub_key)));
+
+    let delivered_recipients = get_delivered_recipients(
+        &alice.0,
+        messages.clone(),
+        receipts.clone(),
+        vec![progenitor.clone()],
+    )
+    .await
+    .unwrap();
+    assert_eq!(delivered_recipients, vec![bob.0.my_pub_key.clone()]);
+
+    // Receipts signed by agents that are not providers of the given progenitors don't count
+    let delivered_recipients = get_delivered_recipients(
+        &alice.0,
+        messages.clone(),
+        receipts.clone(),
+        vec![alice.0.my_pub_key.clone()],
+    )
+    .await
+    .unwrap();
+    assert!(delivered_recipients.is_empty());
+
+    let forged_receipts: Vec<SignedDeliveryReceipt> = receipts
+        .into_iter()
+        .map(|mut receipt| {
+            receipt.provider = bob.0.my_pub_key.clone();
+            receipt.delegation = None;
+            receipt
+        })
+        .collect();
+    let delivered_recipients = get_delivered_recipients(
+        &alice.0,
+        messages,
+        forged_receipts,
+        vec![progenitor.clone()],
+    )
+    .await
+    .unwrap();
+    assert!(delivered_recipients.is_empty());
+}
+
+#[tokio::test(flavor = "multi_thread")]
+#[serial]
+async fn delivery_receipts_are_migrated_to_the_next_epoch() {
+    let epoch_length = Duration::from_secs(40);
+    let Scenario {
+        network_seed,
+        progenitor,
+        alice,
+        bob,
+        carol: _carol,
+        bootstrap_srv,
+    } = setup_with_config(
+        SafeholdQuotas::default(),
+        epoch_length,
+        Duration::from_secs(10),
+    )
+    .await;
+
+    let client = SafeholdServiceClient::create(
+        TempDir::new("safehold-service-test").unwrap().into_path(),
+        network_config(&bootstrap_srv),
+        "client-happ".into(),
+        client_happ_path(),
+        vec![progenitor.clone()],
+        false,
+    )
+    .await
+    .unwrap();
+
+    client.create_clone_request(network_seed).await.unwrap();
+
+    wait_for_providers(&alice.0).await.unwrap();
+    wait_for_providers(&bob.0).await.unwrap();
+
+    let epoch = get_current_time_epoch(epoch_length);
+
+    let messages = send_message_with_options(
+        &alice.0,
+        vec![bob.0.my_pub_key.clone()],
+        vec![0; 10],
+        true,
+        false,
+    )
+    .await
+    .unwrap();
+    let message_hashes: Vec<EntryHash> = alice
+        .0
+        .call_zome(
+            ZomeCallTarget::RoleName("example".into()),
+            "encrypted_messages".into(),
+            "get_message_hashes".into(),
+            ExternIO::encode(messages.clone()).unwrap(),
+        )
+        .await
+        .unwrap()
+        .decode()
+        .unwrap();
+
+    // Bob acknowledges the message in the epoch in which it was stored
+    with_retries(
+        async || {
+            let decrypted_messages = receive_messages(&bob.0).await?;
+            if decrypted_messages.len() != 1 {
+                return Err(anyhow!("Message not received yet"));
+            }
+            Ok(())
+        },
+        10,
+    )
+    .await
+    .unwrap();
+    assert_eq!(get_current_time_epoch(epoch_length), epoch);
+
+    // Wait until the providers have rotated to the next epoch and deleted the old cell
+    while get_current_time_epoch(epoch_length).eq(&epoch) {
+        std::thread::sleep(Duration::from_secs(1));
+    }
+    std::thread::sleep(epoch_length / 2);
+
+    let safehold_service_trait_service_id = safehold_service_trait::SAFEHOLD_SERVICE_HASH.to_vec();
+    let receipts: Vec<SignedDeliveryReceipt> = with_retries(
+        async || {
+            let receipts: Vec<SignedDeliveryReceipt> = make_service_request(
+                &alice.0,
+                safehold_service_trait_service_id.clone(),
+                "get_delivery_receipts".into(),
+                message_hashes.clone(),
+            )
+            .await?;
+            if receipts.is_empty() {
+                return Err(anyhow!("Receipts not migrated yet"));
+            }
+            Ok(receipts)
+        },
+        10,
+    )
+    .await
+    .unwrap();
+
+    let delivered_recipients =
+        get_delivered_recipients(&alice.0, messages, receipts, vec![progenitor.clone()])
+            .await
+            .unwrap();
+    assert_eq!(delivered_recipients, vec![bob.0.my_pub_key.clone()]);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+#[serial]
+async fn messages_are_migrated_to_the_next_epoch() {
+    let epoch_length = Duration::from_secs(40);
+    let Scenario {
+        network_seed,
+        progenitor,
+        alice,
+        bob,
+        carol: _carol,
+        bootstrap_srv,
+    } = setup_with_config(SafeholdQuotas::default(), epoch_length, Duration::from_secs(10)).await;
+
+    let client = SafeholdServiceClient::create(
+        TempDir::new("safehold-service-test").unwrap().into_path(),
+        network_config(&bootstrap_srv),
+        "client-happ".into(),
+        client_happ_path(),
+        vec![progenitor.clone()],
+        false
+    )
+    .await
+    .unwrap();
+
+    client.create_clone_request(network_seed).await.unwrap();
+
+    wait_for_providers(&alice.0).await.unwrap();
+
+    let epoch = get_current_time_epoch(epoch_length);
+
+    send_message(&alice.0, vec![bob.0.my_pub_key.clone()], vec![0; 10])
+        .await
+        .unwrap();
+
+    // Wait until the providers have rotated to the next epoch
+    while get_current_time_epoch(epoch_length).eq(&epoch) {
+        std::thread::sleep(Duration::from_secs(1));
+    }
+    std::thread::sleep(epoch_length / 2);
+
+    wait_for_providers(&bob.0).await.unwrap();
+
+    let decrypted_messages = with_retries(
+        async || {
+            let decrypted_messages = receive_messages(&bob.0).await?;
+            if decrypted_messages.is_empty() {
+                return Err(anyhow!("Message not migrated yet"));
+            }
+            Ok(decrypted_messages)
+        },
+        10,
+    )
+    .await
+    .unwrap();
+    assert_eq!(decrypted_messages.len(), 1);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+#[serial]
+async fn acknowledged_messages_are_not_redelivered_after_migration() {
+    let epoch_length = Duration::from_secs(40);
+    let Scenario {
+        network_seed,
+        progenitor,
+        alice,
+        bob,
+        carol,
+        bootstrap_srv,
+    } = setup_with_config(SafeholdQuotas::default(), epoch_length, Duration::from_secs(10)).await;
+
+    let client = SafeholdServiceClient::create(
+        TempDir::new("safehold-service-test").unwrap().into_path(),
+        network_config(&bootstrap_srv),
+        "client-happ".into(),
+        client_happ_path(),
+        vec![progenitor.clone()],
+        false
+    )
+    .await
+    .unwrap();
+
+    client.create_clone_request(network_seed).await.unwrap();
+
+    wait_for_providers(&alice.0).await.unwrap();
+    wait_for_providers(&bob.0).await.unwrap();
+    wait_for_providers(&carol.0).await.unwrap();
+
+    // Exchange keys so that the next message from alice is a single one for both recipients
+    send_message(&bob.0, vec![alice.0.my_pub_key.clone()], vec![0; 3])
+        .await
+        .unwrap();
+    send_message(&carol.0, vec![alice.0.my_pub_key.clone()], vec![0; 3])
+        .await
+        .unwrap();
+    with_retries(
+        async || {
+            let decrypted_messages = receive_messages(&alice.0).await?;
+            if decrypted_messages.len() != 2 {
+                return Err(anyhow!("Messages not received yet"));
+            }
+            Ok(())
+        },
+        10,
+    )
+    .await
+    .unwrap();
+
+    let messages = send_message(
+        &alice.0,
+        vec![bob.0.my_pub_key.clone(), carol.0.my_pub_key.clone()],
+        vec![0; 10],
+    )
+    .await
+    .unwrap();
+    assert_eq!(messages.len(), 1);
+
+    // Only bob fetches and acknowledges the message before the migration
+    with_retries(
+        async || {
+            let decrypted_messages = receive_messages(&bob.0).await?;
+            if decrypted_messages.len() != 1 {
+                return Err(anyhow!("Message not received yet"));
+            }
+            Ok(())
+        },
+        10,
+    )
+    .await
+    .unwrap();
+
+    // Wait until the providers have rotated to the next epoch and the overlap is over
+    let epoch = get_current_time_epoch(epoch_length);
+    while get_current_time_epoch(epoch_length).eq(&epoch) {
+        std::thread::sleep(Duration::from_secs(1));
+    }
+    std::thread::sleep(epoch_length / 2);
+
+    let decrypted_messages = with_retries(
+        async || {
+            let decrypted_messages = receive_messages(&carol.0).await?;
+            if decrypted_messages.is_empty() {
+                return Err(anyhow!("Message not migrated yet"));
+            }
+            Ok(decrypted_messages)
+        },
+        10,
+    )
+    .await
+    .unwrap();
+    assert_eq!(decrypted_messages.len(), 1);
+
+    let decrypted_messages = receive_messages(&bob.0).await.unwrap();
+    assert_eq!(decrypted_messages.len(), 0);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+#[serial]
+async fn stragglers_are_migrated_after_the_overlap() {
+    let epoch_length = Duration::from_secs(60);
+    let epoch_overlap = Duration::from_secs(30);
+    let Scenario {
+        network_seed,
+        progenitor,
+        alice,
+        bob,
+        carol: _carol,
+        bootstrap_srv,
+    } = setup_with_config(SafeholdQuotas::default(), epoch_length, epoch_overlap).await;
+
+    let client = SafeholdServiceClient::create(
+        TempDir::new("safehold-service-test").unwrap().into_path(),
+        network_config(&bootstrap_srv),
+        "client-happ".into(),
+        client_happ_path(),
+        vec![progenitor.clone()],
+        false
+    )
+    .await
+    .unwrap();
+
+    client.create_clone_request(network_seed.clone()).await.unwrap();
+
+    wait_for_providers(&alice.0).await.unwrap();
+
+    // Another node that keeps storing messages in the old epoch after the providers have rotated
+    let epoch = get_current_time_epoch(epoch_length);
+    let (straggler, _straggler_runtime) = launch(
+        progenitor.clone(),
+        vec![],
+        service_provider_happ_path(),
+        network_seed,
+        network_config(&bootstrap_srv),
+    )
+    .await;
+    let straggler_cell = straggler
+        .create_clone_cell(CreateCloneCellPayload {
+            role_name: "safehold".into(),
+            modifiers: safehold_dna_modifiers(
+                vec![progenitor.clone()],
+                epoch_length,
+                SafeholdQuotas::default(),
+                provisioned_dna_hash(&straggler, "proxy").await,
+                epoch.clone(),
+            ),
+            membrane_proof: None,
+            name: None,
+        })
+        .await
+        .unwrap();
+
+    let messages: Vec<MessageWithProvenance> = alice
+        .0
+        .call_zome(
+            ZomeCallTarget::RoleName("example".into()),
+            "encrypted_messages".into(),
+            "encrypt_message".into(),
+            ExternIO::encode(EncryptMessageInput {
+                recipients: vec![bob.0.my_pub_key.clone()],
+                message: vec![0; 10],
+                ttl: None,
+                delivery_receipt: false,
+                chunk_size: None,
+                group_mode: false,
+                sealed_sender: false,
+            })
+            .unwrap(),
+        )
+        .await
+        .unwrap()
+        .decode()
+        .unwrap();
+
+    while get_current_time_epoch(epoch_length).eq(&epoch) {
+        std::thread::sleep(Duration::from_secs(1));
+    }
+    std::thread::sleep(Duration::from_secs(5));
+
+    let _r: () = straggler
+        .call_zome(
+            ZomeCallTarget::CellId(straggler_cell.cell_id.clone()),
+            "safehold".into(),
+            "create_messages".into(),
+            ExternIO::encode(messages).unwrap(),
+        )
+        .await
+        .unwrap()
+        .decode()
+        .unwrap();
+
+    // After the overlap, the providers sweep the old epoch before deleting it
+    std::thread::sleep(epoch_overlap + epoch_length / 2);
+
+    wait_for_providers(&bob.0).await.unwrap();
+
+    let decrypted_messages = with_retries(
+        async || {
+            let decrypted_messages = receive_messages(&bob.0).await?;
+            if decrypted_messages.is_empty() {
+                return Err(anyhow!("Straggler message not migrated yet"));
+            }
+            Ok(decrypted_messages)
+        },
+        30,
+    )
+    .await
+    .unwrap();
+    assert_eq!(decrypted_messages.len(), 1);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+#[serial]
+async fn online_recipients_are_notified() {
+    let Scenario {
+        network_seed,
+        progenitor,
+        alice,
+        bob,
+        carol,
+        bootstrap_srv,
+    } = setup().await;
+
+    let client = SafeholdServiceClient::create(
+        TempDir::new("safehold-service-test").unwrap().into_path(),
+        network_config(&bootstrap_srv),
+        "client-happ".into(),
+        client_happ_path(),
+        vec![progenitor.clone()],
+        false
+    )
+    .await
+    .unwrap();
+
+    client.create_clone_request(network_seed).await.unwrap();
+
+    wait_for_providers(&alice.0).await.unwrap();
+    wait_for_providers(&bob.0).await.unwrap();
+    wait_for_providers(&carol.0).await.unwrap();
+
+    let bob_notifications = collect_notifications(&bob.0).await;
+    let carol_notifications = collect_notifications(&carol.0).await;
+
+    send_message(&alice.0, vec![bob.0.my_pub_key.clone()], vec![0; 10])
+        .await
+        .unwrap();
+
+    with_retries(
+        async || {
+            if bob_notifications.lock().unwrap().is_empty() {
+                return Err(anyhow!("Bob has not been notified yet"));
+            }
+            Ok(())
+        },
+        30,
+    )
+    .await
+    .unwrap();
+
+    assert_eq!(
+        bob_notifications.lock().unwrap()[0],
+        SafeholdNotification::NewMessages
+    );
+    assert!(carol_notifications.lock().unwrap().is_empty());
+
+    // The notification means the message can be fetched right away
+    let decrypted_messages = receive_messages(&bob.0).await.unwrap();
+    assert_eq!(decrypted_messages.len(), 1);
+}
+
+async fn collect_notifications(app_ws: &AppWebsocket) -> Arc<Mutex<Vec<SafeholdNotification>>> {
+    let notifications: Arc<Mutex<Vec<SafeholdNotification>>> = Arc::new(Mutex::new(vec![]));
+    let n = notifications.clone();
+    app_ws
+        .on_signal(move |signal| {
+            let Signal::App { signal, .. } = signal else {
+                return;
+            };
+            if let Ok(notification) = signal.into_inner().decode::<SafeholdNotification>() {
+                n.lock().unwrap().push(notification);
+            }
+        })
+        .await;
+    notifications
+}
+
+async fn send_message(
+    app_ws: &AppWebsocket,
+    recipients: Vec<AgentPubKey>,
+    message: MessageContents,
+) -> anyhow::Result<Vec<MessageWithProvenance>> {
+    send_message_with_options(app_ws, recipients, message, false, false).await
+}
+
+async fn send_message_with_options(
+    app_ws: &AppWebsocket,
+    recipients: Vec<AgentPubKey>,
+    message: MessageContents,
+    delivery_receipt: bool,
+    sealed_sender: bool,
+) -> anyhow::Result<Vec<MessageWithProvenance>> {
+    let safehold_service_trait_service_id = safehold_service_trait::SAFEHOLD_SERVICE_HASH.to_vec();
+    let messages: Vec<MessageWithProvenance> = app_ws
+        .call_zome(
+            ZomeCallTarget::RoleName("example".into()),
+            "encrypted_messages".into(),
+            "encrypt_message".into(),
+            ExternIO::encode(EncryptMessageInput {
+                recipients,
+                message,
+                ttl: None,
+                delivery_receipt,
+                chunk_size: Some(CHUNK_SIZE),
+                group_mode: false,
+                sealed_sender,
+            })
+            .unwrap(),
+        )
+        .await?
+        .decode()?;
+
+    let _response: () = make_service_request(
+        &app_ws,
+        safehold_service_trait_service_id.clone(),
+        "store_messages".into(),
+        messages.clone(),
+    )
+    .await?;
+
+    Ok(messages)
+}
+
+async fn get_delivered_recipients(
+    app_ws: &AppWebsocket,
+    messages: Vec<MessageWithProvenance>,
+    receipts: Vec<SignedDeliveryReceipt>,
+    progenitors: Vec<AgentPubKey>,
+) -> anyhow::Result<Vec<AgentPubKey>> {
+    let delivered_recipients: Vec<AgentPubKey> = app_ws
+        .call_zome(
+            ZomeCallTarget::RoleName("example".into()),
+            "encrypted_messages".into(),
+            "get_delivered_recipients".into(),
+            ExternIO::encode(GetDeliveredRecipientsInput {
+                messages,
+                receipts,
+                progenitors,
+            })?,
+        )
+        .await?
+        .decode()?;
+    Ok(delivered_recipients)
+}
+
+async fn get_groups(app_ws: &AppWebsocket) -> anyhow::Result<BTreeMap<ActionHash, Group>> {
+    let groups: BTreeMap<ActionHash, Group> = app_ws
+        .call_zome(
+            ZomeCallTarget::RoleName("example".into()),
+            "encrypted_messages".into(),
+            "get_groups".into(),
+            ExternIO::encode(())?,
+        )
+        .await?
+        .decode()?;
+    Ok(groups)
+}
+
+/// Encrypts and stores the message with the `send_message` function of the example zome,
+/// which retries by itself if the safehold service returns a retryable error
+async fn send_message_from_zome(
+    app_ws: &AppWebsocket,
+    recipients: Vec<AgentPubKey>,
+    message: MessageContents,
+) -> anyhow::Result<Vec<MessageWithProvenance>> {
+    let messages: Vec<MessageWithProvenance> = app_ws
+        .call_zome(
+            ZomeCallTarget::RoleName("example".into()),
+            "encrypted_messages".into(),
+            "send_message".into(),
+            ExternIO::encode(EncryptMessageInput {
+                recipients,
+                message,
+                ttl: None,
+                delivery_receipt: false,
+                chunk_size: Some(CHUNK_SIZE),
+                group_mode: false,
+                sealed_sender: false,
+            })?,
+        )
+        .await?
+        .decode()?;
+    Ok(messages)
+}
+
+async fn send_group_message(
+    app_ws: &AppWebsocket,
+    group_hash: &ActionHash,
+    message: MessageContents,
+) -> anyhow::Result<Vec<MessageWithProvenance>> {
+    let safehold_service_trait_service_id = safehold_service_trait::SAFEHOLD_SERVICE_HASH.to_vec();
+    let messages: Vec<MessageWithProvenance> = app_ws
+        .call_zome(
+            ZomeCallTarget::RoleName("example".into()),
+            "encrypted_messages".into(),
+            "encrypt_group_message".into(),
+            ExternIO::encode(EncryptGroupMessageInput {
+                group_hash: group_hash.clone(),
+                message,
+                ttl: None,
+                delivery_receipt: false,
+                chunk_size: None,
+                sealed_sender: false,
+            })?,
+        )
+        .await?
+        .decode()?;
+
+    let _response: () = make_service_request(
+        &app_ws,
+        safehold_service_trait_service_id.clone(),
+        "store_messages".into(),
+        messages.clone(),
+    )
+    .await?;
+
+    Ok(messages)
+}
+
+/// Has the recipients publish their pre keys and waits until the sender can see them,
+/// so that it doesn't fall back to encrypting with their agent keys
+async fn wait_for_pre_keys(
+    sender: &AppWebsocket,
+    recipients: Vec<&AppWebsocket>,
+) -> anyhow::Result<()> {
+    for recipient in recipients {
+        // The first call to the cell publishes its pre key
+        get_pre_key(recipient, recipient.my_pub_key.clone()).await?;
+
+        with_retries(
+            async || {
+                get_pre_key(sender, recipient.my_pub_key.clone())
+                    .await?
+                    .ok_or(anyhow!("The pre key is not visible yet"))
+            },
+            30,
+        )
+        .await?;
+    }
+    Ok(())
+}
+
+async fn get_pre_key(
+    app_ws: &AppWebsocket,
+    agent: AgentPubKey,
+) -> anyhow::Result<Option<X25519PubKey>> {
+    let pre_key: Option<X25519PubKey> = app_ws
+        .call_zome(
+            ZomeCallTarget::RoleName("example".into()),
+            "encrypted_messages".into(),
+            "get_pre_key".into(),
+            ExternIO::encode(agent)?,
+        )
+        .await?
+        .decode()?;
+    Ok(pre_key)
+}
+
+async fn encrypt_message(
+    app_ws: &AppWebsocket,
+    recipients: Vec<AgentPubKey>,
+    message: MessageContents,
+) -> anyhow::Result<Vec<MessageWithProvenance>> {
+    let messages: Vec<MessageWithProvenance> = app_ws
+        .call_zome(
+            ZomeCallTarget::RoleName("example".into()),
+            "encrypted_messages".into(),
+            "encrypt_message".into(),
+            ExternIO::encode(EncryptMessageInput {
+                recipients,
+                message,
+                ttl: None,
+                delivery_receipt: false,
+                chunk_size: Some(CHUNK_SIZE),
+                group_mode: false,
+                sealed_sender: false,
+            })?,
+        )
+        .await?
+        .decode()?;
+    Ok(messages)
+}
+
+/// Builds the outputs that the recipient would get from the safehold service for the given messages,
+/// so that they can be delivered to it in any order
+async fn outputs_for_recipient(
+    sender: &AppWebsocket,
+    messages: Vec<MessageWithProvenance>,
+    recipient: &AgentPubKey,
+) -> anyhow::Result<Vec<MessageOutput>> {
+    let message_hashes: Vec<EntryHash> = sender
+        .call_zome(
+            ZomeCallTarget::RoleName("example".into()),
+            "encrypted_messages".into(),
+            "get_message_hashes".into(),
+            ExternIO::encode(messages.clone())?,
+        )
+        .await?
+        .decode()?;
+
+    Ok(messages
+        .into_iter()
+        .zip(message_hashes)
+        .filter_map(|(message, message_hash)| {
+            let agent_specific_contents = message.message.recipients.get(recipient)?.clone();
+            Some(MessageOutput {
+                message_hash,
+                provenance: message.provenance,
+                message_contents: message.message.contents,
+                agent_specific_contents,
+            })
+        })
+        .collect())
+}
+
+/// Group as stored in the `Group` entries of the `encrypted_messages` zome
+#[derive(Deserialize, Debug, PartialEq)]
+struct Group {
+    name: String,
+    members: BTreeSet<AgentPubKey>,
+}
+
+/// State of the session with a peer, as stored in the `PeerKeys` entries of the `encrypted_messages` zome
+#[derive(Deserialize, Debug, PartialEq)]
+struct PeerSession {
+    my_current_key: Option<X25519PubKey>,
+    their_current_key: Option<X25519PubKey>,
+    sent_messages: u32,
+    received_messages: u32,
+}
+
+async fn get_peer_session(
+    app_ws: &AppWebsocket,
+    peer: &AgentPubKey,
+) -> anyhow::Result<Option<PeerSession>> {
+    let peer_session: Option<PeerSession> = app_ws
+        .call_zome(
+            ZomeCallTarget::RoleName("example".into()),
+            "encrypted_messages".into(),
+            "get_peer_session".into(),
+            ExternIO::encode(peer)?,
+        )
+        .await?
+        .decode()?;
+    Ok(peer_session)
+}
+
+async fn decrypt_messages(
+    app_ws: &AppWebsocket,
+    messages_outputs: Vec<MessageOutput>,
+) -> anyhow::Result<Vec<DecryptedMessageOutput>> {
+    let decrypted_messages: Vec<DecryptedMessageOutput> = app_ws
+        .call_zome(
+            ZomeCallTarget::RoleName("example".into()),
+            "encrypted_messages".into(),
+            "decrypt_messages".into(),
+            ExternIO::encode(messages_outputs)?,
+        )
+        .await?
+        .decode()?;
+    Ok(decrypted_messages)
+}
+
+async fn receive_messages(app_ws: &AppWebsocket) -> anyhow::Result<Vec<DecryptedMessageOutput>> {
+    let safehold_service_trait_service_id = safehold_service_trait::SAFEHOLD_SERVICE_HASH.to_vec();
+    let messages_outputs: Vec<MessageOutput> = make_service_request(
+        app_ws,
+        safehold_service_trait_service_id.clone(),
+        "get_messages".into(),
+        (),
+    )
+    .await?;
+
+    let message_hashes: Vec<EntryHash> = messages_outputs
+        .iter()
+        .map(|m| m.message_hash.clone())
+        .collect();
+
+    let decrypted_messages: Vec<DecryptedMessageOutput> = app_ws
+        .call_zome(
+            ZomeCallTarget::RoleName("example".into()),
+            "encrypted_messages".into(),
+            "decrypt_messages".into(),
+            ExternIO::encode(messages_outputs).unwrap(),
+        )
+        .await?
+        .decode()?;
+
+    let _response: () = make_service_request(
+        app_ws,
+        safehold_service_trait_service_id.clone(),
+        "ack_messages".into(),
+        message_hashes,
+    )
+    .await?;
+
+    Ok(decrypted_messages)
+}
+
+#[tokio::test(flavor = "multi_thread")]
+#[serial]
+async fn zero_epoch_length_is_rejected() {
+    let bootstrap_srv = run_bootstrap_server().await;
+
+    let result = safehold_service_provider::run(
+        TempDir::new("zero-epoch").unwrap().into_path(),
+        network_config(&bootstrap_srv),
+        String::from("test-app"),
+        service_provider_happ_path(),
+        vec![Progenitor::new().agent_pub_key()],
+        SafeholdQuotas::default(),
+        Duration::ZERO,
+        Duration::ZERO,
+        None,
+        false,
+        None,
+    )
+    .await;
+
+    assert!(result.is_err());
+}
+
+#[test]
+fn only_the_outlier_provider_fails_the_settings_check() {
+    let provider = |byte: u8| AgentPubKey::from_raw_36(vec![byte; 36]);
+    let progenitors = vec![provider(0)];
+
+    // The majority prevails when no progenitor runs as a provider
+    let epoch_lengths = vec![(provider(1), 600), (provider(2), 600), (provider(3), 60)];
+    assert_eq!(
+        agreed_settings(&epoch_lengths, &progenitors),
+        BTreeSet::from([600])
+    );
+
+    // A tie doesn't make any of the tied providers fail
+    let epoch_lengths = vec![(provider(1), 600), (provider(2), 60)];
+    assert_eq!(
+        agreed_settings(&epoch_lengths, &progenitors),
+        BTreeSet::from([60, 600])
+    );
+
+    // The progenitor prevails over the majority
+    let epoch_lengths = vec![
+        (provider(0), 60),
+        (provider(1), 600),
+        (provider(2), 600),
+        (provider(3), 60),
+    ];
+    assert_eq!(
+        agreed_settings(&epoch_lengths, &progenitors),
+        BTreeSet::from([60])
+    );
+
+    // The quotas are agreed on in the same way
+    let strict_quotas = SafeholdQuotas {
+        max_recipients_per_message: 2,
+        ..SafeholdQuotas::default()
+    };
+    let quotas = vec![
+        (provider(1), SafeholdQuotas::default()),
+        (provider(2), strict_quotas.clone()),
+        (provider(3), strict_quotas.clone()),
+    ];
+    assert_eq!(
+        agreed_settings(&quotas, &progenitors),
+        BTreeSet::from([strict_quotas])
+    );
+}
+
+#[test]
+fn the_shards_of_offline_providers_are_swept_by_the_live_ones() {
+    let provider = |byte: u8| AgentPubKey::from_raw_36(vec![byte; 36]);
+    let shards: Vec<EntryHash> = (0..64)
+        .map(|i| EntryHash::from_raw_36(vec![i * 4; 36]))
+        .collect();
+    let providers = vec![provider(0), provider(128), provider(255)];
+
+    // Each shard is swept by exactly one provider
+    let swept: Vec<Vec<EntryHash>> = providers
+        .iter()
+        .map(|p| filter_responsible_shards(shards.clone(), &providers, p))
+        .collect();
+    assert_eq!(swept.iter().map(|s| s.len()).sum::<usize>(), shards.len());
+    assert!(swept.iter().all(|s| !s.is_empty()));
+
+    // Once a provider goes offline, the live ones also sweep its shards, but don't take each other's
+    let live_providers = vec![provider(0), provider(128)];
+    for (i, live_provider) in live_providers.iter().enumerate() {
+        let live_swept = filter_responsible_shards(shards.clone(), &live_providers, live_provider);
+        assert!(swept[i].iter().all(|shard| live_swept.contains(shard)));
+        assert!(live_swept
+            .iter()
+            .all(|shard| swept[i].contains(shard) || swept[2].contains(shard)));
+    }
+    assert_eq!(
+        live_providers
+            .iter()
+            .map(|p| filter_responsible_shards(shards.clone(), &live_providers, p).len())
+            .sum::<usize>(),
+        shards.len()
+    );
+}
+
+#[tokio::test(flavor = "multi_thread")]
+#[serial]
+async fn interrupted_migrations_resume_without_duplicating_or_skipping_messages() {
+    let Scenario {
+        network_seed,
+        progenitor,
+        alice,
+        bob,
+        carol: _carol,
+        bootstrap_srv,
+    } = setup().await;
+
+    let client = SafeholdServiceClient::create(
+        TempDir::new("safehold-service-test").unwrap().into_path(),
+        network_config(&bootstrap_srv),
+        "client-happ".into(),
+        client_happ_path(),
+        vec![progenitor.clone()],
+        false
+    )
+    .await
+    .unwrap();
+
+    client.create_clone_request(network_seed.clone()).await.unwrap();
+
+    wait_for_providers(&alice.0).await.unwrap();
+
+    // A provider of its own, with safehold cells for two epochs that nobody else joins
+    let (provider, _provider_runtime) = launch(
+        progenitor.clone(),
+        vec![],
+        service_provider_happ_path(),
+        network_seed,
+        network_config(&bootstrap_srv),
+    )
+    .await;
+    let mut cells = vec![];
+    for epoch in ["interrupted-migration-from", "interrupted-migration-to"] {
+        let cell = provider
+            .create_clone_cell(CreateCloneCellPayload {
+                role_name: "safehold".into(),
+                modifiers: safehold_dna_modifiers(
+                    vec![progenitor.clone()],
+                    DEFAULT_EPOCH_LENGTH,
+                    SafeholdQuotas::default(),
+                    provisioned_dna_hash(&provider, "proxy").await,
+                    epoch.into(),
+                ),
+                membrane_proof: None,
+                name: None,
+            })
+            .await
+            .unwrap();
+        cells.push(cell);
+    }
+    let (from_cell, to_cell) = (&cells[0], &cells[1]);
+
+    let mut messages_count = 0;
+    for i in 0..6 {
+        let messages: Vec<MessageWithProvenance> = alice
+            .0
+            .call_zome(
+                ZomeCallTarget::RoleName("example".into()),
+                "encrypted_messages".into(),
+                "encrypt_message".into(),
+                ExternIO::encode(EncryptMessageInput {
+                    recipients: vec![bob.0.my_pub_key.clone()],
+                    message: vec![i; 10],
+                    ttl: None,
+                    delivery_receipt: false,
+                    chunk_size: None,
+                    group_mode: false,
+                    sealed_sender: false,
+                })
+                .unwrap(),
+            )
+            .await
+            .unwrap()
+            .decode()
+            .unwrap();
+        messages_count += messages.len();
+        let _r: () = provider
+            .call_zome(
+                ZomeCallTarget::CellId(from_cell.cell_id.clone()),
+                "safehold".into(),
+                "create_messages".into(),
+                ExternIO::encode(messages).unwrap(),
+            )
+            .await
+            .unwrap()
+            .decode()
+            .unwrap();
+    }
+
+    // The first batch of the migration, as done by a provider that stops right after it
+    let first_batch_size = 2;
+    let first_page: ExportMessagesPage = provider
+        .call_zome(
+            ZomeCallTarget::CellId(from_cell.cell_id.clone()),
+            "safehold".into(),
+            "export_undeleted_messages_page".into(),
+            ExternIO::encode(ExportMessagesInput {
+                shards: None,
+                cursor: None,
+                max_count: first_batch_size,
+            })
+            .unwrap(),
+        )
+        .await
+        .unwrap()
+        .decode()
+        .unwrap();
+    assert_eq!(first_page.messages.len(), first_batch_size);
+    // Every exported message is still pending for the mailboxes it was found in
+    assert!(first_page
+        .messages
+        .iter()
+        .all(|m| !m.pending_recipients.is_empty()));
+    assert!(first_page.next_cursor.is_some());
+    let first_counts: MigrationCounts = provider
+        .call_zome(
+            ZomeCallTarget::CellId(to_cell.cell_id.clone()),
+            "safehold".into(),
+            "import_messages".into(),
+            ExternIO::encode(first_page.messages).unwrap(),
+        )
+        .await
+        .unwrap()
+        .decode()
+        .unwrap();
+    assert_eq!(first_counts.migrated, first_batch_size);
+
+    let progress_path =
+        migration_progress_path(&TempDir::new("interrupted-migration").unwrap().into_path());
+    let progress = MigrationProgress {
+        from_dna: from_cell.cell_id.dna_hash().clone(),
+        to_dna: to_cell.cell_id.dna_hash().clone(),
+        shards: None,
+        cursor: first_page.next_cursor.clone(),
+        counts: first_counts.clone(),
+        expired: 0,
+    };
+
+    // Stopped after saving the cursor: the resumed migration continues after the first batch,
+    // and adds up its counts to the ones of the first batch
+    write_migration_progress(&progress_path, &progress).unwrap();
+    let counts = migrate_messages(&provider, &progress_path, from_cell, to_cell, None)
+        .await
+        .unwrap();
+    assert_eq!(counts.migrated, messages_count);
+    assert_eq!(counts.skipped_duplicates, 0);
+    assert_eq!(counts.failed, 0);
+    assert!(read_migration_progress(&progress_path).unwrap().is_none());
+
+    // Stopped before saving the cursor of its last batch: the messages are exported again, but not imported twice
+    write_migration_progress(
+        &progress_path,
+        &MigrationProgress {
+            cursor: None,
+            counts: MigrationCounts::default(),
+            ..progress
+        },
+    )
+    .unwrap();
+    let counts = migrate_messages(&provider, &progress_path, from_cell, to_cell, None)
+        .await
+        .unwrap();
+    assert_eq!(counts.migrated, 0);
+    assert_eq!(counts.skipped_duplicates, messages_count);
+
+    let migrated_messages: Vec<ExportedMessage> = provider
+        .call_zome(
+            ZomeCallTarget::CellId(to_cell.cell_id.clone()),
+            "safehold".into(),
+            "export_undeleted_messages".into(),
+            ExternIO::encode(()).unwrap(),
+        )
+        .await
+        .unwrap()
+        .decode()
+        .unwrap();
+    let distinct_messages: BTreeSet<Vec<u8>> = migrated_messages
+        .iter()
+        .map(|m| ExternIO::encode(&m.message).unwrap().0)
+        .collect();
+    assert_eq!(migrated_messages.len(), messages_count);
+    assert_eq!(distinct_messages.len(), messages_count);
+    assert!(migrated_messages
+        .iter()
+        .all(|m| m.pending_recipients.contains(&bob.0.my_pub_key)));
+
+    // The messages that expire in the old epoch are counted but not exported anymore
+    let expiring_messages: Vec<MessageWithProvenance> = alice
+        .0
+        .call_zome(
+            ZomeCallTarget::RoleName("example".into()),
+            "encrypted_messages".into(),
+            "encrypt_message".into(),
+            ExternIO::encode(EncryptMessageInput {
+                recipients: vec![bob.0.my_pub_key.clone()],
+                message: vec![100; 10],
+                ttl: Some(Duration::from_secs(2)),
+                delivery_receipt: false,
+                chunk_size: None,
+                group_mode: false,
+                sealed_sender: false,
+            })
+            .unwrap(),
+        )
+        .await
+        .unwrap()
+        .decode()
+        .unwrap();
+    let _r: () = provider
+        .call_zome(
+            ZomeCallTarget::CellId(from_cell.cell_id.clone()),
+            "safehold".into(),
+            "create_messages".into(),
+            ExternIO::encode(expiring_messages.clone()).unwrap(),
+        )
+        .await
+        .unwrap()
+        .decode()
+        .unwrap();
+    std::thread::sleep(Duration::from_secs(3));
+
+    let page: ExportMessagesPage = provider
+        .call_zome(
+            ZomeCallTarget::CellId(from_cell.cell_id.clone()),
+            "safehold".into(),
+            "export_undeleted_messages_page".into(),
+            ExternIO::encode(ExportMessagesInput {
+                shards: None,
+                cursor: None,
+                max_count: messages_count + expiring_messages.len(),
+            })
+            .unwrap(),
+        )
+        .await
+        .unwrap()
+        .decode()
+        .unwrap();
+    assert_eq!(page.messages.len(), messages_count);
+    assert_eq!(page.expired, expiring_messages.len());
+    assert!(page
+        .messages
+        .iter()
+        .all(|m| !expiring_messages.contains(&m.message)));
 }
@@ -1,17 +1,65 @@
 use hc_zome_traits::*;
 use hdk::prelude::*;
-use safehold_types::{AgentSpecificContents, MessageContents, MessageWithProvenance};
+use safehold_types::{
+    AgentSpecificContents, MessageContents, MessageWithProvenance, SignedDeliveryReceipt,
+};
 
 #[derive(Clone, Serialize, Deserialize, Debug, PartialEq, SerializedBytes)]
 pub struct MessageOutput {
+    /// Stable identifier of the message, to be passed to `ack_messages` once it has been processed
+    pub message_hash: EntryHash,
     pub provenance: AgentPubKey,
     pub message_contents: MessageContents,
     pub agent_specific_contents: AgentSpecificContents,
 }
 
+/// Position of the last message returned by `get_messages_page`
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+pub struct MessagesCursor {
+    pub timestamp: Timestamp,
+    pub create_link_hash: ActionHash,
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct GetMessagesPageInput {
+    /// Continue after the given cursor, or start from the oldest message if `None`
+    pub cursor: Option<MessagesCursor>,
+    pub max_count: usize,
+    /// Budget for the sum of the message and agent specific contents of the page,
+    /// at least one message is always returned if there are any
+    pub max_bytes: usize,
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct GetMessagesPageForRecipientInput {
+    pub recipient: AgentPubKey,
+    pub page: GetMessagesPageInput,
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct MessagesPage {
+    pub messages: Vec<MessageOutput>,
+    /// `None` when there are no more pending messages after this page
+    pub next_cursor: Option<MessagesCursor>,
+}
+
 #[zome_trait]
 pub trait SafeholdService {
     fn store_messages(message: Vec<MessageWithProvenance>) -> ExternResult<()>;
 
+    /// Returns the pending messages for the caller, without removing them from its mailbox
     fn get_messages(_: ()) -> ExternResult<Vec<MessageOutput>>;
+
+    /// Returns a bounded page of the pending messages for the caller, without removing them from its mailbox
+    fn get_messages_page(input: GetMessagesPageInput) -> ExternResult<MessagesPage>;
+
+    /// Removes the given messages from the caller's mailbox, after they have been processed
+    fn ack_messages(message_hashes: Vec<EntryHash>) -> ExternResult<()>;
+
+    /// Returns the delivery receipts for the given messages sent by the caller with `delivery_receipt` set
+    ///
+    /// The receipts are migrated to the next epochs until their message expires
+    fn get_delivery_receipts(
+        message_hashes: Vec<EntryHash>,
+    ) -> ExternResult<Vec<SignedDeliveryReceipt>>;
 }
@@ -1,15 +1,64 @@
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, BTreeSet};
+use std::time::Duration;
 
 use hdi::prelude::*;
 
 pub type MessageContents = Vec<u8>;
 pub type AgentSpecificContents = Vec<u8>;
 
+pub const DEFAULT_MAX_MESSAGE_TTL: Duration = Duration::from_secs(60 * 60 * 24 * 14); // 14 days
+pub const DEFAULT_EPOCH_LENGTH: Duration = Duration::from_secs(60 * 10); // 10 minutes
+/// Maximum size of a serialized `Message`, enforced by the safehold DNA so that providers can plan their storage
+pub const MAX_MESSAGE_SIZE: usize = 1_000_000; // 1MB
+
+/// Index of the epoch that contains the given timestamp, used as the network seed of its safehold clone
+pub fn time_epoch(timestamp: Timestamp, epoch_length: Duration) -> i64 {
+    let epoch_length_millis = (epoch_length.as_millis() as i64).max(1);
+    timestamp.as_millis() / epoch_length_millis
+}
+
+/// Time at which the given epoch starts, the inverse of `time_epoch`
+pub fn epoch_start(epoch: i64, epoch_length: Duration) -> Timestamp {
+    let epoch_length_millis = (epoch_length.as_millis() as i64).max(1);
+    Timestamp::from_micros(epoch * epoch_length_millis * 1000)
+}
+
 #[derive(Clone, PartialEq)]
 #[hdk_entry_helper]
 pub struct Message {
     pub contents: MessageContents,
+    /// The keys of the recipients are in plaintext, so whoever reads the message from the safehold DHT
+    /// knows to whom it was sent, even if the paths of their mailboxes are blinded
     pub recipients: BTreeMap<AgentPubKey, AgentSpecificContents>,
+    /// Set by the sender: after this time the message won't be delivered nor migrated anymore
+    pub expires_at: Timestamp,
+    /// Set by the sender to get a `DeliveryReceipt` when each recipient acknowledges the message
+    #[serde(default)]
+    pub delivery_receipt: bool,
+    /// The provenance is an ephemeral key that only signs this message, and the real sender
+    /// is authenticated inside the encrypted contents, so that the agents reading the safehold DHT
+    /// can't tell who sent it
+    ///
+    /// The provider that handles the `store_messages` call still sees the agent that made it,
+    /// which is the sender unless it goes through a relay, and checks its quotas by it
+    #[serde(default)]
+    pub sealed_sender: bool,
+}
+
+impl Message {
+    pub fn is_expired(&self, now: Timestamp) -> bool {
+        self.expires_at <= now
+    }
+
+    /// Bytes that this message takes in the mailbox of the given recipient
+    pub fn size_for_recipient(&self, recipient: &AgentPubKey) -> usize {
+        self.contents.len()
+            + self
+                .recipients
+                .get(recipient)
+                .map(|contents| contents.len())
+                .unwrap_or_default()
+    }
 }
 
 #[derive(Clone, PartialEq)]
@@ -18,12 +67,398 @@ pub struct MessageWithProvenance {
     pub provenance: AgentPubKey,
     pub signature: Signature,
     pub message: Message,
+    /// Set when the message is stored by another agent than its provenance, such as another
+    /// device of the sender, that the provenance has authorized to do so
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub delegation: Option<SenderDelegation>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct DeliveryReceipt {
+    pub message_hash: EntryHash,
+    pub recipient: AgentPubKey,
+    pub delivered_at: Timestamp,
+}
+
+/// Delivery receipt signed by the service provider that delivered the message
+#[derive(Clone, PartialEq)]
+#[hdk_entry_helper]
+pub struct SignedDeliveryReceipt {
+    pub receipt: DeliveryReceipt,
+    pub provider: AgentPubKey,
+    pub signature: Signature,
+    /// Delegation with which the provider joined the safehold DHT, `None` if it's a progenitor,
+    /// so that the receipt can be checked without its chain, e.g. once migrated to another epoch
+    pub delegation: Option<ProviderDelegation>,
+}
+
+/// Limits enforced by the safehold gateway when storing messages, set in the properties of the safehold DNA
+///
+/// All the providers must use the same quotas, since they are part of the safehold DNA hash
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct SafeholdQuotas {
+    pub max_messages_per_sender_per_epoch: usize,
+    /// Maximum sum of the sizes of the pending messages for one recipient
+    pub max_mailbox_bytes: usize,
+    pub max_recipients_per_message: usize,
+}
+
+impl Default for SafeholdQuotas {
+    fn default() -> Self {
+        Self {
+            max_messages_per_sender_per_epoch: 10_000,
+            max_mailbox_bytes: 100 * 1024 * 1024, // 100MB
+            max_recipients_per_message: 100,
+        }
+    }
+}
+
+/// Properties for the safehold DNA, compatible with the `roles_types::Properties` the providers set
+#[derive(Serialize, Deserialize, Debug, Clone, SerializedBytes)]
+pub struct SafeholdProperties {
+    pub progenitors: Vec<AgentPubKeyB64>,
+    #[serde(default = "default_max_message_ttl_secs")]
+    pub max_message_ttl_secs: u64,
+    /// Every epoch the providers move over to a new safehold DHT
+    #[serde(default = "default_epoch_length_secs")]
+    pub epoch_length_secs: u64,
+    #[serde(default)]
+    pub quotas: SafeholdQuotas,
+    /// The proxy DNA of the providers, in which the provider delegations that the safehold DNA
+    /// accepts are scoped, since the network seed of each safehold DNA is only its epoch
+    ///
+    /// Without it no provider delegation is accepted
+    #[serde(default)]
+    pub proxy_dna_hash: Option<DnaHashB64>,
+}
+
+fn default_max_message_ttl_secs() -> u64 {
+    DEFAULT_MAX_MESSAGE_TTL.as_secs()
+}
+
+fn default_epoch_length_secs() -> u64 {
+    DEFAULT_EPOCH_LENGTH.as_secs()
+}
+
+impl SafeholdProperties {
+    pub fn new(
+        progenitors: Vec<AgentPubKey>,
+        epoch_length: Duration,
+        quotas: SafeholdQuotas,
+        proxy_dna_hash: DnaHash,
+    ) -> Self {
+        Self {
+            progenitors: progenitors.into_iter().map(|p| p.into()).collect(),
+            max_message_ttl_secs: default_max_message_ttl_secs(),
+            epoch_length_secs: epoch_length.as_secs(),
+            quotas,
+            proxy_dna_hash: Some(proxy_dna_hash.into()),
+        }
+    }
+
+    pub fn epoch_length(&self) -> Duration {
+        Duration::from_secs(self.epoch_length_secs)
+    }
+}
+
+/// Settings that must be the same for all the safehold providers, published in the proxy DNA
+#[derive(Clone, PartialEq)]
+#[hdk_entry_helper]
+pub struct ProviderSettings {
+    pub epoch_length_secs: u64,
+    #[serde(default)]
+    pub quotas: SafeholdQuotas,
+    /// Proves that its author is a safehold provider, only progenitors can publish their settings without it
+    pub delegation: Option<ProviderDelegation>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum QuotaExceeded {
+    TooManyRecipients { max: usize, recipients: usize },
+    /// The sender can store messages again from `retry_at` on, when the next epoch starts
+    TooManyMessagesInEpoch { max: usize, retry_at: Timestamp },
+    MailboxFull { recipient: AgentPubKey, max_bytes: usize },
+}
+
+impl std::fmt::Display for QuotaExceeded {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            QuotaExceeded::TooManyRecipients { max, recipients } => write!(
+                f,
+                "Quota exceeded: message has {recipients} recipients but the maximum is {max}"
+            ),
+            QuotaExceeded::TooManyMessagesInEpoch { max, retry_at } => write!(
+                f,
+                "Quota exceeded: sender can't store more than {max} messages in this epoch, which ends at {retry_at}"
+            ),
+            QuotaExceeded::MailboxFull {
+                recipient,
+                max_bytes,
+            } => write!(
+                f,
+                "Quota exceeded: mailbox for {recipient} would exceed {max_bytes} bytes"
+            ),
+        }
+    }
+}
+
+/// Error returned by the safehold service, which survives being nested in the errors of the
+/// zome calls and remote calls that it goes through, so that clients can decide whether to retry
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum SafeholdError {
+    QuotaExceeded(QuotaExceeded),
+    ProvenanceMismatch,
+    InvalidSignature,
+    InvalidDelegation,
+    /// The provider hasn't joined the safehold DHT of the current epoch yet
+    NoProxiedDna,
+    Network(String),
+    Other(String),
+}
+
+const SAFEHOLD_ERROR_MARKER: &str = "SafeholdError(";
+
+impl SafeholdError {
+    /// Whether the same request may succeed if it's made again later or to another provider
+    pub fn is_retryable(&self) -> bool {
+        matches!(
+            self,
+            SafeholdError::NoProxiedDna | SafeholdError::Network(_)
+        )
+    }
+
+    /// Error message that starts with the hex encoded error, which is not altered by the escaping
+    /// of the messages of the errors that wrap it, followed by its human readable description
+    pub fn to_error_message(&self) -> String {
+        let encoded: String = holochain_serialized_bytes::encode(self)
+            .map(|bytes| bytes.iter().map(|byte| format!("{byte:02x}")).collect())
+            .unwrap_or_default();
+        format!("{SAFEHOLD_ERROR_MARKER}{encoded}) {self}")
+    }
+
+    /// Finds the first `SafeholdError` in the message of an error that may wrap it
+    ///
+    /// Every occurrence of the marker is tried, so that any text that happens to contain it
+    /// doesn't hide the error that comes after it
+    pub fn from_error_message(message: &str) -> Option<Self> {
+        message
+            .match_indices(SAFEHOLD_ERROR_MARKER)
+            .find_map(|(index, marker)| {
+                let encoded = &message[index + marker.len()..];
+                let bytes = decode_hex(&encoded[..encoded.find(')')?])?;
+                holochain_serialized_bytes::decode(&bytes).ok()
+            })
+    }
+
+    /// Keeps the `SafeholdError` that caused the error with the given message, if any
+    pub fn from_message(message: String) -> Self {
+        Self::from_error_message(&message).unwrap_or(SafeholdError::Other(message))
+    }
+
+    pub fn from_wasm_error(error: WasmError) -> Self {
+        Self::from_message(error.to_string())
+    }
+}
+
+/// Works on the bytes of the string, since it may contain multibyte characters if it's not hex
+fn decode_hex(encoded: &str) -> Option<Vec<u8>> {
+    let encoded = encoded.as_bytes();
+    if encoded.len() % 2 != 0 || !encoded.iter().all(|byte| byte.is_ascii_hexdigit()) {
+        return None;
+    }
+    encoded
+        .chunks(2)
+        .map(|pair| u8::from_str_radix(std::str::from_utf8(pair).ok()?, 16).ok())
+        .collect()
+}
+
+impl std::fmt::Display for SafeholdError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SafeholdError::QuotaExceeded(quota_exceeded) => write!(f, "{quota_exceeded}"),
+            SafeholdError::ProvenanceMismatch => {
+                write!(f, "Message provenance is not the caller of store_messages.")
+            }
+            SafeholdError::InvalidSignature => write!(f, "Invalid signature."),
+            SafeholdError::InvalidDelegation => write!(
+                f,
+                "The delegation doesn't authorize the caller to store messages for their provenance in this network, or it has expired."
+            ),
+            SafeholdError::NoProxiedDna => write!(f, "No proxied role found"),
+            SafeholdError::Network(err) => write!(f, "Network error: {err}"),
+            SafeholdError::Other(err) => write!(f, "{err}"),
+        }
+    }
+}
+
+impl From<SafeholdError> for WasmError {
+    fn from(error: SafeholdError) -> Self {
+        wasm_error!(WasmErrorInner::Guest(error.to_error_message()))
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct QuotasUsageInput {
+    pub sender: AgentPubKey,
+    pub recipients: BTreeSet<AgentPubKey>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct QuotasUsage {
+    /// Quotas from the properties of the safehold DNA against which this usage is counted
+    pub quotas: SafeholdQuotas,
+    pub sender_messages_in_epoch: usize,
+    /// End of the current epoch, computed from the epoch length in the properties of the safehold DNA
+    pub epoch_ends_at: Timestamp,
+    pub mailbox_bytes: BTreeMap<AgentPubKey, usize>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct RecordSealedMessagesInput {
+    /// Agent that called the gateway to store the sealed messages, whose provenance is not in them
+    pub sender: AgentPubKey,
+    pub count: usize,
+}
+
+/// Certificate by which the `delegator` authorizes the `delegate` agent, such as another device of theirs,
+/// to store the messages that the delegator has signed through the safehold gateway of one services DNA
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, SerializedBytes)]
+pub struct SenderDelegation {
+    pub delegator: AgentPubKey,
+    pub delegate: AgentPubKey,
+    /// Hash of the services DNA in which the delegation is valid
+    pub services_dna_hash: DnaHash,
+    pub expires_at: Timestamp,
+    /// Signature by the delegator of `SenderDelegation::signed_data`
+    pub signature: Signature,
+}
+
+impl SenderDelegation {
+    pub fn signed_data(
+        delegate: &AgentPubKey,
+        services_dna_hash: &DnaHash,
+        expires_at: Timestamp,
+    ) -> Vec<u8> {
+        delegation_signed_data(
+            SENDER_DELEGATION_PREFIX,
+            delegate,
+            services_dna_hash,
+            expires_at,
+        )
+    }
+
+    pub fn is_expired(&self, now: Timestamp) -> bool {
+        self.expires_at <= now
+    }
+}
+
+/// Certificate by which a progenitor authorizes the `delegate` agent to act as a safehold provider:
+/// to join the safehold DHTs, remove the acknowledged messages and publish its provider settings
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, SerializedBytes)]
+pub struct ProviderDelegation {
+    pub delegator: AgentPubKey,
+    pub delegate: AgentPubKey,
+    /// Hash of the proxy DNA shared by the providers in which the delegation is valid,
+    /// which the safehold DNAs know through their `SafeholdProperties`
+    pub proxy_dna_hash: DnaHash,
+    pub expires_at: Timestamp,
+    /// Signature by the delegator of `ProviderDelegation::signed_data`
+    pub signature: Signature,
+}
+
+impl ProviderDelegation {
+    pub fn signed_data(
+        delegate: &AgentPubKey,
+        proxy_dna_hash: &DnaHash,
+        expires_at: Timestamp,
+    ) -> Vec<u8> {
+        delegation_signed_data(
+            PROVIDER_DELEGATION_PREFIX,
+            delegate,
+            proxy_dna_hash,
+            expires_at,
+        )
+    }
+
+    pub fn is_expired(&self, now: Timestamp) -> bool {
+        self.expires_at <= now
+    }
+}
+
+/// The signed data of each kind of delegation starts with a different prefix,
+/// so that a signature for one of them can't be passed off as the other
+const SENDER_DELEGATION_PREFIX: &[u8] = b"safehold-sender-delegation";
+const PROVIDER_DELEGATION_PREFIX: &[u8] = b"safehold-provider-delegation";
+
+fn delegation_signed_data(
+    prefix: &[u8],
+    delegate: &AgentPubKey,
+    dna_hash: &DnaHash,
+    expires_at: Timestamp,
+) -> Vec<u8> {
+    let mut data = prefix.to_vec();
+    data.extend(delegate.get_raw_39());
+    data.extend(dna_hash.get_raw_39());
+    data.extend(expires_at.as_micros().to_be_bytes());
+    data
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct EncryptMessageInput {
     pub recipients: Vec<AgentPubKey>,
     pub message: MessageContents,
+    /// How long the message will be kept for recipients that haven't fetched it yet
+    pub ttl: Option<Duration>,
+    pub delivery_receipt: bool,
+    /// Size of the contents of each chunk, by default the largest that fits in a `Message`
+    #[serde(default)]
+    pub chunk_size: Option<usize>,
+    /// Fail instead of encrypting the message separately for the recipients that haven't published
+    /// a pre key, so that each chunk is stored once with only a wrapped secret for each recipient
+    #[serde(default)]
+    pub group_mode: bool,
+    /// Hide the sender from the agents reading the safehold DHT, only possible for recipients that have
+    /// published a pre key and without delivery receipts
+    ///
+    /// The provider that stores the messages still sees who calls it, see `Message::sealed_sender`
+    #[serde(default)]
+    pub sealed_sender: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct CreateGroupInput {
+    pub name: String,
+    /// The author of the group is always added to them
+    pub members: Vec<AgentPubKey>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct UpdateGroupMembersInput {
+    /// Hash of the action that created the group
+    pub group_hash: ActionHash,
+    pub members: Vec<AgentPubKey>,
+}
+
+/// Message sent in group mode to all the other members of the group created by the given action
+#[derive(Serialize, Deserialize, Debug)]
+pub struct EncryptGroupMessageInput {
+    pub group_hash: ActionHash,
+    pub message: MessageContents,
+    pub ttl: Option<Duration>,
+    pub delivery_receipt: bool,
+    #[serde(default)]
+    pub chunk_size: Option<usize>,
+    #[serde(default)]
+    pub sealed_sender: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct GetDeliveredRecipientsInput {
+    /// All the messages that `encrypt_message` returned for one logical message
+    pub messages: Vec<MessageWithProvenance>,
+    pub receipts: Vec<SignedDeliveryReceipt>,
+    /// Progenitors of the safehold providers, which sign the receipts or delegate the providers that do
+    pub progenitors: Vec<AgentPubKey>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -32,9 +467,175 @@ pub struct DecryptedMessageOutput {
     pub contents: MessageContents,
 }
 
+/// Message of which only some of the chunks have been received yet
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct IncompleteMessageOutput {
+    /// For sealed sender messages, the ephemeral key that signed them until their first chunk is received
+    pub provenance: AgentPubKey,
+    pub message_id: Vec<u8>,
+    pub received_chunks: usize,
+    /// `None` if the first chunk of the message, which carries its secret, hasn't been received yet
+    pub total_chunks: Option<usize>,
+    pub first_received_at: Timestamp,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct AckMessagesInput {
+    pub recipient: AgentPubKey,
+    pub message_hashes: Vec<EntryHash>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct GetDeliveryReceiptsInput {
+    pub sender: AgentPubKey,
+    pub message_hashes: Vec<EntryHash>,
+}
+
+/// Sent by the safehold providers to the online recipients of newly stored messages,
+/// and emitted by their services cell to the UI
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, SerializedBytes)]
+#[serde(tag = "type")]
+pub enum SafeholdNotification {
+    NewMessages,
+}
+
+/// Message exported from an epoch to be migrated to the next one,
+/// only to the recipients that haven't acknowledged it yet
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ExportedMessage {
+    pub message: MessageWithProvenance,
+    pub pending_recipients: BTreeSet<AgentPubKey>,
+}
+
+/// Delivery receipts of a message, exported with the message so that they can be validated in the new epoch
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ExportedDeliveryReceipts {
+    pub message: MessageWithProvenance,
+    pub receipts: Vec<SignedDeliveryReceipt>,
+}
+
+/// Position of the last message exported by `export_undeleted_messages_page`
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ExportMessagesCursor {
+    pub shard: EntryHash,
+    pub message_hash: EntryHash,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ExportMessagesInput {
+    /// Only export the messages in the mailboxes of these shards, or in all of them if `None`
+    pub shards: Option<Vec<EntryHash>>,
+    /// Continue after the given cursor, or start from the beginning if `None`
+    pub cursor: Option<ExportMessagesCursor>,
+    pub max_count: usize,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ExportMessagesPage {
+    /// Only the messages that haven't expired, which are the ones worth migrating
+    pub messages: Vec<ExportedMessage>,
+    /// Number of expired messages that were left out of this page
+    #[serde(default)]
+    pub expired: usize,
+    /// `None` if there are no more messages to export
+    pub next_cursor: Option<ExportMessagesCursor>,
+}
+
+/// Result of importing the messages migrated from a previous epoch
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct MigrationCounts {
+    pub migrated: usize,
+    /// Messages that had already been migrated, by this or another provider
+    pub skipped_duplicates: usize,
+    pub failed: usize,
+}
+
+impl MigrationCounts {
+    pub fn add(&mut self, other: &MigrationCounts) {
+        self.migrated += other.migrated;
+        self.skipped_duplicates += other.skipped_duplicates;
+        self.failed += other.failed;
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct ProxiedCall {
     pub zome_name: ZomeName,
     pub fn_name: FunctionName,
     pub payload: ExternIO,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn quota_error() -> SafeholdError {
+        SafeholdError::QuotaExceeded(QuotaExceeded::TooManyRecipients {
+            max: 2,
+            recipients: 3,
+        })
+    }
+
+    #[test]
+    fn error_is_found_in_the_messages_that_wrap_it() {
+        let message = format!(
+            "Failed to store messages: {:?}",
+            format!("Remote call failed: {}", quota_error().to_error_message())
+        );
+        assert_eq!(
+            SafeholdError::from_error_message(&message),
+            Some(quota_error())
+        );
+    }
+
+    #[test]
+    fn multibyte_characters_after_the_marker_are_ignored() {
+        let message = format!(
+            "SafeholdError(aéa) {}",
+            SafeholdError::NoProxiedDna.to_error_message()
+        );
+        assert_eq!(
+            SafeholdError::from_error_message(&message),
+            Some(SafeholdError::NoProxiedDna)
+        );
+        assert_eq!(SafeholdError::from_error_message("SafeholdError(éa)"), None);
+    }
+
+    #[test]
+    fn message_without_error_has_none() {
+        assert_eq!(SafeholdError::from_error_message("SafeholdError(zz)"), None);
+        assert_eq!(SafeholdError::from_error_message("SafeholdError(abc"), None);
+        assert_eq!(
+            SafeholdError::from_message(String::from("Other error")),
+            SafeholdError::Other(String::from("Other error"))
+        );
+    }
+
+    #[test]
+    fn delegations_for_different_purposes_sign_different_data() {
+        let delegate = AgentPubKey::from_raw_36(vec![1; 36]);
+        let dna_hash = DnaHash::from_raw_36(vec![2; 36]);
+        let expires_at = Timestamp::from_micros(1_000);
+        assert_ne!(
+            SenderDelegation::signed_data(&delegate, &dna_hash, expires_at),
+            ProviderDelegation::signed_data(&delegate, &dna_hash, expires_at)
+        );
+        assert_ne!(
+            SenderDelegation::signed_data(&delegate, &dna_hash, expires_at),
+            SenderDelegation::signed_data(&delegate, &dna_hash, Timestamp::from_micros(2_000))
+        );
+    }
+
+    #[test]
+    fn epoch_starts_are_inside_their_epoch() {
+        let epoch_length = Duration::from_secs(600);
+        let epoch = time_epoch(Timestamp::from_micros(1_234_567_890_123), epoch_length);
+        let start = epoch_start(epoch, epoch_length);
+
+        assert_eq!(time_epoch(start, epoch_length), epoch);
+        assert_eq!(
+            time_epoch(Timestamp::from_micros(start.as_micros() - 1), epoch_length),
+            epoch - 1
+        );
+    }
+}
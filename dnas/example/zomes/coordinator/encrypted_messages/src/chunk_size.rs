@@ -0,0 +1,140 @@
+use encrypted_messages_integrity::Chunk;
+use hdk::prelude::*;
+use safehold_types::{AgentSpecificContents, Message, MAX_MESSAGE_SIZE};
+
+use crate::utils::to_bytes;
+use crate::{ChunkEnvelope, MessageEncryption, SealedContents, SealedHeader};
+
+/// Bytes take one byte when serialized if they are below 128 and two otherwise,
+/// so the sizes are measured with this one to account for the worst case of random bytes
+const WORST_CASE_BYTE: u8 = 0xFF;
+
+/// Size of the authentication tag that encryption adds to the plaintext
+const ENCRYPTION_TAG_SIZE: usize = 16;
+
+/// Size of the secret of a message that is exported for each recipient
+const SECRET_SIZE: usize = 32;
+
+/// Length of the random message ids
+pub const MESSAGE_ID_SIZE: usize = 8;
+
+fn serialized_size<T>(value: T) -> ExternResult<usize>
+where
+    T: TryInto<SerializedBytes, Error = SerializedBytesError>,
+{
+    let bytes: SerializedBytes = value.try_into().map_err(|err| wasm_error!(err))?;
+    Ok(bytes.bytes().len())
+}
+
+fn worst_case_agent(index: usize) -> AgentPubKey {
+    let mut bytes = vec![WORST_CASE_BYTE; 36];
+    bytes[..8].copy_from_slice(&(index as u64).to_be_bytes());
+    AgentPubKey::from_raw_36(bytes)
+}
+
+fn worst_case_x25519_key() -> X25519PubKey {
+    X25519PubKey::from([WORST_CASE_BYTE; 32])
+}
+
+fn worst_case_encrypted_data(plaintext_size: usize) -> XSalsa20Poly1305EncryptedData {
+    XSalsa20Poly1305EncryptedData::new(
+        XSalsa20Poly1305Nonce::from([WORST_CASE_BYTE; 24]),
+        vec![WORST_CASE_BYTE; plaintext_size + ENCRYPTION_TAG_SIZE],
+    )
+}
+
+/// Largest header that a recipient can have in a message: a sealed ratchet header,
+/// which is larger than the ones that are only encrypted with the agent keys
+fn worst_case_recipient_header() -> ExternResult<AgentSpecificContents> {
+    let message_encryption_size = serialized_size(MessageEncryption::Ratchet {
+        encrypted_secret: worst_case_encrypted_data(SECRET_SIZE),
+        sender_ratchet_key: worst_case_x25519_key(),
+        recipient_ratchet_key: worst_case_x25519_key(),
+        message_number: u32::MAX,
+    })?;
+    let unsealed_header_size = to_bytes(worst_case_encrypted_data(message_encryption_size))?.len();
+
+    let sealed_contents_size = serialized_size(SealedContents {
+        sender: worst_case_agent(0),
+        message_encryption: vec![WORST_CASE_BYTE; message_encryption_size],
+        signature: Signature::from([WORST_CASE_BYTE; 64]),
+    })?;
+    let sealed_header_size = serialized_size(SealedHeader {
+        sender_key: worst_case_x25519_key(),
+        recipient_pre_key: worst_case_x25519_key(),
+        sealed_contents: worst_case_encrypted_data(sealed_contents_size),
+    })?;
+
+    Ok(vec![
+        WORST_CASE_BYTE;
+        unsealed_header_size.max(sealed_header_size)
+    ])
+}
+
+/// Serialized size of the largest message that a chunk of the given size can produce
+/// for the given number of recipients
+fn worst_case_message_size(
+    chunk_size: usize,
+    recipients_count: usize,
+    recipient_header: &AgentSpecificContents,
+) -> ExternResult<usize> {
+    let chunk_bytes_size = serialized_size(Chunk {
+        provenance: worst_case_agent(0),
+        message_id: vec![WORST_CASE_BYTE; MESSAGE_ID_SIZE],
+        chunk_index: usize::MAX,
+        total_chunk_number: usize::MAX,
+        contents: vec![WORST_CASE_BYTE; chunk_size],
+    })?;
+    // Chunks encrypted for agents without ratchet sessions are serialized without the message id,
+    // so they are never larger than the envelopes
+    let envelope_size = serialized_size(ChunkEnvelope {
+        message_id: vec![WORST_CASE_BYTE; MESSAGE_ID_SIZE],
+        encrypted_chunk: worst_case_encrypted_data(chunk_bytes_size),
+    })?;
+
+    serialized_size(Message {
+        contents: vec![WORST_CASE_BYTE; envelope_size],
+        recipients: (0..recipients_count)
+            .map(|i| (worst_case_agent(i), recipient_header.clone()))
+            .collect(),
+        expires_at: Timestamp::MAX,
+        delivery_receipt: true,
+        sealed_sender: true,
+    })
+}
+
+/// Largest chunk size with which the messages for the given number of recipients
+/// don't exceed the maximum message size of the safehold service
+///
+/// It's measured by serializing the largest message that such a chunk can produce
+pub fn max_chunk_size(recipients_count: usize) -> ExternResult<usize> {
+    let recipient_header = worst_case_recipient_header()?;
+    let empty_message_size = worst_case_message_size(0, recipients_count, &recipient_header)?;
+    if empty_message_size >= MAX_MESSAGE_SIZE {
+        return Ok(0);
+    }
+
+    // Every byte of the chunk adds the same number of bytes to the message,
+    // besides the few bytes of the length prefixes of the serialized fields
+    let sample_size = 1_024;
+    let sample_message_size =
+        worst_case_message_size(sample_size, recipients_count, &recipient_header)?;
+    let bytes_per_chunk_byte = (sample_message_size - empty_message_size) / sample_size;
+
+    let mut chunk_size = (MAX_MESSAGE_SIZE - empty_message_size) / bytes_per_chunk_byte;
+    loop {
+        let message_size =
+            worst_case_message_size(chunk_size, recipients_count, &recipient_header)?;
+        if message_size <= MAX_MESSAGE_SIZE {
+            return Ok(chunk_size);
+        }
+        chunk_size = chunk_size
+            .saturating_sub((message_size - MAX_MESSAGE_SIZE).div_ceil(bytes_per_chunk_byte));
+    }
+}
+
+/// Returns the largest chunk size that can be used to send a message to the given number of recipients
+#[hdk_extern]
+pub fn get_max_chunk_size(recipients_count: usize) -> ExternResult<usize> {
+    max_chunk_size(recipients_count)
+}
@@ -2,25 +2,207 @@ use std::collections::BTreeMap;
 
 use encrypted_messages_integrity::*;
 use hdk::prelude::*;
+use safehold_types::IncompleteMessageOutput;
 
-pub fn query_pending_chunks(
-) -> ExternResult<BTreeMap<(MessageId, AgentPubKey), Vec<(ActionHash, Chunk)>>> {
-    let chunks = query_pending_chunks_entries()?;
+use crate::utils::{create_relaxed, delete_relaxed, query_latest_record};
 
-    let mut pending_chunks: BTreeMap<(MessageId, AgentPubKey), Vec<(ActionHash, Chunk)>> =
-        BTreeMap::new();
+pub type TransferId = (MessageId, AgentPubKey);
 
-    for chunk in chunks {
-        pending_chunks
-            .entry((chunk.1.message_id.clone(), chunk.1.provenance.clone()))
-            .or_insert(Default::default())
-            .push(chunk);
+/// Returns the latest pending chunks index, without reading the older indexes nor the rest of the source chain
+fn query_pending_chunks_index() -> ExternResult<Option<PendingChunksIndex>> {
+    let Some(record) = query_latest_record(UnitEntryTypes::PendingChunksIndex)? else {
+        return Ok(None);
+    };
+    let Some(entry) = record.entry().as_option() else {
+        return Err(wasm_error!("PendingChunksIndex record has no entry"));
+    };
+    let index = PendingChunksIndex::try_from(entry)?;
+    Ok(Some(index))
+}
+
+/// Returns the messages of which only some chunks have been received,
+/// and whether they were found in an index
+pub fn query_pending_transfers() -> ExternResult<(BTreeMap<TransferId, PendingTransfer>, bool)> {
+    let (transfers, indexed) = match query_pending_chunks_index()? {
+        Some(index) => (index.transfers, true),
+        // The chunks received before the index was introduced
+        None => (unindexed_pending_transfers()?, false),
+    };
+
+    let transfers = transfers
+        .into_iter()
+        .map(|transfer| {
+            (
+                (transfer.message_id.clone(), transfer.provenance.clone()),
+                transfer,
+            )
+        })
+        .collect();
+
+    Ok((transfers, indexed))
+}
+
+pub fn write_pending_chunks_index(
+    transfers: &BTreeMap<TransferId, PendingTransfer>,
+) -> ExternResult<()> {
+    create_relaxed(EntryTypes::PendingChunksIndex(PendingChunksIndex {
+        transfers: transfers.values().cloned().collect(),
+    }))?;
+    Ok(())
+}
+
+/// Fetches the chunks received so far for the given transfer
+pub fn get_transfer_chunks(transfer: &PendingTransfer) -> ExternResult<Vec<(ActionHash, Chunk)>> {
+    let get_inputs: Vec<GetInput> = transfer
+        .chunks
+        .values()
+        .map(|action_hash| GetInput::new(action_hash.clone().into(), GetOptions::local()))
+        .collect();
+
+    let chunks = HDK
+        .with(|hdk| hdk.borrow().get(get_inputs))?
+        .into_iter()
+        .filter_map(|r| r)
+        .filter_map(|r| {
+            let action_hash = r.action_address().clone();
+            let Some(entry) = r.entry.into_option() else {
+                return None;
+            };
+            let Ok(chunk) = Chunk::try_from(entry) else {
+                return None;
+            };
+            Some((action_hash, chunk))
+        })
+        .collect();
+
+    Ok(chunks)
+}
+
+/// Fetches the chunks of the given transfer that were received before the secret of its message
+pub fn get_transfer_encrypted_chunks(
+    transfer: &PendingTransfer,
+) -> ExternResult<Vec<(ActionHash, EncryptedChunk)>> {
+    let get_inputs: Vec<GetInput> = transfer
+        .encrypted_chunks
+        .iter()
+        .map(|action_hash| GetInput::new(action_hash.clone().into(), GetOptions::local()))
+        .collect();
+
+    let encrypted_chunks = HDK
+        .with(|hdk| hdk.borrow().get(get_inputs))?
+        .into_iter()
+        .filter_map(|r| r)
+        .filter_map(|r| {
+            let action_hash = r.action_address().clone();
+            let Some(entry) = r.entry.into_option() else {
+                return None;
+            };
+            let Ok(encrypted_chunk) = EncryptedChunk::try_from(entry) else {
+                return None;
+            };
+            Some((action_hash, encrypted_chunk))
+        })
+        .collect();
+
+    Ok(encrypted_chunks)
+}
+
+/// Deletes the chunks of the messages that weren't completed before the configured timeout,
+/// returning the number of expired messages
+pub fn expire_pending_transfers(
+    transfers: &mut BTreeMap<TransferId, PendingTransfer>,
+) -> ExternResult<usize> {
+    let timeout = encrypted_messages_properties()?.pending_chunks_timeout();
+    let now = sys_time()?;
+
+    let expired_transfers: Vec<TransferId> = transfers
+        .iter()
+        .filter(|(_, transfer)| {
+            now.as_micros() - transfer.first_received_at.as_micros() > timeout.as_micros() as i64
+        })
+        .map(|(transfer_id, _)| transfer_id.clone())
+        .collect();
+
+    for transfer_id in &expired_transfers {
+        let Some(transfer) = transfers.remove(transfer_id) else {
+            continue;
+        };
+        warn!(
+            "Deleting {} of the {} chunks of an incomplete message from {}.",
+            transfer.chunks.len() + transfer.encrypted_chunks.len(),
+            transfer
+                .total_chunk_number
+                .map(|total| total.to_string())
+                .unwrap_or(String::from("unknown")),
+            transfer.provenance
+        );
+        for chunk_hash in transfer.chunks.into_values() {
+            delete_relaxed(chunk_hash)?;
+        }
+        for encrypted_chunk_hash in transfer.encrypted_chunks {
+            delete_relaxed(encrypted_chunk_hash)?;
+        }
+    }
+
+    Ok(expired_transfers.len())
+}
+
+/// Lists the messages of which only some chunks have been received, with their progress
+#[hdk_extern]
+pub fn get_incomplete_messages() -> ExternResult<Vec<IncompleteMessageOutput>> {
+    let (transfers, _) = query_pending_transfers()?;
+
+    Ok(transfers
+        .into_values()
+        .map(|transfer| IncompleteMessageOutput {
+            provenance: transfer.sealed_sender.unwrap_or(transfer.provenance),
+            message_id: transfer.message_id,
+            received_chunks: transfer.chunks.len() + transfer.encrypted_chunks.len(),
+            total_chunks: transfer.total_chunk_number,
+            first_received_at: transfer.first_received_at,
+        })
+        .collect())
+}
+
+/// Deletes the chunks of the incomplete messages that have timed out,
+/// which is also done on every `decrypt_messages` call
+#[hdk_extern]
+pub fn delete_expired_chunks() -> ExternResult<usize> {
+    let (mut transfers, indexed) = query_pending_transfers()?;
+    let expired_count = expire_pending_transfers(&mut transfers)?;
+
+    if expired_count > 0 || !indexed {
+        write_pending_chunks_index(&transfers)?;
+    }
+
+    Ok(expired_count)
+}
+
+fn unindexed_pending_transfers() -> ExternResult<Vec<PendingTransfer>> {
+    let now = sys_time()?;
+    let mut transfers: BTreeMap<TransferId, PendingTransfer> = BTreeMap::new();
+
+    for (action_hash, chunk) in query_pending_chunks_entries()? {
+        transfers
+            .entry((chunk.message_id.clone(), chunk.provenance.clone()))
+            .or_insert(PendingTransfer {
+                provenance: chunk.provenance.clone(),
+                message_id: chunk.message_id.clone(),
+                total_chunk_number: Some(chunk.total_chunk_number),
+                first_received_at: now,
+                chunks: BTreeMap::new(),
+                key_ref: None,
+                encrypted_chunks: vec![],
+                sealed_sender: None,
+            })
+            .chunks
+            .insert(chunk.chunk_index, action_hash);
     }
 
-    Ok(pending_chunks)
+    Ok(transfers.into_values().collect())
 }
 
-pub fn query_pending_chunks_entries() -> ExternResult<Vec<(ActionHash, Chunk)>> {
+fn query_pending_chunks_entries() -> ExternResult<Vec<(ActionHash, Chunk)>> {
     let records = query(
         ChainQueryFilter::new()
             .entry_type(UnitEntryTypes::Chunk.try_into()?)
@@ -0,0 +1,31 @@
+use hdk::prelude::*;
+use safehold_types::SenderDelegation;
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct CreateSenderDelegationInput {
+    pub delegate: AgentPubKey,
+    /// Hash of the services DNA through which the delegate will store the messages
+    pub services_dna_hash: DnaHash,
+    pub expires_at: Timestamp,
+}
+
+/// Authorizes the given agent, such as another device of ours, to store in the safehold service
+/// the messages that we have signed, by setting their `delegation`
+#[hdk_extern]
+pub fn create_sender_delegation(
+    input: CreateSenderDelegationInput,
+) -> ExternResult<SenderDelegation> {
+    let my_pub_key = agent_info()?.agent_initial_pubkey;
+    let signature = sign_raw(
+        my_pub_key.clone(),
+        SenderDelegation::signed_data(&input.delegate, &input.services_dna_hash, input.expires_at),
+    )?;
+
+    Ok(SenderDelegation {
+        delegator: my_pub_key,
+        delegate: input.delegate,
+        services_dna_hash: input.services_dna_hash,
+        expires_at: input.expires_at,
+        signature,
+    })
+}
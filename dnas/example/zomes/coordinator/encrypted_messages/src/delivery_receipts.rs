@@ -0,0 +1,86 @@
+use std::collections::BTreeSet;
+
+use hdk::prelude::*;
+use safehold_types::{
+    GetDeliveredRecipientsInput, MessageWithProvenance, ProviderDelegation, SignedDeliveryReceipt,
+};
+
+/// Returns the hashes with which the given messages are stored in the safehold service,
+/// to be passed to its `get_delivery_receipts` function
+#[hdk_extern]
+pub fn get_message_hashes(messages: Vec<MessageWithProvenance>) -> ExternResult<Vec<EntryHash>> {
+    messages.iter().map(hash_entry).collect()
+}
+
+/// Returns the recipients that have received all the given messages, as signed in the delivery receipts
+#[hdk_extern]
+pub fn get_delivered_recipients(
+    input: GetDeliveredRecipientsInput,
+) -> ExternResult<Vec<AgentPubKey>> {
+    let mut delivered: BTreeSet<(EntryHash, AgentPubKey)> = BTreeSet::new();
+
+    for signed_receipt in input.receipts {
+        if is_valid_receipt(&signed_receipt, &input.progenitors)? {
+            delivered.insert((
+                signed_receipt.receipt.message_hash,
+                signed_receipt.receipt.recipient,
+            ));
+        }
+    }
+
+    let mut recipients: BTreeSet<AgentPubKey> = BTreeSet::new();
+    let mut pending_recipients: BTreeSet<AgentPubKey> = BTreeSet::new();
+
+    for message in input.messages {
+        let message_hash = hash_entry(&message)?;
+        for recipient in message.message.recipients.into_keys() {
+            if delivered.contains(&(message_hash.clone(), recipient.clone())) {
+                recipients.insert(recipient);
+            } else {
+                pending_recipients.insert(recipient);
+            }
+        }
+    }
+
+    Ok(recipients
+        .difference(&pending_recipients)
+        .cloned()
+        .collect())
+}
+
+/// Only the progenitors and the providers they delegated can sign the receipts,
+/// otherwise any agent could claim that a message was delivered
+fn is_valid_receipt(
+    signed_receipt: &SignedDeliveryReceipt,
+    progenitors: &[AgentPubKey],
+) -> ExternResult<bool> {
+    if !progenitors.contains(&signed_receipt.provider) {
+        let Some(delegation) = &signed_receipt.delegation else {
+            return Ok(false);
+        };
+        if delegation.delegate.ne(&signed_receipt.provider)
+            || !progenitors.contains(&delegation.delegator)
+            || delegation.is_expired(signed_receipt.receipt.delivered_at)
+        {
+            return Ok(false);
+        }
+        let valid_delegation = verify_signature_raw(
+            delegation.delegator.clone(),
+            delegation.signature.clone(),
+            ProviderDelegation::signed_data(
+                &delegation.delegate,
+                &delegation.proxy_dna_hash,
+                delegation.expires_at,
+            ),
+        )?;
+        if !valid_delegation {
+            return Ok(false);
+        }
+    }
+
+    verify_signature(
+        signed_receipt.provider.clone(),
+        signed_receipt.signature.clone(),
+        &signed_receipt.receipt,
+    )
+}
@@ -0,0 +1,265 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use encrypted_messages_integrity::*;
+use hdk::prelude::*;
+use safehold_types::{
+    CreateGroupInput, EncryptGroupMessageInput, EncryptMessageInput, MessageWithProvenance,
+    UpdateGroupMembersInput,
+};
+
+use crate::encrypt_message;
+
+/// Latest version of a group, along with the hash of the action that created it
+struct GroupVersion {
+    group_hash: ActionHash,
+    author: AgentPubKey,
+    group: Group,
+}
+
+/// Creates a group of which we are a member, and links it from each of its members
+/// so that they can accept it and send messages to it
+///
+/// Returns the hash that identifies the group, since there can be many groups with the same name
+#[hdk_extern]
+pub fn create_group(input: CreateGroupInput) -> ExternResult<ActionHash> {
+    let mut members: BTreeSet<AgentPubKey> = input.members.into_iter().collect();
+    members.insert(agent_info()?.agent_initial_pubkey);
+
+    let group_hash = create_entry(EntryTypes::Group(Group {
+        name: input.name,
+        members: members.clone(),
+    }))?;
+    create_entry(EntryTypes::GroupAcceptance(GroupAcceptance {
+        group_hash: group_hash.clone(),
+    }))?;
+
+    for member in members {
+        create_link(member, group_hash.clone(), LinkTypes::MemberToGroups, ())?;
+    }
+
+    Ok(group_hash)
+}
+
+/// Accepts to be a member of a group to which we were added, so that we can send messages to it
+#[hdk_extern]
+pub fn accept_group(group_hash: ActionHash) -> ExternResult<()> {
+    if query_accepted_groups()?.contains(&group_hash) {
+        return Ok(());
+    }
+    let my_pub_key = agent_info()?.agent_initial_pubkey;
+    let Some(version) = get_latest_group(group_hash.clone())? else {
+        return Err(wasm_error!("The group {} was not found.", group_hash));
+    };
+    if !version.group.members.contains(&my_pub_key) {
+        return Err(wasm_error!(
+            "We are not a member of the group {}.",
+            group_hash
+        ));
+    }
+
+    create_entry(EntryTypes::GroupAcceptance(GroupAcceptance { group_hash }))?;
+
+    Ok(())
+}
+
+/// Returns the latest version of the groups that we accepted and of which we are still a member
+#[hdk_extern]
+pub fn get_groups() -> ExternResult<BTreeMap<ActionHash, Group>> {
+    let mut groups: BTreeMap<ActionHash, Group> = BTreeMap::new();
+    for group_hash in query_accepted_groups()? {
+        if let Some(version) = find_group(&group_hash)? {
+            groups.insert(group_hash, version.group);
+        }
+    }
+    Ok(groups)
+}
+
+/// Returns the groups to which other agents added us and that we haven't accepted yet
+#[hdk_extern]
+pub fn get_group_invitations() -> ExternResult<BTreeMap<ActionHash, Group>> {
+    let my_pub_key = agent_info()?.agent_initial_pubkey;
+    let accepted_groups = query_accepted_groups()?;
+    let links = get_links(
+        GetLinksInputBuilder::try_new(my_pub_key.clone(), LinkTypes::MemberToGroups)?.build(),
+    )?;
+
+    let mut invitations: BTreeMap<ActionHash, Group> = BTreeMap::new();
+    for link in links {
+        let Some(group_hash) = link.target.into_action_hash() else {
+            continue;
+        };
+        if accepted_groups.contains(&group_hash) {
+            continue;
+        }
+        let Some(version) = get_latest_group(group_hash.clone())? else {
+            continue;
+        };
+        // Our link may not have been deleted yet after we were removed from the group
+        if version.group.members.contains(&my_pub_key) {
+            invitations.insert(group_hash, version.group);
+        }
+    }
+
+    Ok(invitations)
+}
+
+/// Replaces the members of a group that we created, we always stay as a member
+#[hdk_extern]
+pub fn update_group_members(input: UpdateGroupMembersInput) -> ExternResult<()> {
+    let my_pub_key = agent_info()?.agent_initial_pubkey;
+
+    let Some(version) = find_group(&input.group_hash)? else {
+        return Err(wasm_error!("There is no group {}.", input.group_hash));
+    };
+    if version.author.ne(&my_pub_key) {
+        return Err(wasm_error!(
+            "Only the author of the group {} can update its members.",
+            input.group_hash
+        ));
+    }
+
+    let mut members: BTreeSet<AgentPubKey> = input.members.into_iter().collect();
+    members.insert(my_pub_key);
+
+    // All the updates point to the original action, so that the latest one is found with a single get
+    update_entry(
+        version.group_hash.clone(),
+        &Group {
+            name: version.group.name.clone(),
+            members: members.clone(),
+        },
+    )?;
+    for removed_member in version.group.members.difference(&members) {
+        let links = get_links(
+            GetLinksInputBuilder::try_new(removed_member.clone(), LinkTypes::MemberToGroups)?
+                .build(),
+        )?;
+        for link in links {
+            if link.target.into_action_hash().as_ref() == Some(&version.group_hash) {
+                delete_link(link.create_link_hash, GetOptions::default())?;
+            }
+        }
+    }
+
+    for added_member in members.difference(&version.group.members) {
+        create_link(
+            added_member.clone(),
+            version.group_hash.clone(),
+            LinkTypes::MemberToGroups,
+            (),
+        )?;
+    }
+
+    Ok(())
+}
+
+/// Returns the latest version of the given group, if we accepted it and we are still a member
+#[hdk_extern]
+pub fn get_group(group_hash: ActionHash) -> ExternResult<Option<Group>> {
+    Ok(find_group(&group_hash)?.map(|version| version.group))
+}
+
+/// Encrypts the message in group mode for all the other members of the group,
+/// so that each of its chunks is stored only once
+#[hdk_extern]
+pub fn encrypt_group_message(
+    input: EncryptGroupMessageInput,
+) -> ExternResult<Vec<MessageWithProvenance>> {
+    let Some(version) = find_group(&input.group_hash)? else {
+        return Err(wasm_error!("There is no group {}.", input.group_hash));
+    };
+
+    let my_pub_key = agent_info()?.agent_initial_pubkey;
+    let recipients: Vec<AgentPubKey> = version
+        .group
+        .members
+        .into_iter()
+        .filter(|member| member.ne(&my_pub_key))
+        .collect();
+    if recipients.is_empty() {
+        return Err(wasm_error!(
+            "The group {} has no other members.",
+            input.group_hash
+        ));
+    }
+
+    encrypt_message(EncryptMessageInput {
+        recipients,
+        message: input.message,
+        ttl: input.ttl,
+        delivery_receipt: input.delivery_receipt,
+        chunk_size: input.chunk_size,
+        group_mode: true,
+        sealed_sender: input.sealed_sender,
+    })
+}
+
+/// Returns the latest version of the given group, ignoring it if we haven't accepted it
+/// or if we were removed from it
+fn find_group(group_hash: &ActionHash) -> ExternResult<Option<GroupVersion>> {
+    if !query_accepted_groups()?.contains(group_hash) {
+        return Ok(None);
+    }
+    let my_pub_key = agent_info()?.agent_initial_pubkey;
+    let Some(version) = get_latest_group(group_hash.clone())? else {
+        return Ok(None);
+    };
+    if !version.group.members.contains(&my_pub_key) {
+        return Ok(None);
+    }
+    Ok(Some(version))
+}
+
+/// The groups that we accepted are recorded in private entries in our source chain
+fn query_accepted_groups() -> ExternResult<BTreeSet<ActionHash>> {
+    let records = query(
+        ChainQueryFilter::new()
+            .include_entries(true)
+            .entry_type(UnitEntryTypes::GroupAcceptance.try_into()?),
+    )?;
+
+    Ok(records
+        .into_iter()
+        .filter_map(|record| {
+            let entry = record.entry().as_option()?;
+            let group_acceptance = GroupAcceptance::try_from(entry).ok()?;
+            Some(group_acceptance.group_hash)
+        })
+        .collect())
+}
+
+fn get_latest_group(group_hash: ActionHash) -> ExternResult<Option<GroupVersion>> {
+    let Some(Details::Record(details)) = get_details(group_hash.clone(), GetOptions::default())?
+    else {
+        return Ok(None);
+    };
+    if !details.deletes.is_empty() {
+        return Ok(None);
+    }
+
+    let author = details.record.action().author().clone();
+    let latest_record = match details
+        .updates
+        .into_iter()
+        .max_by_key(|update| update.action().timestamp())
+    {
+        Some(update) => get(update.action_address().clone(), GetOptions::default())?,
+        None => Some(details.record),
+    };
+    let Some(latest_record) = latest_record else {
+        return Ok(None);
+    };
+    let Some(group) = latest_record
+        .entry()
+        .to_app_option::<Group>()
+        .map_err(|err| wasm_error!(err))?
+    else {
+        return Ok(None);
+    };
+
+    Ok(Some(GroupVersion {
+        group_hash,
+        author,
+        group,
+    }))
+}
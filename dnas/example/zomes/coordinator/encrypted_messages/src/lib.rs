@@ -1,9 +1,16 @@
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, BTreeSet};
+use std::time::Duration;
 
-use chunks::query_pending_chunks;
-use encrypted_messages_integrity::{Chunk, EntryTypes, MessageId, PeerKeys};
+use chunk_size::{max_chunk_size, MESSAGE_ID_SIZE};
+use chunks::{
+    expire_pending_transfers, get_transfer_chunks, get_transfer_encrypted_chunks,
+    query_pending_transfers, write_pending_chunks_index, TransferId,
+};
+use encrypted_messages_integrity::{Chunk, EncryptedChunk, EntryTypes, MessageId, PendingTransfer};
 use hdk::prelude::*;
-use peer_keys::query_peer_keys;
+use peer_keys::{advance_receiving_session, advance_sending_sessions, PeerKey};
+use pre_keys::{get_pre_key, publish_pre_key, query_my_pre_key, rotate_pre_key};
+use processed_messages::{is_processed, query_processed_messages, record_processed_messages};
 use utils::{create_relaxed, delete_relaxed, from_bytes, to_bytes};
 
 use safehold_service_trait::MessageOutput;
@@ -12,47 +19,195 @@ use safehold_types::{
     MessageWithProvenance,
 };
 
+mod chunk_size;
 mod chunks;
+mod delegations;
+mod delivery_receipts;
+mod groups;
 mod peer_keys;
+mod pre_keys;
+mod processed_messages;
+mod safehold_service;
 mod utils;
 
-pub const CHUNK_SIZE: usize = 2_000; // 2B
+pub const DEFAULT_MESSAGE_TTL: Duration = Duration::from_secs(60 * 60 * 24 * 7); // 7 days
 
 #[derive(Serialize, Deserialize, Debug, SerializedBytes)]
 pub enum MessageEncryption {
+    /// Only sent to peers that run a previous version, which don't know about ratchet sessions
     Secret {
         encrypted_secret: XSalsa20Poly1305EncryptedData,
         sender_encryption_key: X25519PubKey,
         recipient_encryption_key: X25519PubKey,
     },
+    /// The contents are encrypted with the agent keys, only used if the recipient
+    /// hasn't published a pre key, e.g. because it runs a previous version, and never
+    /// used again once it's known to support ratchet sessions
+    ///
+    /// Anyone who gets the agent key of either of them can decrypt these messages
     SigningKey {
         sender_key: AgentPubKey,
         recipient_key: AgentPubKey,
         sender_encryption_key: X25519PubKey,
     },
+    /// The secret of the message is exported with a new key of the sender and the last key announced
+    /// by the recipient, so that each message advances the ratchet of the session between them
+    ///
+    /// This rotates the keys of each message, so that getting the agent key of either of them
+    /// doesn't expose these messages, and the pre key that starts the session is replaced after it
+    /// is used, but the private keys can't be deleted from the keystores of the agents,
+    /// so whoever gets access to the whole keystore of either of them can still decrypt them
+    ///
+    /// Only the first chunk carries it, and the contents of all the chunks are `ChunkEnvelope`s
+    Ratchet {
+        encrypted_secret: XSalsa20Poly1305EncryptedData,
+        sender_ratchet_key: X25519PubKey,
+        recipient_ratchet_key: X25519PubKey,
+        message_number: u32,
+    },
+}
+
+/// Contents of the messages sent to recipients with a ratchet session
+///
+/// The message id is visible so that the chunks received before the secret of their message
+/// can be kept until it arrives
+#[derive(Serialize, Deserialize, Debug, SerializedBytes)]
+pub struct ChunkEnvelope {
+    pub message_id: MessageId,
+    pub encrypted_chunk: XSalsa20Poly1305EncryptedData,
+}
+
+/// Header of a sealed sender message for one of its recipients, encrypted for its pre key
+/// with a key that is only used once
+#[derive(Serialize, Deserialize, Debug, SerializedBytes)]
+pub struct SealedHeader {
+    pub sender_key: X25519PubKey,
+    pub recipient_pre_key: X25519PubKey,
+    pub sealed_contents: XSalsa20Poly1305EncryptedData,
+}
+
+/// Contents of a `SealedHeader`: the serialized `MessageEncryption` signed by the real sender
+#[derive(Serialize, Deserialize, Debug, SerializedBytes)]
+pub struct SealedContents {
+    pub sender: AgentPubKey,
+    pub message_encryption: Vec<u8>,
+    pub signature: Signature,
+}
+
+/// A chunk received from the safehold service
+enum ReceivedChunk {
+    /// With the reference to the secret of its message, if it carried it,
+    /// and the sender authenticated in its header, if it was sealed
+    Decrypted {
+        chunk: Chunk,
+        key_ref: Option<XSalsa20Poly1305KeyRef>,
+        sealed_sender: Option<AgentPubKey>,
+    },
+    /// Its message's secret hasn't been received yet
+    Encrypted(EncryptedChunk),
 }
 
 fn new_message_id() -> ExternResult<Vec<u8>> {
-    let bytes = random_bytes(8)?;
+    let bytes = random_bytes(MESSAGE_ID_SIZE as u32)?;
     Ok(bytes.to_vec())
 }
 
+#[hdk_extern]
+pub fn init() -> ExternResult<InitCallbackResult> {
+    publish_pre_key()?;
+    Ok(InitCallbackResult::Pass)
+}
+
 #[hdk_extern]
 pub fn encrypt_message(input: EncryptMessageInput) -> ExternResult<Vec<MessageWithProvenance>> {
     let key_ref = x_salsa20_poly1305_shared_secret_create_random(None)?;
 
-    let mut messages: Vec<MessageWithProvenance> = vec![];
+    let mut messages: Vec<Message> = vec![];
 
     let agent_info = agent_info()?;
 
     let message_id = new_message_id()?;
 
-    let chunks: Vec<&[u8]> = input.message.chunks(CHUNK_SIZE).into_iter().collect();
+    let ttl = input.ttl.unwrap_or(DEFAULT_MESSAGE_TTL);
+    let expires_at = Timestamp::from_micros(sys_time()?.as_micros() + ttl.as_micros() as i64);
+
+    let max_chunk_size = max_chunk_size(input.recipients.len())?;
+    let chunk_size = input.chunk_size.unwrap_or(max_chunk_size);
+    if chunk_size == 0 || chunk_size > max_chunk_size {
+        return Err(wasm_error!(
+            "The chunk size must be between 1 and {} bytes for {} recipients, but was {}.",
+            max_chunk_size,
+            input.recipients.len(),
+            chunk_size
+        ));
+    }
+    if input.sealed_sender && input.delivery_receipt {
+        return Err(wasm_error!(
+            "Sealed sender messages can't request delivery receipts."
+        ));
+    }
+
+    let chunks: Vec<&[u8]> = input.message.chunks(chunk_size).into_iter().collect();
 
     debug!("Encrypting message into {} chunks.", chunks.len());
 
+    // The sessions advance once per message with a single new key of ours, and its secret
+    // is exported once for each recipient
+    let mut ratchet_recipients: BTreeMap<AgentPubKey, AgentSpecificContents> = BTreeMap::new();
+    let mut legacy_recipients: BTreeMap<AgentPubKey, AgentSpecificContents> = BTreeMap::new();
+    let mut signing_key_recipients: BTreeMap<AgentPubKey, X25519PubKey> = BTreeMap::new();
+
+    let new_key = create_x25519_keypair()?;
+    let sessions = advance_sending_sessions(&input.recipients, new_key)?;
+
+    for (recipient, (peer_key, message_number)) in sessions {
+        match peer_key {
+            Some(PeerKey::Ratchet(their_current_key)) => {
+                let message_encryption = MessageEncryption::Ratchet {
+                    encrypted_secret: x_salsa20_poly1305_shared_secret_export(
+                        new_key,
+                        their_current_key,
+                        key_ref.clone(),
+                    )?,
+                    sender_ratchet_key: new_key,
+                    recipient_ratchet_key: their_current_key,
+                    message_number,
+                };
+                let header = match input.sealed_sender {
+                    true => seal_for_recipient(&recipient, message_encryption)?,
+                    false => encrypt_for_recipient(&recipient, message_encryption)?,
+                };
+                ratchet_recipients.insert(recipient.clone(), header);
+            }
+            // Their messages would need a separate ciphertext, and their headers can't be sealed
+            Some(PeerKey::Legacy(_)) | None if input.group_mode || input.sealed_sender => {
+                return Err(wasm_error!(
+                    "Can't send a group or sealed sender message to {}, which hasn't published a pre key.",
+                    recipient
+                ));
+            }
+            Some(PeerKey::Legacy(their_current_key)) => {
+                let message_encryption = MessageEncryption::Secret {
+                    encrypted_secret: x_salsa20_poly1305_shared_secret_export(
+                        new_key,
+                        their_current_key,
+                        key_ref.clone(),
+                    )?,
+                    sender_encryption_key: new_key,
+                    recipient_encryption_key: their_current_key,
+                };
+                legacy_recipients.insert(
+                    recipient.clone(),
+                    encrypt_for_recipient(&recipient, message_encryption)?,
+                );
+            }
+            None => {
+                signing_key_recipients.insert(recipient, new_key);
+            }
+        }
+    }
+
     for (i, chunk_contents) in chunks.iter().enumerate() {
-        let mut agent_keys: BTreeMap<AgentPubKey, AgentSpecificContents> = BTreeMap::new();
         let chunk = Chunk {
             provenance: agent_info.agent_initial_pubkey.clone(),
             message_id: message_id.clone(),
@@ -64,77 +219,70 @@ pub fn encrypt_message(input: EncryptMessageInput) -> ExternResult<Vec<MessageWi
             SerializedBytes::try_from(chunk).map_err(|err| wasm_error!(err))?;
         let chunk_bytes = chunk_serialized_bytes.bytes().to_vec();
 
-        for recipient in input.recipients.clone() {
-            let new_key = create_x25519_keypair()?;
+        for (recipient, new_key) in &signing_key_recipients {
+            let encrypted_data = ed_25519_x_salsa20_poly1305_encrypt(
+                agent_info.agent_initial_pubkey.clone(),
+                recipient.clone(),
+                XSalsa20Poly1305Data::from(chunk_bytes.clone()),
+            )?;
+            let encrypted_message_bytes = to_bytes(encrypted_data)?;
 
-            let their_current_key = query_peer_keys(&recipient)?
-                .map(|p| p.their_current_key)
-                .flatten();
+            let mut recipients: BTreeMap<AgentPubKey, AgentSpecificContents> = BTreeMap::new();
 
-            let new_peer_keys = PeerKeys {
-                peer: recipient.clone(),
-                my_current_key: Some(new_key),
-                their_current_key: their_current_key.clone(),
+            let message_encryption = MessageEncryption::SigningKey {
+                sender_key: agent_info.agent_initial_pubkey.clone(),
+                sender_encryption_key: *new_key,
+                recipient_key: recipient.clone(),
             };
-            create_relaxed(EntryTypes::PeerKeys(new_peer_keys))?;
-
-            if let Some(their_current_key) = their_current_key {
-                let encrypted_secret = x_salsa20_poly1305_shared_secret_export(
-                    new_key,
-                    their_current_key,
-                    key_ref.clone(),
-                )?;
-
-                let encrypted_message_secret = MessageEncryption::Secret {
-                    sender_encryption_key: new_key,
-                    recipient_encryption_key: their_current_key,
-                    encrypted_secret,
-                };
-                let message_encryption_bytes = SerializedBytes::try_from(encrypted_message_secret)
-                    .map_err(|err| wasm_error!(err))?;
-
-                let encrypted_data = ed_25519_x_salsa20_poly1305_encrypt(
-                    agent_info.agent_initial_pubkey.clone(),
-                    recipient.clone(),
-                    XSalsa20Poly1305Data::from(message_encryption_bytes.bytes().clone()),
-                )?;
-
-                agent_keys.insert(recipient, to_bytes(encrypted_data)?);
-            } else {
-                let encrypted_data = ed_25519_x_salsa20_poly1305_encrypt(
-                    agent_info.agent_initial_pubkey.clone(),
-                    recipient.clone(),
-                    XSalsa20Poly1305Data::from(chunk_bytes.clone()),
-                )?;
-                let encrypted_message_bytes = to_bytes(encrypted_data)?;
+            recipients.insert(
+                recipient.clone(),
+                encrypt_for_recipient(recipient, message_encryption)?,
+            );
 
-                let mut recipients: BTreeMap<AgentPubKey, AgentSpecificContents> = BTreeMap::new();
+            let encrypted_message = Message {
+                contents: encrypted_message_bytes,
+                recipients,
+                expires_at,
+                delivery_receipt: input.delivery_receipt,
+                sealed_sender: input.sealed_sender,
+            };
 
-                let message_encryption = MessageEncryption::SigningKey {
-                    sender_key: agent_info.agent_initial_pubkey.clone(),
-                    sender_encryption_key: new_key,
-                    recipient_key: recipient.clone(),
-                };
-                let message_encryption_bytes = SerializedBytes::try_from(message_encryption)
-                    .map_err(|err| wasm_error!(err))?;
+            messages.push(encrypted_message);
+        }
 
-                let encrypted_data = ed_25519_x_salsa20_poly1305_encrypt(
-                    agent_info.agent_initial_pubkey.clone(),
-                    recipient.clone(),
-                    XSalsa20Poly1305Data::from(message_encryption_bytes.bytes().clone()),
-                )?;
-                recipients.insert(recipient.clone(), to_bytes(encrypted_data)?);
+        if !ratchet_recipients.is_empty() {
+            let encrypted_chunk = x_salsa20_poly1305_encrypt(
+                key_ref.clone(),
+                XSalsa20Poly1305Data::from(chunk_bytes.clone()),
+            )?;
+            let envelope = ChunkEnvelope {
+                message_id: message_id.clone(),
+                encrypted_chunk,
+            };
+            let envelope_bytes =
+                SerializedBytes::try_from(envelope).map_err(|err| wasm_error!(err))?;
+
+            // Only the first chunk carries the secret, the rest carry only their ciphertext
+            let recipients: BTreeMap<AgentPubKey, AgentSpecificContents> = match i {
+                0 => ratchet_recipients.clone(),
+                _ => ratchet_recipients
+                    .keys()
+                    .map(|recipient| (recipient.clone(), vec![]))
+                    .collect(),
+            };
 
-                let encrypted_message = Message {
-                    contents: encrypted_message_bytes,
-                    recipients,
-                };
+            let message = Message {
+                contents: envelope_bytes.bytes().to_vec(),
+                recipients,
+                expires_at,
+                delivery_receipt: input.delivery_receipt,
+                sealed_sender: input.sealed_sender,
+            };
 
-                messages.push(sign_message(encrypted_message)?);
-            }
+            messages.push(message);
         }
 
-        if agent_keys.len() > 0 {
+        if !legacy_recipients.is_empty() {
             let encrypted_message = x_salsa20_poly1305_encrypt(
                 key_ref.clone(),
                 XSalsa20Poly1305Data::from(chunk_bytes),
@@ -143,13 +291,24 @@ pub fn encrypt_message(input: EncryptMessageInput) -> ExternResult<Vec<MessageWi
 
             let message = Message {
                 contents: encrypted_message_bytes,
-                recipients: agent_keys,
+                recipients: legacy_recipients.clone(),
+                expires_at,
+                delivery_receipt: input.delivery_receipt,
+                sealed_sender: input.sealed_sender,
             };
 
-            messages.push(sign_message(message)?);
+            messages.push(message);
         }
     }
 
+    let messages = match input.sealed_sender {
+        true => sign_messages_ephemeral(messages)?,
+        false => messages
+            .into_iter()
+            .map(sign_message)
+            .collect::<ExternResult<Vec<MessageWithProvenance>>>()?,
+    };
+
     info!(
         "Successfully encrypted message into {} messages.",
         messages.len()
@@ -158,6 +317,99 @@ pub fn encrypt_message(input: EncryptMessageInput) -> ExternResult<Vec<MessageWi
     Ok(messages)
 }
 
+/// Encrypts the message encryption header with the agent keys, so that only the recipient
+/// can know which keys were used
+fn encrypt_for_recipient(
+    recipient: &AgentPubKey,
+    message_encryption: MessageEncryption,
+) -> ExternResult<AgentSpecificContents> {
+    let message_encryption_bytes =
+        SerializedBytes::try_from(message_encryption).map_err(|err| wasm_error!(err))?;
+
+    let encrypted_data = ed_25519_x_salsa20_poly1305_encrypt(
+        agent_info()?.agent_initial_pubkey,
+        recipient.clone(),
+        XSalsa20Poly1305Data::from(message_encryption_bytes.bytes().clone()),
+    )?;
+
+    to_bytes(encrypted_data)
+}
+
+/// Encrypts the message encryption header for the pre key of the recipient with a new key,
+/// along with our signature of it, so that the recipient can authenticate us without the
+/// readers of the safehold DHT being able to tell who the header comes from
+fn seal_for_recipient(
+    recipient: &AgentPubKey,
+    message_encryption: MessageEncryption,
+) -> ExternResult<AgentSpecificContents> {
+    let Some(recipient_pre_key) = get_pre_key(recipient.clone())? else {
+        return Err(wasm_error!("{} hasn't published a pre key.", recipient));
+    };
+    let my_pub_key = agent_info()?.agent_initial_pubkey;
+
+    let message_encryption_bytes = SerializedBytes::try_from(message_encryption)
+        .map_err(|err| wasm_error!(err))?
+        .bytes()
+        .to_vec();
+    let signature = sign_raw(my_pub_key.clone(), message_encryption_bytes.clone())?;
+    let sealed_contents = SealedContents {
+        sender: my_pub_key,
+        message_encryption: message_encryption_bytes,
+        signature,
+    };
+    let sealed_contents_bytes =
+        SerializedBytes::try_from(sealed_contents).map_err(|err| wasm_error!(err))?;
+
+    let sender_key = create_x25519_keypair()?;
+    let sealed_header = SealedHeader {
+        sender_key,
+        recipient_pre_key,
+        sealed_contents: x_25519_x_salsa20_poly1305_encrypt(
+            sender_key,
+            recipient_pre_key,
+            XSalsa20Poly1305Data::from(sealed_contents_bytes.bytes().clone()),
+        )?,
+    };
+    let sealed_header_bytes =
+        SerializedBytes::try_from(sealed_header).map_err(|err| wasm_error!(err))?;
+
+    Ok(sealed_header_bytes.bytes().to_vec())
+}
+
+/// Opens the header of a sealed sender message, returning the sender that signed it
+fn open_sealed_header(
+    sealed_header: SealedHeader,
+) -> ExternResult<(AgentPubKey, MessageEncryption)> {
+    let Some(sealed_contents_bytes) = x_25519_x_salsa20_poly1305_decrypt(
+        sealed_header.recipient_pre_key,
+        sealed_header.sender_key,
+        sealed_header.sealed_contents,
+    )?
+    else {
+        return Err(wasm_error!("Failed to open the sealed header."));
+    };
+    let sealed_contents = SealedContents::try_from(SerializedBytes::from(UnsafeBytes::from(
+        sealed_contents_bytes.as_ref().to_vec(),
+    )))
+    .map_err(|err| wasm_error!(err))?;
+
+    let valid = verify_signature_raw(
+        sealed_contents.sender.clone(),
+        sealed_contents.signature,
+        sealed_contents.message_encryption.clone(),
+    )?;
+    if !valid {
+        return Err(wasm_error!("Invalid signature of the sealed header."));
+    }
+
+    let encryption = MessageEncryption::try_from(SerializedBytes::from(UnsafeBytes::from(
+        sealed_contents.message_encryption,
+    )))
+    .map_err(|err| wasm_error!(err))?;
+
+    Ok((sealed_contents.sender, encryption))
+}
+
 fn sign_message(message: Message) -> ExternResult<MessageWithProvenance> {
     let my_pub_key = agent_info()?.agent_initial_pubkey;
 
@@ -170,54 +422,202 @@ fn sign_message(message: Message) -> ExternResult<MessageWithProvenance> {
         provenance: my_pub_key,
         signature,
         message,
+        delegation: None,
     })
 }
 
+/// Signs all the messages with the same ephemeral key, which becomes their provenance,
+/// so that the chunks of a sealed sender message can still be put together
+fn sign_messages_ephemeral(messages: Vec<Message>) -> ExternResult<Vec<MessageWithProvenance>> {
+    let hashes = messages
+        .iter()
+        .map(|message| {
+            let bytes =
+                SerializedBytes::try_from(message.clone()).map_err(|err| wasm_error!(err))?;
+            hash_blake2b(bytes.bytes().to_vec(), 32)
+        })
+        .collect::<ExternResult<Vec<Vec<u8>>>>()?;
+
+    let ephemeral_signatures = sign_ephemeral(hashes)?;
+
+    Ok(messages
+        .into_iter()
+        .zip(ephemeral_signatures.signatures)
+        .map(|(message, signature)| MessageWithProvenance {
+            provenance: ephemeral_signatures.key.clone(),
+            signature,
+            message,
+            delegation: None,
+        })
+        .collect())
+}
+
 #[hdk_extern]
 pub fn decrypt_messages(messages: Vec<MessageOutput>) -> ExternResult<Vec<DecryptedMessageOutput>> {
-    let pending_chunks = query_pending_chunks()?;
+    let (mut transfers, indexed) = query_pending_transfers()?;
+    let mut transfers_changed = !indexed;
+    if expire_pending_transfers(&mut transfers)? > 0 {
+        transfers_changed = true;
+    }
+    let processed_messages = query_processed_messages()?;
+    let my_pre_key = query_my_pre_key()?;
+    let mut pre_key_consumed = false;
     let mut new_chunks: BTreeMap<(MessageId, HoloHash<hash_type::Agent>), Vec<Chunk>> =
         BTreeMap::new();
+    let mut new_encrypted_chunks: Vec<EncryptedChunk> = vec![];
+    let mut message_keys: BTreeMap<TransferId, XSalsa20Poly1305KeyRef> = transfers
+        .iter()
+        .filter_map(|(transfer_id, transfer)| {
+            Some((transfer_id.clone(), transfer.key_ref.clone()?))
+        })
+        .collect();
+    // The transfers of sealed sender messages are identified by their ephemeral provenance,
+    // while their chunks are authenticated by the real sender
+    let mut sealed_senders: BTreeMap<TransferId, AgentPubKey> = transfers
+        .iter()
+        .filter_map(|(transfer_id, transfer)| {
+            Some((transfer_id.clone(), transfer.sealed_sender.clone()?))
+        })
+        .collect();
+
+    // The messages that carry a secret go first, so that the chunks of their message can be decrypted
+    let mut messages = messages;
+    messages.sort_by_key(|message| message.agent_specific_contents.is_empty());
 
     for message in messages {
         let provenance = message.provenance.clone();
-        let result = decrypt_message(message);
-        let Ok(chunk) = result else {
-            error!("Failed to decrypt message: {:?}", result);
-            continue;
+        let result = decrypt_message(message, &message_keys, my_pre_key, &mut pre_key_consumed);
+        let (chunk, key_ref, sealed_sender) = match result {
+            Ok(ReceivedChunk::Decrypted {
+                chunk,
+                key_ref,
+                sealed_sender,
+            }) => (chunk, key_ref, sealed_sender),
+            Ok(ReceivedChunk::Encrypted(encrypted_chunk)) => {
+                // The real sender of sealed sender chunks is only known once they are decrypted
+                if !is_processed(
+                    &processed_messages,
+                    &encrypted_chunk.provenance,
+                    &encrypted_chunk.message_id,
+                ) {
+                    new_encrypted_chunks.push(encrypted_chunk);
+                }
+                continue;
+            }
+            Err(err) => {
+                error!("Failed to decrypt message: {:?}", err);
+                continue;
+            }
         };
 
-        if chunk.provenance.ne(&provenance) {
+        let transfer_id: TransferId = (chunk.message_id.clone(), provenance.clone());
+        if let Some(sealed_sender) = sealed_sender {
+            sealed_senders.insert(transfer_id.clone(), sealed_sender);
+        }
+
+        if chunk
+            .provenance
+            .ne(sealed_senders.get(&transfer_id).unwrap_or(&provenance))
+        {
             error!("Invalid provenance for chunk.");
             continue;
         }
 
-        new_chunks
-            .entry((chunk.message_id.clone(), chunk.provenance.clone()))
-            .or_insert(Default::default())
-            .push(chunk);
+        // The same message can be delivered more than once, e.g. after being migrated between epochs
+        if is_processed(&processed_messages, &chunk.provenance, &chunk.message_id) {
+            debug!("Dropping an already processed message.");
+            continue;
+        }
+
+        if let Some(key_ref) = key_ref {
+            message_keys.insert(transfer_id.clone(), key_ref);
+        }
+
+        let chunks = new_chunks.entry(transfer_id).or_insert(Default::default());
+        if chunks.iter().any(|c| c.chunk_index == chunk.chunk_index) {
+            continue;
+        }
+        chunks.push(chunk);
+    }
+
+    let now = sys_time()?;
+
+    for encrypted_chunk in new_encrypted_chunks {
+        let transfer_id: TransferId = (
+            encrypted_chunk.message_id.clone(),
+            encrypted_chunk.provenance.clone(),
+        );
+        let transfer = transfers.entry(transfer_id).or_insert(PendingTransfer {
+            provenance: encrypted_chunk.provenance.clone(),
+            message_id: encrypted_chunk.message_id.clone(),
+            total_chunk_number: None,
+            first_received_at: now,
+            chunks: BTreeMap::new(),
+            key_ref: None,
+            encrypted_chunks: vec![],
+            sealed_sender: None,
+        });
+        let encrypted_chunk_hash = create_relaxed(EntryTypes::EncryptedChunk(encrypted_chunk))?;
+        transfer.encrypted_chunks.push(encrypted_chunk_hash);
+        transfers_changed = true;
+    }
+
+    // Decrypt the chunks that were waiting for the secrets received in this call
+    for (transfer_id, transfer) in transfers.iter_mut() {
+        if transfer.encrypted_chunks.is_empty() {
+            continue;
+        }
+        let Some(key_ref) = message_keys.get(transfer_id) else {
+            continue;
+        };
+        let sender = sealed_senders
+            .get(transfer_id)
+            .unwrap_or(&transfer.provenance);
+        for (encrypted_chunk_hash, encrypted_chunk) in get_transfer_encrypted_chunks(transfer)? {
+            match decrypt_chunk(
+                key_ref.clone(),
+                &encrypted_chunk.message_id,
+                from_bytes(encrypted_chunk.contents)?,
+            ) {
+                Ok(chunk) if chunk.provenance.eq(sender) => {
+                    let chunks = new_chunks.entry(transfer_id.clone()).or_default();
+                    if chunks.iter().all(|c| c.chunk_index != chunk.chunk_index) {
+                        chunks.push(chunk);
+                    }
+                }
+                result => error!("Failed to decrypt a pending chunk: {:?}", result),
+            }
+            delete_relaxed(encrypted_chunk_hash)?;
+        }
+        transfer.encrypted_chunks.clear();
+        transfers_changed = true;
     }
 
     let mut decrypted_messages: Vec<DecryptedMessageOutput> = vec![];
+    let mut newly_processed: BTreeSet<(AgentPubKey, MessageId)> = BTreeSet::new();
 
     for ((message_id, provenance), new_chunks) in new_chunks {
-        let pending_chunks = pending_chunks
-            .get(&(message_id.clone(), provenance.clone()))
-            .cloned()
-            .unwrap_or(vec![]);
+        let transfer_id: TransferId = (message_id.clone(), provenance.clone());
+        let pending_chunks = match transfers.get(&transfer_id) {
+            Some(transfer) => get_transfer_chunks(transfer)?,
+            None => vec![],
+        };
+
+        // Drop the chunks that were already received in a previous call
+        let new_chunks: Vec<Chunk> = new_chunks
+            .into_iter()
+            .filter(|chunk| {
+                pending_chunks
+                    .iter()
+                    .all(|(_, pending_chunk)| pending_chunk.chunk_index != chunk.chunk_index)
+            })
+            .collect();
 
         let Some(chunk) = new_chunks.first() else {
-            warn!("No chunks found while decrypting a message.");
+            debug!("Dropping already received chunks.");
             continue;
         };
 
-        if chunk.total_chunk_number != new_chunks.len() + pending_chunks.len() {
-            for chunk in new_chunks {
-                create_relaxed(EntryTypes::Chunk(chunk))?;
-            }
-            continue;
-        }
-
         let mut chunks: Vec<&Chunk> = new_chunks
             .iter()
             .chain(pending_chunks.iter().map(|c| &c.1))
@@ -225,15 +625,38 @@ pub fn decrypt_messages(messages: Vec<MessageOutput>) -> ExternResult<Vec<Decryp
 
         chunks.sort_by_key(|c| c.chunk_index);
 
-        let all_chunks_found = chunks
-            .iter()
-            .enumerate()
-            .all(|(i, chunk)| chunk.chunk_index == i);
+        let all_chunks_found = chunk.total_chunk_number == chunks.len()
+            && chunks
+                .iter()
+                .enumerate()
+                .all(|(i, chunk)| chunk.chunk_index == i);
 
         if !all_chunks_found {
+            let transfer = transfers
+                .entry(transfer_id.clone())
+                .or_insert(PendingTransfer {
+                    provenance: provenance.clone(),
+                    message_id: message_id.clone(),
+                    total_chunk_number: Some(chunk.total_chunk_number),
+                    first_received_at: now,
+                    chunks: BTreeMap::new(),
+                    key_ref: None,
+                    encrypted_chunks: vec![],
+                    sealed_sender: None,
+                });
+            transfer.total_chunk_number = Some(chunk.total_chunk_number);
+            if transfer.key_ref.is_none() {
+                transfer.key_ref = message_keys.get(&transfer_id).cloned();
+            }
+            if transfer.sealed_sender.is_none() {
+                transfer.sealed_sender = sealed_senders.get(&transfer_id).cloned();
+            }
             for chunk in new_chunks {
-                create_relaxed(EntryTypes::Chunk(chunk))?;
+                let chunk_index = chunk.chunk_index;
+                let chunk_hash = create_relaxed(EntryTypes::Chunk(chunk))?;
+                transfer.chunks.insert(chunk_index, chunk_hash);
             }
+            transfers_changed = true;
             continue;
         }
 
@@ -247,46 +670,105 @@ pub fn decrypt_messages(messages: Vec<MessageOutput>) -> ExternResult<Vec<Decryp
         for (chunk_hash, _pending_chunk) in &pending_chunks {
             delete_relaxed(chunk_hash.clone())?;
         }
+        if transfers.remove(&transfer_id).is_some() {
+            transfers_changed = true;
+        }
 
         let all_bytes: Vec<u8> = chunks
             .into_iter()
             .map(|c| c.contents.clone())
             .flatten()
             .collect();
+        let sender = sealed_senders.remove(&transfer_id).unwrap_or(provenance);
+        newly_processed.insert((sender.clone(), message_id));
         decrypted_messages.push(DecryptedMessageOutput {
-            provenance,
+            provenance: sender,
             contents: all_bytes,
         });
     }
 
+    record_processed_messages(processed_messages, newly_processed)?;
+
+    if pre_key_consumed {
+        rotate_pre_key()?;
+    }
+
+    if transfers_changed {
+        write_pending_chunks_index(&transfers)?;
+    }
+
     Ok(decrypted_messages)
 }
 
-fn decrypt_message(message: MessageOutput) -> ExternResult<Chunk> {
-    let agent_info = agent_info()?;
-
-    let decrypted_data = ed_25519_x_salsa20_poly1305_decrypt(
-        agent_info.agent_initial_pubkey.clone(),
-        message.provenance.clone(),
-        from_bytes(message.agent_specific_contents)?,
-    )?;
+/// Sets `pre_key_consumed` if the message started a session with our current pre key
+fn decrypt_message(
+    message: MessageOutput,
+    message_keys: &BTreeMap<TransferId, XSalsa20Poly1305KeyRef>,
+    my_pre_key: Option<X25519PubKey>,
+    pre_key_consumed: &mut bool,
+) -> ExternResult<ReceivedChunk> {
+    // The chunks after the first one only carry their ciphertext
+    if message.agent_specific_contents.is_empty() {
+        let envelope = deserialize_envelope(message.message_contents)?;
+        let transfer_id: TransferId = (envelope.message_id.clone(), message.provenance.clone());
+
+        let Some(key_ref) = message_keys.get(&transfer_id) else {
+            return Ok(ReceivedChunk::Encrypted(EncryptedChunk {
+                provenance: message.provenance,
+                message_id: envelope.message_id,
+                contents: to_bytes(envelope.encrypted_chunk)?,
+            }));
+        };
 
-    let bytes = SerializedBytes::from(UnsafeBytes::from(decrypted_data.as_ref().to_vec()));
+        let chunk = decrypt_chunk(
+            key_ref.clone(),
+            &envelope.message_id,
+            envelope.encrypted_chunk,
+        )?;
+        return Ok(ReceivedChunk::Decrypted {
+            chunk,
+            key_ref: None,
+            sealed_sender: None,
+        });
+    }
 
-    let encryption = MessageEncryption::try_from(bytes).map_err(|err| wasm_error!(err))?;
+    let (sender, encryption, sealed_sender) =
+        match deserialize_sealed_header(message.agent_specific_contents.clone()) {
+            Ok(sealed_header) => {
+                let (sender, encryption) = open_sealed_header(sealed_header)?;
+                (sender.clone(), encryption, Some(sender))
+            }
+            Err(_) => {
+                let decrypted_data = ed_25519_x_salsa20_poly1305_decrypt(
+                    agent_info()?.agent_initial_pubkey,
+                    message.provenance.clone(),
+                    from_bytes(message.agent_specific_contents)?,
+                )?;
+                let bytes =
+                    SerializedBytes::from(UnsafeBytes::from(decrypted_data.as_ref().to_vec()));
+                let encryption =
+                    MessageEncryption::try_from(bytes).map_err(|err| wasm_error!(err))?;
+                (message.provenance.clone(), encryption, None)
+            }
+        };
 
-    let their_new_key = match &encryption {
+    let (their_new_key, message_number) = match &encryption {
         MessageEncryption::Secret {
             sender_encryption_key: sender_key,
             ..
-        } => sender_key.clone(),
+        } => (sender_key.clone(), None),
         MessageEncryption::SigningKey {
             sender_encryption_key: new_sender_key,
             ..
-        } => new_sender_key.clone(),
+        } => (new_sender_key.clone(), None),
+        MessageEncryption::Ratchet {
+            sender_ratchet_key,
+            message_number,
+            ..
+        } => (*sender_ratchet_key, Some(*message_number)),
     };
 
-    let decrypted_message = match encryption {
+    let received_chunk = match encryption {
         MessageEncryption::Secret {
             encrypted_secret,
             sender_encryption_key: sender_key,
@@ -303,7 +785,11 @@ fn decrypt_message(message: MessageOutput) -> ExternResult<Chunk> {
             else {
                 return Err(wasm_error!("Failed to decrypt the message."));
             };
-            decrypted_message.as_ref().to_vec()
+            ReceivedChunk::Decrypted {
+                chunk: deserialize_chunk(decrypted_message.as_ref().to_vec())?,
+                key_ref: None,
+                sealed_sender,
+            }
         }
         MessageEncryption::SigningKey {
             sender_key,
@@ -316,31 +802,75 @@ fn decrypt_message(message: MessageOutput) -> ExternResult<Chunk> {
                 from_bytes(message.message_contents)?,
             )?;
 
-            decrypted_message.as_ref().to_vec()
+            ReceivedChunk::Decrypted {
+                chunk: deserialize_chunk(decrypted_message.as_ref().to_vec())?,
+                key_ref: None,
+                sealed_sender,
+            }
+        }
+        MessageEncryption::Ratchet {
+            encrypted_secret,
+            sender_ratchet_key,
+            recipient_ratchet_key,
+            ..
+        } => {
+            if my_pre_key.eq(&Some(recipient_ratchet_key)) {
+                *pre_key_consumed = true;
+            }
+            let key_ref = x_salsa20_poly1305_shared_secret_ingest(
+                recipient_ratchet_key,
+                sender_ratchet_key,
+                encrypted_secret,
+                None,
+            )?;
+            let envelope = deserialize_envelope(message.message_contents)?;
+            let chunk = decrypt_chunk(
+                key_ref.clone(),
+                &envelope.message_id,
+                envelope.encrypted_chunk,
+            )?;
+            ReceivedChunk::Decrypted {
+                chunk,
+                key_ref: Some(key_ref),
+                sealed_sender,
+            }
         }
     };
 
-    let their_current_key = query_peer_keys(&message.provenance)?
-        .map(|p| p.their_current_key)
-        .flatten();
+    advance_receiving_session(&sender, their_new_key, message_number)?;
 
-    let changed_key = match their_current_key {
-        Some(key) => key.ne(&their_new_key),
-        None => true,
-    };
+    Ok(received_chunk)
+}
 
-    if changed_key {
-        let new_peer_keys = PeerKeys {
-            peer: message.provenance.clone(),
-            my_current_key: None,
-            their_current_key: Some(their_new_key.clone()),
-        };
-        create_relaxed(EntryTypes::PeerKeys(new_peer_keys))?;
+/// Decrypts a chunk with the secret of its message, checking that it belongs to the given message
+fn decrypt_chunk(
+    key_ref: XSalsa20Poly1305KeyRef,
+    message_id: &MessageId,
+    encrypted_chunk: XSalsa20Poly1305EncryptedData,
+) -> ExternResult<Chunk> {
+    let Some(decrypted_chunk) = x_salsa20_poly1305_decrypt(key_ref, encrypted_chunk)? else {
+        return Err(wasm_error!("Failed to decrypt the chunk."));
+    };
+    let chunk = deserialize_chunk(decrypted_chunk.as_ref().to_vec())?;
+    if chunk.message_id.ne(message_id) {
+        return Err(wasm_error!("The chunk belongs to a different message."));
     }
+    Ok(chunk)
+}
 
-    let bytes = SerializedBytes::from(UnsafeBytes::from(decrypted_message));
-    let chunk = Chunk::try_from(bytes)
-        .map_err(|err| wasm_error!("Failed to deserialize chunk: {:?}", err))?;
+fn deserialize_chunk(bytes: Vec<u8>) -> ExternResult<Chunk> {
+    let bytes = SerializedBytes::from(UnsafeBytes::from(bytes));
+    Chunk::try_from(bytes).map_err(|err| wasm_error!("Failed to deserialize chunk: {:?}", err))
+}
 
-    Ok(chunk)
+fn deserialize_sealed_header(bytes: Vec<u8>) -> ExternResult<SealedHeader> {
+    let bytes = SerializedBytes::from(UnsafeBytes::from(bytes));
+    SealedHeader::try_from(bytes)
+        .map_err(|err| wasm_error!("Failed to deserialize sealed header: {:?}", err))
+}
+
+fn deserialize_envelope(bytes: Vec<u8>) -> ExternResult<ChunkEnvelope> {
+    let bytes = SerializedBytes::from(UnsafeBytes::from(bytes));
+    ChunkEnvelope::try_from(bytes)
+        .map_err(|err| wasm_error!("Failed to deserialize chunk envelope: {:?}", err))
 }
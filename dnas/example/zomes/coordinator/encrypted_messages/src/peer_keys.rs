@@ -1,6 +1,9 @@
 use encrypted_messages_integrity::*;
 use hdk::prelude::*;
 
+use crate::pre_keys::get_pre_key;
+use crate::utils::create_relaxed;
+
 pub fn query_all_peer_keys() -> ExternResult<Vec<(Record, PeerKeys)>> {
     let records = query(
         ChainQueryFilter::new()
@@ -24,20 +27,140 @@ pub fn query_all_peer_keys() -> ExternResult<Vec<(Record, PeerKeys)>> {
     Ok(peer_keys)
 }
 
-pub fn query_peer_keys(recipient: &AgentPubKey) -> ExternResult<Option<PeerKeys>> {
-    let peer_keys = query_all_peer_keys()?;
+/// Returns the latest state of the session with each peer, scanning the source chain only once
+pub fn query_latest_peer_keys() -> ExternResult<BTreeMap<AgentPubKey, PeerKeys>> {
+    let mut latest_peer_keys: BTreeMap<AgentPubKey, (Timestamp, PeerKeys)> = BTreeMap::new();
 
-    let recipient_peer_keys: Vec<(Record, PeerKeys)> = peer_keys
-        .into_iter()
-        .filter(|(_, peer_keys)| peer_keys.peer.eq(recipient))
-        .collect();
+    for (record, peer_keys) in query_all_peer_keys()? {
+        let timestamp = record.action().timestamp();
+        let is_latest = latest_peer_keys
+            .get(&peer_keys.peer)
+            .map(|(latest, _)| *latest <= timestamp)
+            .unwrap_or(true);
+        if is_latest {
+            latest_peer_keys.insert(peer_keys.peer.clone(), (timestamp, peer_keys));
+        }
+    }
 
-    let Some((_, last_peer_key)) = recipient_peer_keys
+    Ok(latest_peer_keys
         .into_iter()
-        .max_by_key(|(r, _)| r.action().timestamp())
-    else {
-        return Ok(None);
-    };
+        .map(|(peer, (_, peer_keys))| (peer, peer_keys))
+        .collect())
+}
+
+pub fn query_peer_keys(recipient: &AgentPubKey) -> ExternResult<Option<PeerKeys>> {
+    Ok(query_latest_peer_keys()?.remove(recipient))
+}
+
+/// Returns the state of the session with the given peer, if any message was exchanged with it
+#[hdk_extern]
+pub fn get_peer_session(peer: AgentPubKey) -> ExternResult<Option<PeerKeys>> {
+    query_peer_keys(&peer)
+}
+
+/// Key of the peer to encrypt a new message to
+pub enum PeerKey {
+    /// The last key announced by the peer in a numbered message, or its published pre key
+    Ratchet(X25519PubKey),
+    /// The last key announced by a peer that runs a previous version without ratchet sessions
+    Legacy(X25519PubKey),
+}
+
+/// Advances the sessions with all the recipients of a new message, announcing to all of them
+/// the same new key of ours
+///
+/// The source chain is scanned only once for all the recipients, however many they are
+///
+/// Returns the key of each peer to encrypt the message to, if any, and the number of the message
+pub fn advance_sending_sessions(
+    peers: &[AgentPubKey],
+    new_key: X25519PubKey,
+) -> ExternResult<BTreeMap<AgentPubKey, (Option<PeerKey>, u32)>> {
+    let mut latest_peer_keys = query_latest_peer_keys()?;
+    let mut sessions: BTreeMap<AgentPubKey, (Option<PeerKey>, u32)> = BTreeMap::new();
+
+    for peer in peers {
+        if sessions.contains_key(peer) {
+            continue;
+        }
+        let peer_keys = latest_peer_keys.remove(peer);
+        let their_current_key = peer_keys.as_ref().and_then(|p| p.their_current_key);
+        let received_messages = peer_keys.as_ref().map(|p| p.received_messages).unwrap_or(0);
+        let sent_messages = peer_keys.as_ref().map(|p| p.sent_messages).unwrap_or(0) + 1;
+        let ratchet = peer_keys.as_ref().is_some_and(|p| p.ratchet);
+
+        let peer_key = match their_current_key {
+            Some(key) if received_messages > 0 => Some(PeerKey::Ratchet(key)),
+            _ => match get_pre_key(peer.clone())? {
+                Some(pre_key) => Some(PeerKey::Ratchet(pre_key)),
+                // Falling back to the agent keys would expose the message to whoever gets them
+                None if ratchet => {
+                    return Err(wasm_error!(
+                        "The pre key of {} can't be found, retry later.",
+                        peer
+                    ))
+                }
+                None => their_current_key.map(PeerKey::Legacy),
+            },
+        };
+
+        create_relaxed(EntryTypes::PeerKeys(PeerKeys {
+            peer: peer.clone(),
+            my_current_key: Some(new_key),
+            their_current_key,
+            sent_messages,
+            received_messages,
+            ratchet: ratchet || matches!(peer_key, Some(PeerKey::Ratchet(_))),
+        }))?;
+
+        sessions.insert(peer.clone(), (peer_key, sent_messages));
+    }
+
+    Ok(sessions)
+}
+
+/// Records the key announced by the peer in a message received from it
+///
+/// Messages from previous versions don't have a number and always update the key,
+/// while numbered messages only do so if no later message from the peer was received before
+pub fn advance_receiving_session(
+    peer: &AgentPubKey,
+    their_new_key: X25519PubKey,
+    message_number: Option<u32>,
+) -> ExternResult<()> {
+    let peer_keys = query_peer_keys(peer)?;
+    let received_messages = peer_keys.as_ref().map(|p| p.received_messages).unwrap_or(0);
+
+    if let Some(message_number) = message_number {
+        if message_number <= received_messages {
+            debug!("Received an older message from {peer} out of order.");
+            return Ok(());
+        }
+        if message_number > received_messages + 1 {
+            debug!(
+                "{} messages from {peer} are missing or arriving out of order.",
+                message_number - received_messages - 1
+            );
+        }
+    }
+
+    let ratchet = peer_keys.as_ref().is_some_and(|p| p.ratchet) || message_number.is_some();
+
+    if peer_keys
+        .as_ref()
+        .is_some_and(|p| p.their_current_key.eq(&Some(their_new_key)) && p.ratchet == ratchet)
+    {
+        return Ok(());
+    }
+
+    create_relaxed(EntryTypes::PeerKeys(PeerKeys {
+        peer: peer.clone(),
+        my_current_key: peer_keys.as_ref().and_then(|p| p.my_current_key),
+        their_current_key: Some(their_new_key),
+        sent_messages: peer_keys.map(|p| p.sent_messages).unwrap_or(0),
+        received_messages: message_number.unwrap_or(received_messages),
+        ratchet,
+    }))?;
 
-    Ok(Some(last_peer_key))
+    Ok(())
 }
@@ -0,0 +1,84 @@
+use encrypted_messages_integrity::*;
+use hdk::prelude::*;
+
+use crate::utils::query_latest_record;
+
+/// Creates a new X25519 key and publishes it, so that other agents can start a session with us
+pub fn publish_pre_key() -> ExternResult<X25519PubKey> {
+    let key = create_x25519_keypair()?;
+    let pre_key = PreKey { key };
+    let entry_hash = hash_entry(&pre_key)?;
+
+    create_entry(EntryTypes::PreKey(pre_key))?;
+    create_link(
+        agent_info()?.agent_initial_pubkey,
+        entry_hash,
+        LinkTypes::AgentToPreKeys,
+        (),
+    )?;
+
+    Ok(key)
+}
+
+/// Returns the last pre key published by the given agent, if any
+#[hdk_extern]
+pub fn get_pre_key(agent: AgentPubKey) -> ExternResult<Option<X25519PubKey>> {
+    let links = get_links(
+        GetLinksInputBuilder::try_new(agent.clone(), LinkTypes::AgentToPreKeys)?.build(),
+    )?;
+
+    let Some(link) = links
+        .into_iter()
+        .filter(|link| link.author.eq(&agent))
+        .max_by_key(|link| link.timestamp)
+    else {
+        return Ok(None);
+    };
+    let Some(entry_hash) = link.target.into_entry_hash() else {
+        return Ok(None);
+    };
+    let Some(record) = get(entry_hash, GetOptions::default())? else {
+        return Ok(None);
+    };
+    let Some(pre_key) = record
+        .entry()
+        .to_app_option::<PreKey>()
+        .map_err(|err| wasm_error!(err))?
+    else {
+        return Ok(None);
+    };
+
+    Ok(Some(pre_key.key))
+}
+
+/// Returns the last pre key that we published, from our own source chain
+pub fn query_my_pre_key() -> ExternResult<Option<X25519PubKey>> {
+    let Some(record) = query_latest_record(UnitEntryTypes::PreKey)? else {
+        return Ok(None);
+    };
+    let Some(pre_key) = record
+        .entry()
+        .to_app_option::<PreKey>()
+        .map_err(|err| wasm_error!(err))?
+    else {
+        return Ok(None);
+    };
+
+    Ok(Some(pre_key.key))
+}
+
+/// Replaces our pre key once a peer has started a session with it, so that each pre key starts
+/// as few sessions as possible, and unlinks the previous ones so that no peer encrypts to them again
+pub fn rotate_pre_key() -> ExternResult<X25519PubKey> {
+    let my_pub_key = agent_info()?.agent_initial_pubkey;
+    let links = get_links(
+        GetLinksInputBuilder::try_new(my_pub_key.clone(), LinkTypes::AgentToPreKeys)?.build(),
+    )?;
+    for link in links {
+        if link.author.eq(&my_pub_key) {
+            delete_link(link.create_link_hash, GetOptions::default())?;
+        }
+    }
+
+    publish_pre_key()
+}
@@ -0,0 +1,120 @@
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+
+use encrypted_messages_integrity::*;
+use hdk::prelude::*;
+
+use crate::utils::create_relaxed;
+
+/// Maximum number of message ids remembered for each sender,
+/// older redelivered messages would be surfaced again
+pub const MAX_PROCESSED_MESSAGE_IDS_PER_SENDER: usize = 100;
+
+/// Every this many entries all the remembered ids are written again, so that only the entries
+/// since the last snapshot need to be read, while the rest only hold the newly processed ids
+const PROCESSED_MESSAGES_SNAPSHOT_INTERVAL: usize = 32;
+
+pub struct ProcessedMessagesLog {
+    message_ids: BTreeMap<AgentPubKey, VecDeque<MessageId>>,
+    entries_count: usize,
+}
+
+/// Rebuilds the ids of the last processed messages from the latest snapshot and the entries after it
+pub fn query_processed_messages() -> ExternResult<ProcessedMessagesLog> {
+    let mut actions = query(
+        ChainQueryFilter::new()
+            .entry_type(UnitEntryTypes::ProcessedMessages.try_into()?)
+            .action_type(ActionType::Create)
+            .include_entries(false),
+    )?;
+    actions.sort_by_key(|record| record.action().action_seq());
+
+    let entries_count = actions.len();
+    let mut message_ids: BTreeMap<AgentPubKey, VecDeque<MessageId>> = BTreeMap::new();
+    if entries_count == 0 {
+        return Ok(ProcessedMessagesLog {
+            message_ids,
+            entries_count,
+        });
+    }
+
+    let latest_snapshot = (entries_count - 1) / PROCESSED_MESSAGES_SNAPSHOT_INTERVAL
+        * PROCESSED_MESSAGES_SNAPSHOT_INTERVAL;
+    let get_inputs: Vec<GetInput> = actions[latest_snapshot..]
+        .iter()
+        .map(|record| GetInput::new(record.action_address().clone().into(), GetOptions::local()))
+        .collect();
+    let records = HDK.with(|hdk| hdk.borrow().get(get_inputs))?;
+
+    for record in records.into_iter().flatten() {
+        let Some(entry) = record.entry().as_option() else {
+            continue;
+        };
+        let Ok(processed_messages) = ProcessedMessages::try_from(entry) else {
+            continue;
+        };
+        for (sender, ids) in processed_messages.message_ids {
+            for message_id in ids {
+                remember(&mut message_ids, sender.clone(), message_id);
+            }
+        }
+    }
+
+    Ok(ProcessedMessagesLog {
+        message_ids,
+        entries_count,
+    })
+}
+
+/// The sender is the real sender of the message, also for sealed sender messages,
+/// so that a message is recognized even if it's redelivered with another ephemeral provenance
+pub fn is_processed(
+    processed_messages: &ProcessedMessagesLog,
+    sender: &AgentPubKey,
+    message_id: &MessageId,
+) -> bool {
+    processed_messages
+        .message_ids
+        .get(sender)
+        .is_some_and(|message_ids| message_ids.contains(message_id))
+}
+
+/// Writes the newly processed message ids, along with all the remembered ones if it's time for a snapshot
+pub fn record_processed_messages(
+    processed_messages: ProcessedMessagesLog,
+    newly_processed: BTreeSet<(AgentPubKey, MessageId)>,
+) -> ExternResult<()> {
+    if newly_processed.is_empty() {
+        return Ok(());
+    }
+
+    let mut message_ids =
+        match processed_messages.entries_count % PROCESSED_MESSAGES_SNAPSHOT_INTERVAL {
+            0 => processed_messages.message_ids,
+            _ => BTreeMap::new(),
+        };
+    for (sender, message_id) in newly_processed {
+        remember(&mut message_ids, sender, message_id);
+    }
+
+    create_relaxed(EntryTypes::ProcessedMessages(ProcessedMessages {
+        message_ids,
+    }))?;
+
+    Ok(())
+}
+
+/// Drops the oldest ids of the sender beyond `MAX_PROCESSED_MESSAGE_IDS_PER_SENDER`
+fn remember(
+    message_ids: &mut BTreeMap<AgentPubKey, VecDeque<MessageId>>,
+    sender: AgentPubKey,
+    message_id: MessageId,
+) {
+    let sender_ids = message_ids.entry(sender).or_default();
+    if sender_ids.contains(&message_id) {
+        return;
+    }
+    sender_ids.push_back(message_id);
+    if sender_ids.len() > MAX_PROCESSED_MESSAGE_IDS_PER_SENDER {
+        sender_ids.pop_front();
+    }
+}
@@ -0,0 +1,57 @@
+use hdk::prelude::*;
+use safehold_types::{EncryptMessageInput, MessageWithProvenance, SafeholdError};
+use service_providers_types::MakeServiceRequestInput;
+
+use crate::encrypt_message;
+
+/// Number of times that storing the messages is attempted if the safehold service
+/// returns an error that may go away, like a network error or a provider that is still starting
+const STORE_MESSAGES_ATTEMPTS: usize = 3;
+
+/// Encrypts the message and stores it with the safehold service through the services role
+///
+/// Fails with the `SafeholdError` returned by the service, so that the caller can match on it
+#[hdk_extern]
+pub fn send_message(input: EncryptMessageInput) -> ExternResult<Vec<MessageWithProvenance>> {
+    let messages = encrypt_message(input)?;
+
+    let mut attempt = 1;
+    loop {
+        match store_messages(messages.clone()) {
+            Ok(()) => return Ok(messages),
+            Err(error) if error.is_retryable() && attempt < STORE_MESSAGES_ATTEMPTS => {
+                warn!("Failed to store messages, retrying: {error}");
+                attempt += 1;
+            }
+            Err(error) => return Err(error.into()),
+        }
+    }
+}
+
+/// Each request goes to one of the safehold providers
+fn store_messages(messages: Vec<MessageWithProvenance>) -> Result<(), SafeholdError> {
+    let payload = holochain_serialized_bytes::encode(&messages)
+        .map(|bytes| SerializedBytes::from(UnsafeBytes::from(bytes)))
+        .map_err(|err| SafeholdError::Other(format!("{err:?}")))?;
+
+    let response = call(
+        CallTargetCell::OtherRole(RoleName::from("services")),
+        ZomeName::from("service_providers"),
+        FunctionName::from("make_service_request"),
+        None,
+        MakeServiceRequestInput {
+            service_id: safehold_service_trait::SAFEHOLD_SERVICE_HASH.to_vec(),
+            fn_name: FunctionName::from("store_messages"),
+            payload,
+        },
+    )
+    .map_err(SafeholdError::from_wasm_error)?;
+
+    match response {
+        ZomeCallResponse::Ok(_) => Ok(()),
+        ZomeCallResponse::NetworkError(err) => Err(SafeholdError::Network(err)),
+        response => Err(SafeholdError::from_message(format!(
+            "Failed to store messages: {response:?}"
+        ))),
+    }
+}
@@ -1,4 +1,4 @@
-use encrypted_messages_integrity::EntryTypes;
+use encrypted_messages_integrity::{EntryTypes, UnitEntryTypes};
 use hdk::prelude::*;
 
 #[derive(PartialEq, Eq, Serialize, Deserialize, SerializedBytes, Debug, Clone)]
@@ -21,7 +21,7 @@ pub fn from_bytes(bytes: Vec<u8>) -> ExternResult<XSalsa20Poly1305EncryptedData>
 }
 
 ///Allow other processes to get commited to source chain before commiting the commit
-pub fn create_relaxed(entry_type: EntryTypes) -> ExternResult<()> {
+pub fn create_relaxed(entry_type: EntryTypes) -> ExternResult<ActionHash> {
     HDK.with(|h| {
         let index = ScopedEntryDefIndex::try_from(&entry_type)?;
         let vis = EntryVisibility::from(&entry_type);
@@ -36,9 +36,7 @@ pub fn create_relaxed(entry_type: EntryTypes) -> ExternResult<()> {
             // a long time.
             ChainTopOrdering::Relaxed,
         ))
-    })?;
-
-    Ok(())
+    })
 }
 
 ///Allowing for other operations to commit before deleting an entry
@@ -50,3 +48,25 @@ pub fn delete_relaxed(address: ActionHash) -> ExternResult<()> {
 
     Ok(())
 }
+
+/// Returns the latest record of the given entry type in the source chain
+///
+/// Only the actions of that type are queried, and only the entry of the latest one is fetched,
+/// so that the cost doesn't depend on the rest of the chain nor on the size of the older entries
+pub fn query_latest_record(entry_type: UnitEntryTypes) -> ExternResult<Option<Record>> {
+    let records = query(
+        ChainQueryFilter::new()
+            .entry_type(entry_type.try_into()?)
+            .action_type(ActionType::Create)
+            .include_entries(false),
+    )?;
+
+    let Some(latest) = records
+        .into_iter()
+        .max_by_key(|record| record.action().action_seq())
+    else {
+        return Ok(None);
+    };
+
+    get(latest.action_address().clone(), GetOptions::local())
+}
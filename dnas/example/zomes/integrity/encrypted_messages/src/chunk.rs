@@ -1,3 +1,5 @@
+use std::collections::BTreeMap;
+
 use hdi::prelude::*;
 use safehold_types::MessageContents;
 
@@ -13,11 +15,38 @@ pub struct Chunk {
     pub contents: MessageContents,
 }
 
+/// Maximum size of the contents of a chunk, well below the maximum entry size
+pub const MAX_CHUNK_CONTENTS_SIZE: usize = 1_000_000; // 1MB
+
 pub fn validate_create_chunk(
     _action: EntryCreationAction,
     chunk: Chunk,
 ) -> ExternResult<ValidateCallbackResult> {
-    // TODO: add the appropriate validation rules
+    if chunk.total_chunk_number == 0 {
+        return Ok(ValidateCallbackResult::Invalid(
+            "total_chunk_number must be greater than 0".to_string(),
+        ));
+    }
+    if chunk.chunk_index >= chunk.total_chunk_number {
+        return Ok(ValidateCallbackResult::Invalid(
+            "chunk_index must be lower than total_chunk_number".to_string(),
+        ));
+    }
+    if chunk.contents.len() > MAX_CHUNK_CONTENTS_SIZE {
+        return Ok(ValidateCallbackResult::Invalid(format!(
+            "chunk contents can't be bigger than {MAX_CHUNK_CONTENTS_SIZE} bytes"
+        )));
+    }
+    if chunk.provenance.get_raw_32().iter().all(|byte| *byte == 0) {
+        return Ok(ValidateCallbackResult::Invalid(
+            "chunk provenance must not be empty".to_string(),
+        ));
+    }
+    if chunk.message_id.is_empty() {
+        return Ok(ValidateCallbackResult::Invalid(
+            "chunk message_id must not be empty".to_string(),
+        ));
+    }
     Ok(ValidateCallbackResult::Valid)
 }
 
@@ -32,12 +61,126 @@ pub fn validate_update_chunk(
     ))
 }
 
+/// Chunks are deleted by their author once the message is complete or has timed out
 pub fn validate_delete_chunk(
+    action: Delete,
+    original_action: EntryCreationAction,
+    _original_chunk: Chunk,
+) -> ExternResult<ValidateCallbackResult> {
+    if action.author.ne(original_action.author()) {
+        return Ok(ValidateCallbackResult::Invalid(
+            "chunks can only be deleted by their author".to_string(),
+        ));
+    }
+    Ok(ValidateCallbackResult::Valid)
+}
+
+/// Chunk received before the secret of its message, kept encrypted until the secret arrives
+#[derive(Clone)]
+#[hdk_entry_helper]
+pub struct EncryptedChunk {
+    pub provenance: AgentPubKey,
+    pub message_id: MessageId,
+    /// The serialized symmetric ciphertext of the chunk
+    pub contents: Vec<u8>,
+}
+
+pub fn validate_create_encrypted_chunk(
+    _action: EntryCreationAction,
+    encrypted_chunk: EncryptedChunk,
+) -> ExternResult<ValidateCallbackResult> {
+    if encrypted_chunk.contents.len() > MAX_CHUNK_CONTENTS_SIZE {
+        return Ok(ValidateCallbackResult::Invalid(format!(
+            "encrypted chunks can't be bigger than {MAX_CHUNK_CONTENTS_SIZE} bytes"
+        )));
+    }
+    if encrypted_chunk.message_id.is_empty() {
+        return Ok(ValidateCallbackResult::Invalid(
+            "encrypted chunk message_id must not be empty".to_string(),
+        ));
+    }
+    Ok(ValidateCallbackResult::Valid)
+}
+
+pub fn validate_update_encrypted_chunk(
+    _action: Update,
+    _encrypted_chunk: EncryptedChunk,
+    _original_action: EntryCreationAction,
+    _original_encrypted_chunk: EncryptedChunk,
+) -> ExternResult<ValidateCallbackResult> {
+    Ok(ValidateCallbackResult::Invalid(
+        "encrypted chunks cannot be updated".to_string(),
+    ))
+}
+
+/// Encrypted chunks are deleted by their author once they are decrypted or have timed out
+pub fn validate_delete_encrypted_chunk(
+    action: Delete,
+    original_action: EntryCreationAction,
+    _original_encrypted_chunk: EncryptedChunk,
+) -> ExternResult<ValidateCallbackResult> {
+    if action.author.ne(original_action.author()) {
+        return Ok(ValidateCallbackResult::Invalid(
+            "encrypted chunks can only be deleted by their author".to_string(),
+        ));
+    }
+    Ok(ValidateCallbackResult::Valid)
+}
+
+/// Message of which only some of the chunks have been received
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct PendingTransfer {
+    pub provenance: AgentPubKey,
+    pub message_id: MessageId,
+    /// `None` until the first chunk of the message has been decrypted
+    pub total_chunk_number: Option<usize>,
+    pub first_received_at: Timestamp,
+    /// Action hashes of the chunk entries received so far, by their index
+    pub chunks: BTreeMap<usize, ActionHash>,
+    /// Reference to the secret of the message in the keystore, once it has been received
+    #[serde(default)]
+    pub key_ref: Option<XSalsa20Poly1305KeyRef>,
+    /// Action hashes of the encrypted chunk entries received before the secret of the message
+    #[serde(default)]
+    pub encrypted_chunks: Vec<ActionHash>,
+    /// Real sender of a sealed sender message, whose `provenance` is an ephemeral key,
+    /// once its header has been received
+    #[serde(default)]
+    pub sealed_sender: Option<AgentPubKey>,
+}
+
+/// Snapshot of the pending transfers, written whenever they change
+/// so that they can be found without scanning the whole source chain
+#[derive(Clone)]
+#[hdk_entry_helper]
+pub struct PendingChunksIndex {
+    pub transfers: Vec<PendingTransfer>,
+}
+
+pub fn validate_create_pending_chunks_index(
+    _action: EntryCreationAction,
+    _pending_chunks_index: PendingChunksIndex,
+) -> ExternResult<ValidateCallbackResult> {
+    Ok(ValidateCallbackResult::Valid)
+}
+
+pub fn validate_update_pending_chunks_index(
+    _action: Update,
+    _pending_chunks_index: PendingChunksIndex,
+    _original_action: EntryCreationAction,
+    _original_pending_chunks_index: PendingChunksIndex,
+) -> ExternResult<ValidateCallbackResult> {
+    Ok(ValidateCallbackResult::Invalid(
+        "pending chunks indexes cannot be updated".to_string(),
+    ))
+}
+
+pub fn validate_delete_pending_chunks_index(
     _action: Delete,
     _original_action: EntryCreationAction,
-    _original_chunk: Chunk,
+    _original_pending_chunks_index: PendingChunksIndex,
 ) -> ExternResult<ValidateCallbackResult> {
     Ok(ValidateCallbackResult::Invalid(
-        "chunks cannot be deleted".to_string(),
+        "pending chunks indexes cannot be deleted".to_string(),
     ))
 }
@@ -0,0 +1,151 @@
+use std::collections::BTreeSet;
+
+use hdi::prelude::*;
+
+/// Named set of agents to which messages can be sent at once,
+/// whose membership is managed by the agent that created it
+///
+/// Groups are identified by the hash of the action that created them, since their names aren't unique
+///
+/// Groups are public entries: anyone in the network can read their name and their members,
+/// although not the messages sent to them
+#[derive(Clone)]
+#[hdk_entry_helper]
+pub struct Group {
+    pub name: String,
+    pub members: BTreeSet<AgentPubKey>,
+}
+
+pub fn validate_create_group(
+    action: EntryCreationAction,
+    group: Group,
+) -> ExternResult<ValidateCallbackResult> {
+    if group.name.is_empty() {
+        return Ok(ValidateCallbackResult::Invalid(
+            "group name must not be empty".to_string(),
+        ));
+    }
+    if !group.members.contains(action.author()) {
+        return Ok(ValidateCallbackResult::Invalid(
+            "the author of a group must be one of its members".to_string(),
+        ));
+    }
+    Ok(ValidateCallbackResult::Valid)
+}
+
+pub fn validate_update_group(
+    action: Update,
+    group: Group,
+    original_action: EntryCreationAction,
+    original_group: Group,
+) -> ExternResult<ValidateCallbackResult> {
+    if action.author.ne(original_action.author()) {
+        return Ok(ValidateCallbackResult::Invalid(
+            "groups can only be updated by their author".to_string(),
+        ));
+    }
+    if group.name.ne(&original_group.name) {
+        return Ok(ValidateCallbackResult::Invalid(
+            "the name of a group cannot be changed".to_string(),
+        ));
+    }
+    Ok(ValidateCallbackResult::Valid)
+}
+
+pub fn validate_delete_group(
+    action: Delete,
+    original_action: EntryCreationAction,
+    _original_group: Group,
+) -> ExternResult<ValidateCallbackResult> {
+    if action.author.ne(original_action.author()) {
+        return Ok(ValidateCallbackResult::Invalid(
+            "groups can only be deleted by their author".to_string(),
+        ));
+    }
+    Ok(ValidateCallbackResult::Valid)
+}
+
+/// Private record that we accepted to be a member of a group, since anyone can add any agent
+/// to the groups they create
+#[derive(Clone)]
+#[hdk_entry_helper]
+pub struct GroupAcceptance {
+    pub group_hash: ActionHash,
+}
+
+pub fn validate_create_group_acceptance(
+    _action: EntryCreationAction,
+    _group_acceptance: GroupAcceptance,
+) -> ExternResult<ValidateCallbackResult> {
+    Ok(ValidateCallbackResult::Valid)
+}
+
+pub fn validate_update_group_acceptance(
+    _action: Update,
+    _group_acceptance: GroupAcceptance,
+    _original_action: EntryCreationAction,
+    _original_group_acceptance: GroupAcceptance,
+) -> ExternResult<ValidateCallbackResult> {
+    Ok(ValidateCallbackResult::Invalid(
+        "group acceptances cannot be updated".to_string(),
+    ))
+}
+
+pub fn validate_delete_group_acceptance(
+    _action: Delete,
+    _original_action: EntryCreationAction,
+    _original_group_acceptance: GroupAcceptance,
+) -> ExternResult<ValidateCallbackResult> {
+    Ok(ValidateCallbackResult::Invalid(
+        "group acceptances cannot be deleted".to_string(),
+    ))
+}
+
+/// Links from each member to the groups it belongs to, created and deleted by the author of the group
+pub fn validate_create_link_member_to_groups(
+    action: CreateLink,
+    base_address: AnyLinkableHash,
+    target_address: AnyLinkableHash,
+    _tag: LinkTag,
+) -> ExternResult<ValidateCallbackResult> {
+    if base_address.into_agent_pub_key().is_none() {
+        return Ok(ValidateCallbackResult::Invalid(String::from(
+            "The base of a MemberToGroups link must be an agent",
+        )));
+    }
+
+    let action_hash =
+        target_address
+            .into_action_hash()
+            .ok_or(wasm_error!(WasmErrorInner::Guest(
+                "No action hash associated with link".to_string()
+            )))?;
+    let record = must_get_valid_record(action_hash)?;
+    let Ok(Some(_group)) = record.entry().to_app_option::<Group>() else {
+        return Ok(ValidateCallbackResult::Invalid(String::from(
+            "The target of a MemberToGroups link must be a group",
+        )));
+    };
+    if record.action().author().ne(&action.author) {
+        return Ok(ValidateCallbackResult::Invalid(String::from(
+            "MemberToGroups links can only be created by the author of the group",
+        )));
+    }
+
+    Ok(ValidateCallbackResult::Valid)
+}
+
+pub fn validate_delete_link_member_to_groups(
+    action: DeleteLink,
+    original_action: CreateLink,
+    _base: AnyLinkableHash,
+    _target: AnyLinkableHash,
+    _tag: LinkTag,
+) -> ExternResult<ValidateCallbackResult> {
+    if action.author.ne(&original_action.author) {
+        return Ok(ValidateCallbackResult::Invalid(String::from(
+            "MemberToGroups links can only be deleted by their author",
+        )));
+    }
+    Ok(ValidateCallbackResult::Valid)
+}
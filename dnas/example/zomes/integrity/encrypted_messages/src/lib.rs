@@ -6,6 +6,18 @@ pub mod peer_keys;
 pub use chunk::*;
 pub mod chunk;
 
+pub use processed_messages::*;
+pub mod processed_messages;
+
+pub use pre_key::*;
+pub mod pre_key;
+
+pub use group::*;
+pub mod group;
+
+pub use properties::*;
+pub mod properties;
+
 #[derive(Serialize, Deserialize)]
 #[serde(tag = "type")]
 #[hdk_entry_types]
@@ -15,13 +27,24 @@ pub enum EntryTypes {
     PeerKeys(PeerKeys),
     #[entry_type(visibility = "private")]
     Chunk(Chunk),
+    #[entry_type(visibility = "private")]
+    ProcessedMessages(ProcessedMessages),
+    #[entry_type(visibility = "private")]
+    PendingChunksIndex(PendingChunksIndex),
+    PreKey(PreKey),
+    #[entry_type(visibility = "private")]
+    EncryptedChunk(EncryptedChunk),
+    Group(Group),
+    #[entry_type(visibility = "private")]
+    GroupAcceptance(GroupAcceptance),
 }
 
-// #[derive(Serialize, Deserialize)]
-// #[hdk_link_types]
-// pub enum LinkTypes {
-//     RecipientToMessages,
-// }
+#[derive(Serialize, Deserialize)]
+#[hdk_link_types]
+pub enum LinkTypes {
+    AgentToPreKeys,
+    MemberToGroups,
+}
 
 // Validation you perform during the genesis process. Nobody else on the network performs it, only you.
 // There *is no* access to network calls in this callback
@@ -39,6 +62,29 @@ pub fn validate_agent_joining(
     Ok(ValidateCallbackResult::Valid)
 }
 
+/// The contents of private entries are not available to the authorities validating their deletes,
+/// so only their entry type and author can be checked
+fn validate_delete_private_entry(
+    action: Delete,
+    original_action: EntryCreationAction,
+) -> ExternResult<ValidateCallbackResult> {
+    let chunk_entry_type: EntryType = UnitEntryTypes::Chunk.try_into()?;
+    let encrypted_chunk_entry_type: EntryType = UnitEntryTypes::EncryptedChunk.try_into()?;
+    if original_action.entry_type().ne(&chunk_entry_type)
+        && original_action.entry_type().ne(&encrypted_chunk_entry_type)
+    {
+        return Ok(ValidateCallbackResult::Invalid(
+            "Only chunks can be deleted".to_string(),
+        ));
+    }
+    if action.author.ne(original_action.author()) {
+        return Ok(ValidateCallbackResult::Invalid(
+            "chunks can only be deleted by their author".to_string(),
+        ));
+    }
+    Ok(ValidateCallbackResult::Valid)
+}
+
 // This is the unified validation callback for all entries and link types in this integrity zome
 // Below is a match template for all of the variants of `DHT Ops` and entry and link types
 // Holochain has already performed the following validation for you:
@@ -59,7 +105,7 @@ pub fn validate_agent_joining(
 // You can read more about validation here: https://docs.rs/hdi/latest/hdi/index.html#data-validation
 #[hdk_extern]
 pub fn validate(op: Op) -> ExternResult<ValidateCallbackResult> {
-    match op.flattened::<EntryTypes, ()>()? {
+    match op.flattened::<EntryTypes, LinkTypes>()? {
         FlatOp::StoreEntry(store_entry) => match store_entry {
             OpEntry::CreateEntry { app_entry, action } => match app_entry {
                 EntryTypes::PeerKeys(message) => {
@@ -68,6 +114,32 @@ pub fn validate(op: Op) -> ExternResult<ValidateCallbackResult> {
                 EntryTypes::Chunk(chunk) => {
                     validate_create_chunk(EntryCreationAction::Create(action), chunk)
                 }
+                EntryTypes::ProcessedMessages(processed_messages) => {
+                    validate_create_processed_messages(
+                        EntryCreationAction::Create(action),
+                        processed_messages,
+                    )
+                }
+                EntryTypes::GroupAcceptance(group_acceptance) => validate_create_group_acceptance(
+                    EntryCreationAction::Create(action),
+                    group_acceptance,
+                ),
+                EntryTypes::PendingChunksIndex(pending_chunks_index) => {
+                    validate_create_pending_chunks_index(
+                        EntryCreationAction::Create(action),
+                        pending_chunks_index,
+                    )
+                }
+                EntryTypes::PreKey(pre_key) => {
+                    validate_create_pre_key(EntryCreationAction::Create(action), pre_key)
+                }
+                EntryTypes::EncryptedChunk(encrypted_chunk) => validate_create_encrypted_chunk(
+                    EntryCreationAction::Create(action),
+                    encrypted_chunk,
+                ),
+                EntryTypes::Group(group) => {
+                    validate_create_group(EntryCreationAction::Create(action), group)
+                }
             },
             OpEntry::UpdateEntry {
                 app_entry, action, ..
@@ -78,6 +150,32 @@ pub fn validate(op: Op) -> ExternResult<ValidateCallbackResult> {
                 EntryTypes::Chunk(chunk) => {
                     validate_create_chunk(EntryCreationAction::Update(action), chunk)
                 }
+                EntryTypes::ProcessedMessages(processed_messages) => {
+                    validate_create_processed_messages(
+                        EntryCreationAction::Update(action),
+                        processed_messages,
+                    )
+                }
+                EntryTypes::GroupAcceptance(group_acceptance) => validate_create_group_acceptance(
+                    EntryCreationAction::Update(action),
+                    group_acceptance,
+                ),
+                EntryTypes::PendingChunksIndex(pending_chunks_index) => {
+                    validate_create_pending_chunks_index(
+                        EntryCreationAction::Update(action),
+                        pending_chunks_index,
+                    )
+                }
+                EntryTypes::PreKey(pre_key) => {
+                    validate_create_pre_key(EntryCreationAction::Update(action), pre_key)
+                }
+                EntryTypes::EncryptedChunk(encrypted_chunk) => validate_create_encrypted_chunk(
+                    EntryCreationAction::Update(action),
+                    encrypted_chunk,
+                ),
+                EntryTypes::Group(group) => {
+                    validate_create_group(EntryCreationAction::Update(action), group)
+                }
             },
             _ => Ok(ValidateCallbackResult::Valid),
         },
@@ -126,6 +224,113 @@ pub fn validate(op: Op) -> ExternResult<ValidateCallbackResult> {
                         };
                         validate_update_chunk(action, chunk, original_create_action, original_chunk)
                     }
+                    EntryTypes::ProcessedMessages(processed_messages) => {
+                        let original_app_entry =
+                            must_get_valid_record(action.clone().original_action_address)?;
+                        let original_processed_messages =
+                            match ProcessedMessages::try_from(original_app_entry) {
+                                Ok(entry) => entry,
+                                Err(e) => {
+                                    return Ok(ValidateCallbackResult::Invalid(format!(
+                                        "Expected to get ProcessedMessages from Record: {e:?}"
+                                    )));
+                                }
+                            };
+                        validate_update_processed_messages(
+                            action,
+                            processed_messages,
+                            original_create_action,
+                            original_processed_messages,
+                        )
+                    }
+                    EntryTypes::GroupAcceptance(group_acceptance) => {
+                        let original_app_entry =
+                            must_get_valid_record(action.clone().original_action_address)?;
+                        let original_group_acceptance =
+                            match GroupAcceptance::try_from(original_app_entry) {
+                                Ok(entry) => entry,
+                                Err(e) => {
+                                    return Ok(ValidateCallbackResult::Invalid(format!(
+                                        "Expected to get GroupAcceptance from Record: {e:?}"
+                                    )));
+                                }
+                            };
+                        validate_update_group_acceptance(
+                            action,
+                            group_acceptance,
+                            original_create_action,
+                            original_group_acceptance,
+                        )
+                    }
+                    EntryTypes::PendingChunksIndex(pending_chunks_index) => {
+                        let original_app_entry =
+                            must_get_valid_record(action.clone().original_action_address)?;
+                        let original_pending_chunks_index =
+                            match PendingChunksIndex::try_from(original_app_entry) {
+                                Ok(entry) => entry,
+                                Err(e) => {
+                                    return Ok(ValidateCallbackResult::Invalid(format!(
+                                        "Expected to get PendingChunksIndex from Record: {e:?}"
+                                    )));
+                                }
+                            };
+                        validate_update_pending_chunks_index(
+                            action,
+                            pending_chunks_index,
+                            original_create_action,
+                            original_pending_chunks_index,
+                        )
+                    }
+                    EntryTypes::PreKey(pre_key) => {
+                        let original_app_entry =
+                            must_get_valid_record(action.clone().original_action_address)?;
+                        let original_pre_key = match PreKey::try_from(original_app_entry) {
+                            Ok(entry) => entry,
+                            Err(e) => {
+                                return Ok(ValidateCallbackResult::Invalid(format!(
+                                    "Expected to get PreKey from Record: {e:?}"
+                                )));
+                            }
+                        };
+                        validate_update_pre_key(
+                            action,
+                            pre_key,
+                            original_create_action,
+                            original_pre_key,
+                        )
+                    }
+                    EntryTypes::EncryptedChunk(encrypted_chunk) => {
+                        let original_app_entry =
+                            must_get_valid_record(action.clone().original_action_address)?;
+                        let original_encrypted_chunk =
+                            match EncryptedChunk::try_from(original_app_entry) {
+                                Ok(entry) => entry,
+                                Err(e) => {
+                                    return Ok(ValidateCallbackResult::Invalid(format!(
+                                        "Expected to get EncryptedChunk from Record: {e:?}"
+                                    )));
+                                }
+                            };
+                        validate_update_encrypted_chunk(
+                            action,
+                            encrypted_chunk,
+                            original_create_action,
+                            original_encrypted_chunk,
+                        )
+                    }
+                    EntryTypes::Group(group) => {
+                        let original_app_entry =
+                            must_get_valid_record(action.clone().original_action_address)?;
+                        let original_group = match Group::try_from(original_app_entry) {
+                            Ok(entry) => entry,
+                            Err(e) => {
+                                return Ok(ValidateCallbackResult::Invalid(format!(
+                                    "Expected to get Group from Record: {e:?}"
+                                )));
+                            }
+                        };
+                        validate_update_group(action, group, original_create_action, original_group)
+                    }
                 }
             }
             _ => Ok(ValidateCallbackResult::Valid),
@@ -151,9 +356,10 @@ pub fn validate(op: Op) -> ExternResult<ValidateCallbackResult> {
             let entry = match original_record.entry().as_option() {
                 Some(entry) => entry,
                 None => {
-                    return Ok(ValidateCallbackResult::Invalid(
-                        "Original record for a delete must contain an entry".to_string(),
-                    ));
+                    return validate_delete_private_entry(
+                        delete_entry.clone().action,
+                        original_action,
+                    );
                 }
             };
             let original_app_entry = match EntryTypes::deserialize_from_type(
@@ -180,6 +386,44 @@ pub fn validate(op: Op) -> ExternResult<ValidateCallbackResult> {
                     original_action,
                     original_chunk,
                 ),
+                EntryTypes::ProcessedMessages(original_processed_messages) => {
+                    validate_delete_processed_messages(
+                        delete_entry.clone().action,
+                        original_action,
+                        original_processed_messages,
+                    )
+                }
+                EntryTypes::GroupAcceptance(original_group_acceptance) => {
+                    validate_delete_group_acceptance(
+                        delete_entry.clone().action,
+                        original_action,
+                        original_group_acceptance,
+                    )
+                }
+                EntryTypes::PendingChunksIndex(original_pending_chunks_index) => {
+                    validate_delete_pending_chunks_index(
+                        delete_entry.clone().action,
+                        original_action,
+                        original_pending_chunks_index,
+                    )
+                }
+                EntryTypes::PreKey(original_pre_key) => validate_delete_pre_key(
+                    delete_entry.clone().action,
+                    original_action,
+                    original_pre_key,
+                ),
+                EntryTypes::EncryptedChunk(original_encrypted_chunk) => {
+                    validate_delete_encrypted_chunk(
+                        delete_entry.clone().action,
+                        original_action,
+                        original_encrypted_chunk,
+                    )
+                }
+                EntryTypes::Group(original_group) => validate_delete_group(
+                    delete_entry.clone().action,
+                    original_action,
+                    original_group,
+                ),
             }
         }
         FlatOp::RegisterCreateLink {
@@ -188,9 +432,14 @@ pub fn validate(op: Op) -> ExternResult<ValidateCallbackResult> {
             target_address,
             tag,
             action,
-        } => Ok(ValidateCallbackResult::Invalid(String::from(
-            "No link types in the encrypted_channels zome.",
-        ))),
+        } => match link_type {
+            LinkTypes::AgentToPreKeys => {
+                validate_create_link_agent_to_pre_keys(action, base_address, target_address, tag)
+            }
+            LinkTypes::MemberToGroups => {
+                validate_create_link_member_to_groups(action, base_address, target_address, tag)
+            }
+        },
         FlatOp::RegisterDeleteLink {
             link_type,
             base_address,
@@ -198,9 +447,22 @@ pub fn validate(op: Op) -> ExternResult<ValidateCallbackResult> {
             tag,
             original_action,
             action,
-        } => Ok(ValidateCallbackResult::Invalid(String::from(
-            "No link types in the encrypted_channels zome.",
-        ))),
+        } => match link_type {
+            LinkTypes::AgentToPreKeys => validate_delete_link_agent_to_pre_keys(
+                action,
+                original_action,
+                base_address,
+                target_address,
+                tag,
+            ),
+            LinkTypes::MemberToGroups => validate_delete_link_member_to_groups(
+                action,
+                original_action,
+                base_address,
+                target_address,
+                tag,
+            ),
+        },
         FlatOp::StoreRecord(store_record) => {
             match store_record {
                 // Complementary validation to the `StoreEntry` Op, in which the record itself is validated
@@ -213,6 +475,34 @@ pub fn validate(op: Op) -> ExternResult<ValidateCallbackResult> {
                     EntryTypes::Chunk(chunk) => {
                         validate_create_chunk(EntryCreationAction::Create(action), chunk)
                     }
+                    EntryTypes::ProcessedMessages(processed_messages) => {
+                        validate_create_processed_messages(
+                            EntryCreationAction::Create(action),
+                            processed_messages,
+                        )
+                    }
+                    EntryTypes::GroupAcceptance(group_acceptance) => {
+                        validate_create_group_acceptance(
+                            EntryCreationAction::Create(action),
+                            group_acceptance,
+                        )
+                    }
+                    EntryTypes::PendingChunksIndex(pending_chunks_index) => {
+                        validate_create_pending_chunks_index(
+                            EntryCreationAction::Create(action),
+                            pending_chunks_index,
+                        )
+                    }
+                    EntryTypes::PreKey(pre_key) => {
+                        validate_create_pre_key(EntryCreationAction::Create(action), pre_key)
+                    }
+                    EntryTypes::EncryptedChunk(encrypted_chunk) => validate_create_encrypted_chunk(
+                        EntryCreationAction::Create(action),
+                        encrypted_chunk,
+                    ),
+                    EntryTypes::Group(group) => {
+                        validate_create_group(EntryCreationAction::Create(action), group)
+                    }
                 },
                 // Complementary validation to the `RegisterUpdate` Op, in which the record itself is validated
                 // If you want to optimize performance, you can remove the validation for an entry type here and keep it in `StoreEntry` and in `RegisterUpdate`
@@ -298,6 +588,198 @@ pub fn validate(op: Op) -> ExternResult<ValidateCallbackResult> {
                                 Ok(result)
                             }
                         }
+                        EntryTypes::ProcessedMessages(processed_messages) => {
+                            let result = validate_create_processed_messages(
+                                EntryCreationAction::Update(action.clone()),
+                                processed_messages.clone(),
+                            )?;
+                            if let ValidateCallbackResult::Valid = result {
+                                let original_processed_messages: Option<ProcessedMessages> =
+                                    original_record
+                                        .entry()
+                                        .to_app_option()
+                                        .map_err(|e| wasm_error!(e))?;
+                                let original_processed_messages = match original_processed_messages
+                                {
+                                    Some(processed_messages) => processed_messages,
+                                    None => {
+                                        return Ok(
+                                            ValidateCallbackResult::Invalid(
+                                                "The updated entry type must be the same as the original entry type"
+                                                    .to_string(),
+                                            ),
+                                        );
+                                    }
+                                };
+                                validate_update_processed_messages(
+                                    action,
+                                    processed_messages,
+                                    original_action,
+                                    original_processed_messages,
+                                )
+                            } else {
+                                Ok(result)
+                            }
+                        }
+                        EntryTypes::GroupAcceptance(group_acceptance) => {
+                            let result = validate_create_group_acceptance(
+                                EntryCreationAction::Update(action.clone()),
+                                group_acceptance.clone(),
+                            )?;
+                            if let ValidateCallbackResult::Valid = result {
+                                let original_group_acceptance: Option<GroupAcceptance> =
+                                    original_record
+                                        .entry()
+                                        .to_app_option()
+                                        .map_err(|e| wasm_error!(e))?;
+                                let original_group_acceptance = match original_group_acceptance {
+                                    Some(group_acceptance) => group_acceptance,
+                                    None => {
+                                        return Ok(
+                                            ValidateCallbackResult::Invalid(
+                                                "The updated entry type must be the same as the original entry type"
+                                                    .to_string(),
+                                            ),
+                                        );
+                                    }
+                                };
+                                validate_update_group_acceptance(
+                                    action,
+                                    group_acceptance,
+                                    original_action,
+                                    original_group_acceptance,
+                                )
+                            } else {
+                                Ok(result)
+                            }
+                        }
+                        EntryTypes::PendingChunksIndex(pending_chunks_index) => {
+                            let result = validate_create_pending_chunks_index(
+                                EntryCreationAction::Update(action.clone()),
+                                pending_chunks_index.clone(),
+                            )?;
+                            if let ValidateCallbackResult::Valid = result {
+                                let original_pending_chunks_index: Option<PendingChunksIndex> =
+                                    original_record
+                                        .entry()
+                                        .to_app_option()
+                                        .map_err(|e| wasm_error!(e))?;
+                                let original_pending_chunks_index =
+                                    match original_pending_chunks_index {
+                                        Some(pending_chunks_index) => pending_chunks_index,
+                                        None => {
+                                            return Ok(
+                                            ValidateCallbackResult::Invalid(
+                                                "The updated entry type must be the same as the original entry type"
+                                                    .to_string(),
+                                            ),
+                                        );
+                                        }
+                                    };
+                                validate_update_pending_chunks_index(
+                                    action,
+                                    pending_chunks_index,
+                                    original_action,
+                                    original_pending_chunks_index,
+                                )
+                            } else {
+                                Ok(result)
+                            }
+                        }
+                        EntryTypes::PreKey(pre_key) => {
+                            let result = validate_create_pre_key(
+                                EntryCreationAction::Update(action.clone()),
+                                pre_key.clone(),
+                            )?;
+                            if let ValidateCallbackResult::Valid = result {
+                                let original_pre_key: Option<PreKey> = original_record
+                                    .entry()
+                                    .to_app_option()
+                                    .map_err(|e| wasm_error!(e))?;
+                                let original_pre_key = match original_pre_key {
+                                    Some(pre_key) => pre_key,
+                                    None => {
+                                        return Ok(
+                                            ValidateCallbackResult::Invalid(
+                                                "The updated entry type must be the same as the original entry type"
+                                                    .to_string(),
+                                            ),
+                                        );
+                                    }
+                                };
+                                validate_update_pre_key(
+                                    action,
+                                    pre_key,
+                                    original_action,
+                                    original_pre_key,
+                                )
+                            } else {
+                                Ok(result)
+                            }
+                        }
+                        EntryTypes::EncryptedChunk(encrypted_chunk) => {
+                            let result = validate_create_encrypted_chunk(
+                                EntryCreationAction::Update(action.clone()),
+                                encrypted_chunk.clone(),
+                            )?;
+                            if let ValidateCallbackResult::Valid = result {
+                                let original_encrypted_chunk: Option<EncryptedChunk> =
+                                    original_record
+                                        .entry()
+                                        .to_app_option()
+                                        .map_err(|e| wasm_error!(e))?;
+                                let original_encrypted_chunk = match original_encrypted_chunk {
+                                    Some(encrypted_chunk) => encrypted_chunk,
+                                    None => {
+                                        return Ok(
+                                            ValidateCallbackResult::Invalid(
+                                                "The updated entry type must be the same as the original entry type"
+                                                    .to_string(),
+                                            ),
+                                        );
+                                    }
+                                };
+                                validate_update_encrypted_chunk(
+                                    action,
+                                    encrypted_chunk,
+                                    original_action,
+                                    original_encrypted_chunk,
+                                )
+                            } else {
+                                Ok(result)
+                            }
+                        }
+                        EntryTypes::Group(group) => {
+                            let result = validate_create_group(
+                                EntryCreationAction::Update(action.clone()),
+                                group.clone(),
+                            )?;
+                            if let ValidateCallbackResult::Valid = result {
+                                let original_group: Option<Group> = original_record
+                                    .entry()
+                                    .to_app_option()
+                                    .map_err(|e| wasm_error!(e))?;
+                                let original_group = match original_group {
+                                    Some(group) => group,
+                                    None => {
+                                        return Ok(
+                                            ValidateCallbackResult::Invalid(
+                                                "The updated entry type must be the same as the original entry type"
+                                                    .to_string(),
+                                            ),
+                                        );
+                                    }
+                                };
+                                validate_update_group(
+                                    action,
+                                    group,
+                                    original_action,
+                                    original_group,
+                                )
+                            } else {
+                                Ok(result)
+                            }
+                        }
                     }
                 }
                 // Complementary validation to the `RegisterDelete` Op, in which the record itself is validated
@@ -329,9 +811,7 @@ pub fn validate(op: Op) -> ExternResult<ValidateCallbackResult> {
                     let entry = match original_record.entry().as_option() {
                         Some(entry) => entry,
                         None => {
-                            return Ok(ValidateCallbackResult::Invalid(
-                                "Original record for a delete must contain an entry".to_string(),
-                            ));
+                            return validate_delete_private_entry(action, original_action);
                         }
                     };
                     let original_app_entry = match EntryTypes::deserialize_from_type(
@@ -356,6 +836,40 @@ pub fn validate(op: Op) -> ExternResult<ValidateCallbackResult> {
                         EntryTypes::Chunk(original_chunk) => {
                             validate_delete_chunk(action, original_action, original_chunk)
                         }
+                        EntryTypes::ProcessedMessages(original_processed_messages) => {
+                            validate_delete_processed_messages(
+                                action,
+                                original_action,
+                                original_processed_messages,
+                            )
+                        }
+                        EntryTypes::GroupAcceptance(original_group_acceptance) => {
+                            validate_delete_group_acceptance(
+                                action,
+                                original_action,
+                                original_group_acceptance,
+                            )
+                        }
+                        EntryTypes::PendingChunksIndex(original_pending_chunks_index) => {
+                            validate_delete_pending_chunks_index(
+                                action,
+                                original_action,
+                                original_pending_chunks_index,
+                            )
+                        }
+                        EntryTypes::PreKey(original_pre_key) => {
+                            validate_delete_pre_key(action, original_action, original_pre_key)
+                        }
+                        EntryTypes::EncryptedChunk(original_encrypted_chunk) => {
+                            validate_delete_encrypted_chunk(
+                                action,
+                                original_action,
+                                original_encrypted_chunk,
+                            )
+                        }
+                        EntryTypes::Group(original_group) => {
+                            validate_delete_group(action, original_action, original_group)
+                        }
                     }
                 }
                 // Complementary validation to the `RegisterCreateLink` Op, in which the record itself is validated
@@ -367,9 +881,20 @@ pub fn validate(op: Op) -> ExternResult<ValidateCallbackResult> {
                     tag,
                     link_type,
                     action,
-                } => Ok(ValidateCallbackResult::Invalid(String::from(
-                    "No link types in the encrypted_channels zome.",
-                ))),
+                } => match link_type {
+                    LinkTypes::AgentToPreKeys => validate_create_link_agent_to_pre_keys(
+                        action,
+                        base_address,
+                        target_address,
+                        tag,
+                    ),
+                    LinkTypes::MemberToGroups => validate_create_link_member_to_groups(
+                        action,
+                        base_address,
+                        target_address,
+                        tag,
+                    ),
+                },
                 // Complementary validation to the `RegisterDeleteLink` Op, in which the record itself is validated
                 // If you want to optimize performance, you can remove the validation for an entry type here and keep it in `RegisterDeleteLink`
                 // Notice that doing so will cause `must_get_valid_record` for this record to return a valid record even if the `RegisterDeleteLink` validation failed
@@ -377,9 +902,43 @@ pub fn validate(op: Op) -> ExternResult<ValidateCallbackResult> {
                     original_action_hash,
                     base_address,
                     action,
-                } => Ok(ValidateCallbackResult::Invalid(String::from(
-                    "No link types in the encrypted_channels zome.",
-                ))),
+                } => {
+                    let record = must_get_valid_record(original_action_hash)?;
+                    let create_link = match record.action() {
+                        Action::CreateLink(create_link) => create_link.clone(),
+                        _ => {
+                            return Ok(ValidateCallbackResult::Invalid(
+                                "The action that a DeleteLink deletes must be a CreateLink"
+                                    .to_string(),
+                            ));
+                        }
+                    };
+                    let link_type = match LinkTypes::from_type(
+                        create_link.zome_index,
+                        create_link.link_type,
+                    )? {
+                        Some(lt) => lt,
+                        None => {
+                            return Ok(ValidateCallbackResult::Valid);
+                        }
+                    };
+                    match link_type {
+                        LinkTypes::AgentToPreKeys => validate_delete_link_agent_to_pre_keys(
+                            action,
+                            create_link.clone(),
+                            base_address,
+                            create_link.target_address,
+                            create_link.tag,
+                        ),
+                        LinkTypes::MemberToGroups => validate_delete_link_member_to_groups(
+                            action,
+                            create_link.clone(),
+                            base_address,
+                            create_link.target_address,
+                            create_link.tag,
+                        ),
+                    }
+                }
                 OpRecord::CreatePrivateEntry { .. } => Ok(ValidateCallbackResult::Valid),
                 OpRecord::UpdatePrivateEntry { .. } => Ok(ValidateCallbackResult::Valid),
                 OpRecord::CreateCapClaim { .. } => Ok(ValidateCallbackResult::Valid),
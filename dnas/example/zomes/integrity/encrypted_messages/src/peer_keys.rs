@@ -1,18 +1,39 @@
 use hdi::prelude::*;
 
+/// State of the ratchet session with a peer: every message sent to it uses a new key of ours,
+/// and announces it so that the peer encrypts its next message to that key
+///
+/// The keys stay in the keystore after they are used, since the keystore can't delete them,
+/// so the session doesn't protect past messages from whoever gets access to the whole keystore,
+/// but it does from whoever only gets the agent key
 #[derive(Clone)]
 #[hdk_entry_helper]
 pub struct PeerKeys {
     pub peer: AgentPubKey,
     pub my_current_key: Option<X25519PubKey>,
     pub their_current_key: Option<X25519PubKey>,
+    /// Number of the last message sent to the peer in this session
+    #[serde(default)]
+    pub sent_messages: u32,
+    /// Highest message number received from the peer, to avoid going back to an older key
+    /// when its messages arrive out of order
+    #[serde(default)]
+    pub received_messages: u32,
+    /// Set once the peer is known to support ratchet sessions, after which messages to it are never
+    /// encrypted with the agent keys, even if its pre key can't be found
+    #[serde(default)]
+    pub ratchet: bool,
 }
 
 pub fn validate_create_peer_keys(
     _action: EntryCreationAction,
     peer_keys: PeerKeys,
 ) -> ExternResult<ValidateCallbackResult> {
-    // TODO: add the appropriate validation rules
+    if peer_keys.my_current_key.is_none() && peer_keys.their_current_key.is_none() {
+        return Ok(ValidateCallbackResult::Invalid(
+            "peer keys must have at least one key set".to_string(),
+        ));
+    }
     Ok(ValidateCallbackResult::Valid)
 }
 
@@ -0,0 +1,85 @@
+use hdi::prelude::*;
+
+/// X25519 key published by an agent so that its peers can start a session with it
+/// without having to encrypt the first message with its agent key
+///
+/// It's first published when the agent joins, and replaced with a new one each time a peer
+/// starts a session with it, while the sealed messages are encrypted to the latest one
+#[derive(Clone)]
+#[hdk_entry_helper]
+pub struct PreKey {
+    pub key: X25519PubKey,
+}
+
+pub fn validate_create_pre_key(
+    _action: EntryCreationAction,
+    _pre_key: PreKey,
+) -> ExternResult<ValidateCallbackResult> {
+    Ok(ValidateCallbackResult::Valid)
+}
+
+pub fn validate_update_pre_key(
+    _action: Update,
+    _pre_key: PreKey,
+    _original_action: EntryCreationAction,
+    _original_pre_key: PreKey,
+) -> ExternResult<ValidateCallbackResult> {
+    Ok(ValidateCallbackResult::Invalid(
+        "pre keys cannot be updated".to_string(),
+    ))
+}
+
+pub fn validate_delete_pre_key(
+    _action: Delete,
+    _original_action: EntryCreationAction,
+    _original_pre_key: PreKey,
+) -> ExternResult<ValidateCallbackResult> {
+    Ok(ValidateCallbackResult::Invalid(
+        "pre keys cannot be deleted".to_string(),
+    ))
+}
+
+pub fn validate_create_link_agent_to_pre_keys(
+    action: CreateLink,
+    base_address: AnyLinkableHash,
+    target_address: AnyLinkableHash,
+    _tag: LinkTag,
+) -> ExternResult<ValidateCallbackResult> {
+    if base_address
+        .into_agent_pub_key()
+        .ne(&Some(action.author.clone()))
+    {
+        return Ok(ValidateCallbackResult::Invalid(String::from(
+            "The base of an AgentToPreKeys link must be its author",
+        )));
+    }
+
+    let entry_hash = target_address
+        .into_entry_hash()
+        .ok_or(wasm_error!(WasmErrorInner::Guest(
+            "No entry hash associated with link".to_string()
+        )))?;
+    let entry = must_get_entry(entry_hash)?;
+    if PreKey::try_from(entry.content).is_err() {
+        return Ok(ValidateCallbackResult::Invalid(String::from(
+            "The target of an AgentToPreKeys link must be a PreKey",
+        )));
+    }
+
+    Ok(ValidateCallbackResult::Valid)
+}
+
+pub fn validate_delete_link_agent_to_pre_keys(
+    action: DeleteLink,
+    original_action: CreateLink,
+    _base: AnyLinkableHash,
+    _target: AnyLinkableHash,
+    _tag: LinkTag,
+) -> ExternResult<ValidateCallbackResult> {
+    if action.author.ne(&original_action.author) {
+        return Ok(ValidateCallbackResult::Invalid(String::from(
+            "AgentToPreKeys links can only be deleted by their author",
+        )));
+    }
+    Ok(ValidateCallbackResult::Valid)
+}
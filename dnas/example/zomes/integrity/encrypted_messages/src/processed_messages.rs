@@ -0,0 +1,44 @@
+use std::collections::{BTreeMap, VecDeque};
+
+use hdi::prelude::*;
+
+use crate::MessageId;
+
+/// Ids of the messages that were already decrypted, by the agent that sent them, oldest first,
+/// to drop the ones that get delivered again
+///
+/// Some entries are snapshots of all the ids that are still remembered, and the ones after them
+/// only hold the ids that were processed since the previous entry
+#[derive(Clone)]
+#[hdk_entry_helper]
+pub struct ProcessedMessages {
+    pub message_ids: BTreeMap<AgentPubKey, VecDeque<MessageId>>,
+}
+
+pub fn validate_create_processed_messages(
+    _action: EntryCreationAction,
+    _processed_messages: ProcessedMessages,
+) -> ExternResult<ValidateCallbackResult> {
+    Ok(ValidateCallbackResult::Valid)
+}
+
+pub fn validate_update_processed_messages(
+    _action: Update,
+    _processed_messages: ProcessedMessages,
+    _original_action: EntryCreationAction,
+    _original_processed_messages: ProcessedMessages,
+) -> ExternResult<ValidateCallbackResult> {
+    Ok(ValidateCallbackResult::Invalid(
+        "processed messages cannot be updated".to_string(),
+    ))
+}
+
+pub fn validate_delete_processed_messages(
+    _action: Delete,
+    _original_action: EntryCreationAction,
+    _original_processed_messages: ProcessedMessages,
+) -> ExternResult<ValidateCallbackResult> {
+    Ok(ValidateCallbackResult::Invalid(
+        "processed messages cannot be deleted".to_string(),
+    ))
+}
@@ -0,0 +1,37 @@
+use std::time::Duration;
+
+use hdi::prelude::*;
+
+/// Time after which the chunks of a message that hasn't been fully received are deleted,
+/// by then the rest of its chunks have expired in the safehold service
+pub const DEFAULT_PENDING_CHUNKS_TIMEOUT: Duration = Duration::from_secs(60 * 60 * 24 * 7); // 7 days
+
+#[derive(Serialize, Deserialize, Debug, Clone, SerializedBytes)]
+pub struct EncryptedMessagesProperties {
+    #[serde(default = "default_pending_chunks_timeout_secs")]
+    pub pending_chunks_timeout_secs: u64,
+}
+
+fn default_pending_chunks_timeout_secs() -> u64 {
+    DEFAULT_PENDING_CHUNKS_TIMEOUT.as_secs()
+}
+
+impl Default for EncryptedMessagesProperties {
+    fn default() -> Self {
+        Self {
+            pending_chunks_timeout_secs: default_pending_chunks_timeout_secs(),
+        }
+    }
+}
+
+impl EncryptedMessagesProperties {
+    pub fn pending_chunks_timeout(&self) -> Duration {
+        Duration::from_secs(self.pending_chunks_timeout_secs)
+    }
+}
+
+pub fn encrypted_messages_properties() -> ExternResult<EncryptedMessagesProperties> {
+    let properties = dna_info()?.modifiers.properties;
+    // The DNA can be installed without any properties
+    Ok(EncryptedMessagesProperties::try_from(properties).unwrap_or_default())
+}
@@ -1,8 +1,9 @@
 use hdk::prelude::*;
 use proxy_integrity::*;
-use safehold_types::ProxiedCall;
+use safehold_types::{ProxiedCall, SafeholdError};
 use utils::create_relaxed;
 
+mod provider_settings;
 mod utils;
 
 #[hdk_extern]
@@ -12,46 +13,111 @@ pub fn create_proxied_dna(proxied_dna: DnaHash) -> ExternResult<()> {
 }
 
 #[hdk_extern]
-pub fn query_proxied_dna() -> ExternResult<Option<DnaHash>> {
+pub fn retire_proxied_dna(proxied_dna: DnaHash) -> ExternResult<()> {
+    create_relaxed(EntryTypes::RetiredProxiedDna(RetiredProxiedDna {
+        proxied_dna,
+    }))?;
+    Ok(())
+}
+
+fn query_entries<T: TryFrom<Entry>>(entry_type: UnitEntryTypes) -> ExternResult<Vec<(Record, T)>> {
     let records = query(
         ChainQueryFilter::new()
             .include_entries(true)
-            .entry_type(UnitEntryTypes::ProxiedDna.try_into()?),
+            .entry_type(entry_type.try_into()?),
     )?;
 
-    let Some(last_record) = records.into_iter().max_by_key(|r| r.action().timestamp()) else {
-        return Ok(None);
-    };
-    let Some(entry) = last_record.entry().as_option() else {
-        return Ok(None);
-    };
-    let Ok(role) = ProxiedDna::try_from(entry) else {
-        return Ok(None);
+    let entries = records
+        .into_iter()
+        .filter_map(|record| {
+            let entry = record.entry().as_option()?.clone();
+            let Ok(entry) = T::try_from(entry) else {
+                return None;
+            };
+            Some((record, entry))
+        })
+        .collect();
+    Ok(entries)
+}
+
+/// Returns the proxied DNAs that have not been retired, the newest first
+#[hdk_extern]
+pub fn query_proxied_dnas() -> ExternResult<Vec<DnaHash>> {
+    let retired: BTreeSet<DnaHash> =
+        query_entries::<RetiredProxiedDna>(UnitEntryTypes::RetiredProxiedDna)?
+            .into_iter()
+            .map(|(_, retired)| retired.proxied_dna)
+            .collect();
+
+    let mut proxied_dnas = query_entries::<ProxiedDna>(UnitEntryTypes::ProxiedDna)?;
+    proxied_dnas.sort_by_key(|(record, _)| std::cmp::Reverse(record.action().timestamp()));
+
+    let mut active_dnas: Vec<DnaHash> = vec![];
+    for (_, proxied_dna) in proxied_dnas {
+        if !retired.contains(&proxied_dna.proxied_dna)
+            && !active_dnas.contains(&proxied_dna.proxied_dna)
+        {
+            active_dnas.push(proxied_dna.proxied_dna);
+        }
+    }
+    Ok(active_dnas)
+}
+
+/// Returns the newest proxied DNA, to which new data is written
+#[hdk_extern]
+pub fn query_proxied_dna() -> ExternResult<Option<DnaHash>> {
+    Ok(query_proxied_dnas(())?.into_iter().next())
+}
+
+fn call_proxied_dna(dna_hash: DnaHash, input: &ProxiedCall) -> ExternResult<ExternIO> {
+    let cell_id = CellId::new(dna_hash, agent_info()?.agent_initial_pubkey);
+
+    let response = HDK
+        .with(|h| {
+            h.borrow().call(vec![Call::new(
+                CallTarget::ConductorCell(CallTargetCell::OtherCell(cell_id)),
+                input.zome_name.clone(),
+                input.fn_name.clone(),
+                None,
+                input.payload.clone(),
+            )])
+        })
+        .map_err(SafeholdError::from_wasm_error)?;
+    let result = match response.into_iter().next() {
+        Some(ZomeCallResponse::Ok(result)) => result,
+        Some(ZomeCallResponse::NetworkError(err)) => return Err(SafeholdError::Network(err).into()),
+        response => {
+            return Err(SafeholdError::from_message(format!(
+                "Failed to make proxied call: {response:?}"
+            ))
+            .into())
+        }
     };
-    Ok(Some(role.proxied_dna))
+
+    Ok(result)
 }
 
 #[hdk_extern]
 pub fn proxied_call(input: ProxiedCall) -> ExternResult<ExternIO> {
     let Some(dna_hash) = query_proxied_dna(())? else {
-        return Err(wasm_error!("No proxied role found"));
+        return Err(SafeholdError::NoProxiedDna.into());
     };
 
-    let cell_id = CellId::new(dna_hash, agent_info()?.agent_initial_pubkey);
+    call_proxied_dna(dna_hash, &input)
+}
 
-    let response = HDK.with(|h| {
-        h.borrow().call(vec![Call::new(
-            CallTarget::ConductorCell(CallTargetCell::OtherCell(cell_id)),
-            input.zome_name,
-            input.fn_name,
-            None,
-            input.payload,
-        )])
-    })?;
-    let Some(ZomeCallResponse::Ok(result)) = response.get(0) else {
-        return Err(wasm_error!("Failed to make proxied call: {response:?}"));
-    };
+/// Makes the call in all the proxied DNAs that have not been retired, the newest first
+///
+/// While the safehold clones are rotating, the old and the new epochs are both active
+#[hdk_extern]
+pub fn proxied_call_all(input: ProxiedCall) -> ExternResult<Vec<ExternIO>> {
+    let dna_hashes = query_proxied_dnas(())?;
+    if dna_hashes.is_empty() {
+        return Err(SafeholdError::NoProxiedDna.into());
+    }
 
-    // let result: ExternIO = result.decode().map_err(|err| wasm_error!("{}", err))?;
-    Ok(result.clone())
+    dna_hashes
+        .into_iter()
+        .map(|dna_hash| call_proxied_dna(dna_hash, &input))
+        .collect()
 }
@@ -0,0 +1,108 @@
+use std::time::Duration;
+
+use hdk::prelude::*;
+use proxy_integrity::*;
+
+use crate::utils::{create_link_relaxed, create_relaxed};
+
+/// Each provider links to its settings again after this interval, to show that it's still running
+const PROVIDER_SETTINGS_REFRESH_INTERVAL: Duration = Duration::from_secs(60 * 10); // 10 minutes
+
+/// Providers that haven't refreshed their settings for this long are considered to have stopped
+const PROVIDER_SETTINGS_EXPIRY: Duration = Duration::from_secs(60 * 30); // 30 minutes
+
+fn all_provider_settings_path() -> ExternResult<TypedPath> {
+    Path::from("all_provider_settings").typed(LinkTypes::AllProviderSettings)
+}
+
+/// Returns the latest link to the settings of each provider, ignoring the providers that stopped refreshing them
+fn get_latest_provider_settings_links() -> ExternResult<BTreeMap<AgentPubKey, Link>> {
+    let base = all_provider_settings_path()?.path_entry_hash()?;
+    let links =
+        get_links(GetLinksInputBuilder::try_new(base, LinkTypes::AllProviderSettings)?.build())?;
+
+    let mut latest_links: BTreeMap<AgentPubKey, Link> = BTreeMap::new();
+    for link in links {
+        let is_latest = latest_links
+            .get(&link.author)
+            .map(|latest| latest.timestamp < link.timestamp)
+            .unwrap_or(true);
+        if is_latest {
+            latest_links.insert(link.author.clone(), link);
+        }
+    }
+
+    let now = sys_time()?;
+    latest_links.retain(|_, link| {
+        now.as_micros() - link.timestamp.as_micros() < PROVIDER_SETTINGS_EXPIRY.as_micros() as i64
+    });
+
+    Ok(latest_links)
+}
+
+/// Publishes the settings of this provider if they changed or if they are about to expire,
+/// and returns the latest settings published by each of the other live providers
+#[hdk_extern]
+pub fn announce_provider_settings(
+    provider_settings: ProviderSettings,
+) -> ExternResult<BTreeMap<AgentPubKey, ProviderSettings>> {
+    let my_pub_key = agent_info()?.agent_initial_pubkey;
+    let latest_links = get_latest_provider_settings_links()?;
+
+    let provider_settings_hash = hash_entry(&provider_settings)?;
+    let my_latest_link = latest_links.get(&my_pub_key);
+    let already_published = my_latest_link
+        .and_then(|link| link.target.clone().into_entry_hash())
+        .is_some_and(|target| target.eq(&provider_settings_hash));
+    let now = sys_time()?;
+    let recently_linked = my_latest_link.is_some_and(|link| {
+        now.as_micros() - link.timestamp.as_micros()
+            < PROVIDER_SETTINGS_REFRESH_INTERVAL.as_micros() as i64
+    });
+
+    if !already_published {
+        create_relaxed(EntryTypes::ProviderSettings(provider_settings))?;
+    }
+    if !already_published || !recently_linked {
+        create_link_relaxed(
+            all_provider_settings_path()?.path_entry_hash()?,
+            provider_settings_hash,
+            LinkTypes::AllProviderSettings,
+            (),
+        )?;
+    }
+
+    get_peers_settings(latest_links, &my_pub_key)
+}
+
+/// Returns the latest settings published by each of the live providers other than this one,
+/// for providers that can't publish their own settings because they were not delegated
+#[hdk_extern]
+pub fn get_providers_settings() -> ExternResult<BTreeMap<AgentPubKey, ProviderSettings>> {
+    let my_pub_key = agent_info()?.agent_initial_pubkey;
+    get_peers_settings(get_latest_provider_settings_links()?, &my_pub_key)
+}
+
+fn get_peers_settings(
+    latest_links: BTreeMap<AgentPubKey, Link>,
+    my_pub_key: &AgentPubKey,
+) -> ExternResult<BTreeMap<AgentPubKey, ProviderSettings>> {
+    let mut peers_settings: BTreeMap<AgentPubKey, ProviderSettings> = BTreeMap::new();
+    for (author, link) in latest_links {
+        if author.eq(my_pub_key) {
+            continue;
+        }
+        let Some(entry_hash) = link.target.into_entry_hash() else {
+            continue;
+        };
+        let Some(record) = get(entry_hash, GetOptions::default())? else {
+            continue;
+        };
+        let Ok(Some(settings)) = record.entry().to_app_option::<ProviderSettings>() else {
+            continue;
+        };
+        peers_settings.insert(author, settings);
+    }
+
+    Ok(peers_settings)
+}
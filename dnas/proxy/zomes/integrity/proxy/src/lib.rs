@@ -2,6 +2,9 @@ pub mod proxied_role;
 use hdi::prelude::*;
 pub use proxied_role::*;
 
+pub mod provider_settings;
+pub use provider_settings::*;
+
 #[derive(Serialize, Deserialize)]
 #[serde(tag = "type")]
 #[hdk_entry_types]
@@ -9,6 +12,15 @@ pub use proxied_role::*;
 pub enum EntryTypes {
     #[entry_type(visibility = "private")]
     ProxiedDna(ProxiedDna),
+    ProviderSettings(ProviderSettings),
+    #[entry_type(visibility = "private")]
+    RetiredProxiedDna(RetiredProxiedDna),
+}
+
+#[derive(Serialize, Deserialize)]
+#[hdk_link_types]
+pub enum LinkTypes {
+    AllProviderSettings,
 }
 
 // Validation you perform during the genesis process. Nobody else on the network performs it, only you.
@@ -47,12 +59,24 @@ pub fn validate_agent_joining(
 // You can read more about validation here: https://docs.rs/hdi/latest/hdi/index.html#data-validation
 #[hdk_extern]
 pub fn validate(op: Op) -> ExternResult<ValidateCallbackResult> {
-    match op.flattened::<EntryTypes, ()>()? {
+    match op.flattened::<EntryTypes, LinkTypes>()? {
         FlatOp::StoreEntry(store_entry) => match store_entry {
             OpEntry::CreateEntry { app_entry, action } => match app_entry {
                 EntryTypes::ProxiedDna(proxied_role) => {
                     validate_create_proxied_role(EntryCreationAction::Create(action), proxied_role)
                 }
+                EntryTypes::ProviderSettings(provider_settings) => {
+                    validate_create_provider_settings(
+                        EntryCreationAction::Create(action),
+                        provider_settings,
+                    )
+                }
+                EntryTypes::RetiredProxiedDna(retired_proxied_dna) => {
+                    validate_create_retired_proxied_dna(
+                        EntryCreationAction::Create(action),
+                        retired_proxied_dna,
+                    )
+                }
             },
             OpEntry::UpdateEntry {
                 app_entry, action, ..
@@ -60,6 +84,18 @@ pub fn validate(op: Op) -> ExternResult<ValidateCallbackResult> {
                 EntryTypes::ProxiedDna(proxied_role) => {
                     validate_create_proxied_role(EntryCreationAction::Update(action), proxied_role)
                 }
+                EntryTypes::ProviderSettings(provider_settings) => {
+                    validate_create_provider_settings(
+                        EntryCreationAction::Update(action),
+                        provider_settings,
+                    )
+                }
+                EntryTypes::RetiredProxiedDna(retired_proxied_dna) => {
+                    validate_create_retired_proxied_dna(
+                        EntryCreationAction::Update(action),
+                        retired_proxied_dna,
+                    )
+                }
             },
             _ => Ok(ValidateCallbackResult::Valid),
         },
@@ -95,6 +131,44 @@ pub fn validate(op: Op) -> ExternResult<ValidateCallbackResult> {
                             original_proxied_role,
                         )
                     }
+                    EntryTypes::ProviderSettings(provider_settings) => {
+                        let original_app_entry =
+                            must_get_valid_record(action.clone().original_action_address)?;
+                        let original_provider_settings =
+                            match ProviderSettings::try_from(original_app_entry) {
+                                Ok(entry) => entry,
+                                Err(e) => {
+                                    return Ok(ValidateCallbackResult::Invalid(format!(
+                                        "Expected to get ProviderSettings from Record: {e:?}"
+                                    )));
+                                }
+                            };
+                        validate_update_provider_settings(
+                            action,
+                            provider_settings,
+                            original_create_action,
+                            original_provider_settings,
+                        )
+                    }
+                    EntryTypes::RetiredProxiedDna(retired_proxied_dna) => {
+                        let original_app_entry =
+                            must_get_valid_record(action.clone().original_action_address)?;
+                        let original_retired_proxied_dna =
+                            match RetiredProxiedDna::try_from(original_app_entry) {
+                                Ok(entry) => entry,
+                                Err(e) => {
+                                    return Ok(ValidateCallbackResult::Invalid(format!(
+                                        "Expected to get RetiredProxiedDna from Record: {e:?}"
+                                    )));
+                                }
+                            };
+                        validate_update_retired_proxied_dna(
+                            action,
+                            retired_proxied_dna,
+                            original_create_action,
+                            original_retired_proxied_dna,
+                        )
+                    }
                 }
             }
             _ => Ok(ValidateCallbackResult::Valid),
@@ -144,6 +218,20 @@ pub fn validate(op: Op) -> ExternResult<ValidateCallbackResult> {
                     original_action,
                     original_proxied_role,
                 ),
+                EntryTypes::ProviderSettings(original_provider_settings) => {
+                    validate_delete_provider_settings(
+                        delete_entry.clone().action,
+                        original_action,
+                        original_provider_settings,
+                    )
+                }
+                EntryTypes::RetiredProxiedDna(original_retired_proxied_dna) => {
+                    validate_delete_retired_proxied_dna(
+                        delete_entry.clone().action,
+                        original_action,
+                        original_retired_proxied_dna,
+                    )
+                }
             }
         }
         FlatOp::RegisterCreateLink {
@@ -152,9 +240,14 @@ pub fn validate(op: Op) -> ExternResult<ValidateCallbackResult> {
             target_address,
             tag,
             action,
-        } => Ok(ValidateCallbackResult::Invalid(
-            "There are no link types in this integrity zome".to_string(),
-        )),
+        } => match link_type {
+            LinkTypes::AllProviderSettings => validate_create_link_all_provider_settings(
+                action,
+                base_address,
+                target_address,
+                tag,
+            ),
+        },
         FlatOp::RegisterDeleteLink {
             link_type,
             base_address,
@@ -162,9 +255,11 @@ pub fn validate(op: Op) -> ExternResult<ValidateCallbackResult> {
             tag,
             original_action,
             action,
-        } => Ok(ValidateCallbackResult::Invalid(
-            "There are no link types in this integrity zome".to_string(),
-        )),
+        } => match link_type {
+            LinkTypes::AllProviderSettings => Ok(ValidateCallbackResult::Invalid(String::from(
+                "AllProviderSettings links cannot be deleted",
+            ))),
+        },
         FlatOp::StoreRecord(store_record) => {
             match store_record {
                 // Complementary validation to the `StoreEntry` Op, in which the record itself is validated
@@ -175,6 +270,18 @@ pub fn validate(op: Op) -> ExternResult<ValidateCallbackResult> {
                         EntryCreationAction::Create(action),
                         proxied_role,
                     ),
+                    EntryTypes::ProviderSettings(provider_settings) => {
+                        validate_create_provider_settings(
+                            EntryCreationAction::Create(action),
+                            provider_settings,
+                        )
+                    }
+                    EntryTypes::RetiredProxiedDna(retired_proxied_dna) => {
+                        validate_create_retired_proxied_dna(
+                            EntryCreationAction::Create(action),
+                            retired_proxied_dna,
+                        )
+                    }
                 },
                 // Complementary validation to the `RegisterUpdate` Op, in which the record itself is validated
                 // If you want to optimize performance, you can remove the validation for an entry type here and keep it in `StoreEntry` and in `RegisterUpdate`
@@ -229,6 +336,71 @@ pub fn validate(op: Op) -> ExternResult<ValidateCallbackResult> {
                                 Ok(result)
                             }
                         }
+                        EntryTypes::ProviderSettings(provider_settings) => {
+                            let result = validate_create_provider_settings(
+                                EntryCreationAction::Update(action.clone()),
+                                provider_settings.clone(),
+                            )?;
+                            if let ValidateCallbackResult::Valid = result {
+                                let original_provider_settings: Option<ProviderSettings> =
+                                    original_record
+                                        .entry()
+                                        .to_app_option()
+                                        .map_err(|e| wasm_error!(e))?;
+                                let original_provider_settings = match original_provider_settings {
+                                    Some(provider_settings) => provider_settings,
+                                    None => {
+                                        return Ok(
+                                            ValidateCallbackResult::Invalid(
+                                                "The updated entry type must be the same as the original entry type"
+                                                    .to_string(),
+                                            ),
+                                        );
+                                    }
+                                };
+                                validate_update_provider_settings(
+                                    action,
+                                    provider_settings,
+                                    original_action,
+                                    original_provider_settings,
+                                )
+                            } else {
+                                Ok(result)
+                            }
+                        }
+                        EntryTypes::RetiredProxiedDna(retired_proxied_dna) => {
+                            let result = validate_create_retired_proxied_dna(
+                                EntryCreationAction::Update(action.clone()),
+                                retired_proxied_dna.clone(),
+                            )?;
+                            if let ValidateCallbackResult::Valid = result {
+                                let original_retired_proxied_dna: Option<RetiredProxiedDna> =
+                                    original_record
+                                        .entry()
+                                        .to_app_option()
+                                        .map_err(|e| wasm_error!(e))?;
+                                let original_retired_proxied_dna =
+                                    match original_retired_proxied_dna {
+                                        Some(retired_proxied_dna) => retired_proxied_dna,
+                                        None => {
+                                            return Ok(
+                                            ValidateCallbackResult::Invalid(
+                                                "The updated entry type must be the same as the original entry type"
+                                                    .to_string(),
+                                            ),
+                                        );
+                                        }
+                                    };
+                                validate_update_retired_proxied_dna(
+                                    action,
+                                    retired_proxied_dna,
+                                    original_action,
+                                    original_retired_proxied_dna,
+                                )
+                            } else {
+                                Ok(result)
+                            }
+                        }
                     }
                 }
                 // Complementary validation to the `RegisterDelete` Op, in which the record itself is validated
@@ -288,6 +460,20 @@ pub fn validate(op: Op) -> ExternResult<ValidateCallbackResult> {
                                 original_proxied_role,
                             )
                         }
+                        EntryTypes::ProviderSettings(original_provider_settings) => {
+                            validate_delete_provider_settings(
+                                action,
+                                original_action,
+                                original_provider_settings,
+                            )
+                        }
+                        EntryTypes::RetiredProxiedDna(original_retired_proxied_dna) => {
+                            validate_delete_retired_proxied_dna(
+                                action,
+                                original_action,
+                                original_retired_proxied_dna,
+                            )
+                        }
                     }
                 }
                 // Complementary validation to the `RegisterCreateLink` Op, in which the record itself is validated
@@ -299,9 +485,14 @@ pub fn validate(op: Op) -> ExternResult<ValidateCallbackResult> {
                     tag,
                     link_type,
                     action,
-                } => Ok(ValidateCallbackResult::Invalid(
-                    "There are no link types in this integrity zome".to_string(),
-                )),
+                } => match link_type {
+                    LinkTypes::AllProviderSettings => validate_create_link_all_provider_settings(
+                        action,
+                        base_address,
+                        target_address,
+                        tag,
+                    ),
+                },
                 // Complementary validation to the `RegisterDeleteLink` Op, in which the record itself is validated
                 // If you want to optimize performance, you can remove the validation for an entry type here and keep it in `RegisterDeleteLink`
                 // Notice that doing so will cause `must_get_valid_record` for this record to return a valid record even if the `RegisterDeleteLink` validation failed
@@ -310,7 +501,7 @@ pub fn validate(op: Op) -> ExternResult<ValidateCallbackResult> {
                     base_address,
                     action,
                 } => Ok(ValidateCallbackResult::Invalid(
-                    "There are no link types in this integrity zome".to_string(),
+                    "The links in this integrity zome cannot be deleted".to_string(),
                 )),
                 OpRecord::CreatePrivateEntry { .. } => Ok(ValidateCallbackResult::Valid),
                 OpRecord::UpdatePrivateEntry { .. } => Ok(ValidateCallbackResult::Valid),
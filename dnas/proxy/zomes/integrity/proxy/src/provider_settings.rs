@@ -0,0 +1,115 @@
+use hdi::prelude::*;
+pub use safehold_types::ProviderSettings;
+use safehold_types::{ProviderDelegation, SafeholdProperties};
+
+/// The proxy DNA is installed with the same progenitors as the safehold DNA
+fn is_progenitor(agent: &AgentPubKey) -> ExternResult<bool> {
+    let properties = dna_info()?.modifiers.properties;
+    let properties = SafeholdProperties::try_from(properties).map_err(|err| wasm_error!(err))?;
+    Ok(properties
+        .progenitors
+        .contains(&AgentPubKeyB64::from(agent.clone())))
+}
+
+/// Only progenitors and the providers delegated by them can publish their settings,
+/// so that other agents can't interfere with the epoch length that the providers agree on
+pub fn validate_create_provider_settings(
+    action: EntryCreationAction,
+    provider_settings: ProviderSettings,
+) -> ExternResult<ValidateCallbackResult> {
+    validate_provider_settings_author(action.author(), *action.timestamp(), provider_settings)
+}
+
+/// The delegation must be for this proxy DNA and not have expired when the settings were published
+fn validate_provider_settings_author(
+    author: &AgentPubKey,
+    timestamp: Timestamp,
+    provider_settings: ProviderSettings,
+) -> ExternResult<ValidateCallbackResult> {
+    let Some(delegation) = provider_settings.delegation else {
+        if is_progenitor(author)? {
+            return Ok(ValidateCallbackResult::Valid);
+        }
+        return Ok(ValidateCallbackResult::Invalid(String::from(
+            "Only progenitors can publish provider settings without a delegation",
+        )));
+    };
+
+    if delegation.delegate.ne(author) {
+        return Ok(ValidateCallbackResult::Invalid(String::from(
+            "The delegation is not for the author of the provider settings",
+        )));
+    }
+    if delegation.proxy_dna_hash.ne(&dna_info()?.hash) {
+        return Ok(ValidateCallbackResult::Invalid(String::from(
+            "The delegation is for the providers of another network",
+        )));
+    }
+    if delegation.is_expired(timestamp) {
+        return Ok(ValidateCallbackResult::Invalid(String::from(
+            "The delegation had expired",
+        )));
+    }
+    if !is_progenitor(&delegation.delegator)? {
+        return Ok(ValidateCallbackResult::Invalid(String::from(
+            "The delegator is not a progenitor",
+        )));
+    }
+    let valid = verify_signature_raw(
+        delegation.delegator.clone(),
+        delegation.signature.clone(),
+        ProviderDelegation::signed_data(
+            &delegation.delegate,
+            &delegation.proxy_dna_hash,
+            delegation.expires_at,
+        ),
+    )?;
+    if !valid {
+        return Ok(ValidateCallbackResult::Invalid(String::from(
+            "Invalid delegation signature",
+        )));
+    }
+    Ok(ValidateCallbackResult::Valid)
+}
+
+pub fn validate_update_provider_settings(
+    _action: Update,
+    _provider_settings: ProviderSettings,
+    _original_action: EntryCreationAction,
+    _original_provider_settings: ProviderSettings,
+) -> ExternResult<ValidateCallbackResult> {
+    Ok(ValidateCallbackResult::Invalid(
+        "Provider settings cannot be updated".to_string(),
+    ))
+}
+
+pub fn validate_delete_provider_settings(
+    _action: Delete,
+    _original_action: EntryCreationAction,
+    _original_provider_settings: ProviderSettings,
+) -> ExternResult<ValidateCallbackResult> {
+    Ok(ValidateCallbackResult::Invalid(
+        "Provider settings cannot be deleted".to_string(),
+    ))
+}
+
+pub fn validate_create_link_all_provider_settings(
+    action: CreateLink,
+    _base_address: AnyLinkableHash,
+    target_address: AnyLinkableHash,
+    _tag: LinkTag,
+) -> ExternResult<ValidateCallbackResult> {
+    let entry_hash = target_address
+        .into_entry_hash()
+        .ok_or(wasm_error!(WasmErrorInner::Guest(
+            "No entry hash associated with link".to_string()
+        )))?;
+    let entry = must_get_entry(entry_hash)?;
+    let Ok(provider_settings) = ProviderSettings::try_from(entry.content) else {
+        return Ok(ValidateCallbackResult::Invalid(String::from(
+            "AllProviderSettings links must point to a ProviderSettings entry",
+        )));
+    };
+    // The settings are attributed to the author of the link, so they must have been published for them
+    validate_provider_settings_author(&action.author, action.timestamp, provider_settings)
+}
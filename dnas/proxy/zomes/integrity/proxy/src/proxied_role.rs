@@ -34,3 +34,38 @@ pub fn validate_delete_proxied_role(
         "Proxied Roles cannot be deleted".to_string(),
     ))
 }
+
+/// Marks a proxied DNA whose cell has been removed, so that calls are not forwarded to it anymore
+#[derive(Clone, PartialEq)]
+#[hdk_entry_helper]
+pub struct RetiredProxiedDna {
+    pub proxied_dna: DnaHash,
+}
+
+pub fn validate_create_retired_proxied_dna(
+    _action: EntryCreationAction,
+    _retired_proxied_dna: RetiredProxiedDna,
+) -> ExternResult<ValidateCallbackResult> {
+    Ok(ValidateCallbackResult::Valid)
+}
+
+pub fn validate_update_retired_proxied_dna(
+    _action: Update,
+    _retired_proxied_dna: RetiredProxiedDna,
+    _original_action: EntryCreationAction,
+    _original_retired_proxied_dna: RetiredProxiedDna,
+) -> ExternResult<ValidateCallbackResult> {
+    Ok(ValidateCallbackResult::Invalid(
+        "Retired Proxied DNAs cannot be updated".to_string(),
+    ))
+}
+
+pub fn validate_delete_retired_proxied_dna(
+    _action: Delete,
+    _original_action: EntryCreationAction,
+    _original_retired_proxied_dna: RetiredProxiedDna,
+) -> ExternResult<ValidateCallbackResult> {
+    Ok(ValidateCallbackResult::Invalid(
+        "Retired Proxied DNAs cannot be deleted".to_string(),
+    ))
+}
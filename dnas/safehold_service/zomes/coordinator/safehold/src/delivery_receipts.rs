@@ -0,0 +1,104 @@
+use hdk::prelude::*;
+use safehold_integrity::*;
+use safehold_types::{DeliveryReceipt, GetDeliveryReceiptsInput};
+
+use crate::provider_proof::query_joining_delegation;
+use crate::utils::{create_link_relaxed, create_relaxed};
+
+/// Creates the delivery receipts for the given messages acknowledged by the recipient,
+/// skipping the messages whose sender didn't request them
+pub fn create_delivery_receipts(
+    recipient: &AgentPubKey,
+    message_hashes: Vec<EntryHash>,
+) -> ExternResult<()> {
+    let provider = agent_info()?.agent_initial_pubkey;
+    let delegation = query_joining_delegation()?.map(|(_, delegation)| delegation);
+    let now = sys_time()?;
+
+    for message_hash in message_hashes {
+        let Some(record) = get(message_hash.clone(), GetOptions::default())? else {
+            continue;
+        };
+        let Ok(Some(message)) = record.entry().to_app_option::<MessageWithProvenance>() else {
+            continue;
+        };
+        if !message.message.delivery_receipt || !message.message.recipients.contains_key(recipient)
+        {
+            continue;
+        }
+
+        let receipt = DeliveryReceipt {
+            message_hash: message_hash.clone(),
+            recipient: recipient.clone(),
+            delivered_at: now,
+        };
+        let signature = sign(provider.clone(), &receipt)?;
+        let signed_receipt = SignedDeliveryReceipt {
+            receipt,
+            provider: provider.clone(),
+            signature,
+            delegation: delegation.clone(),
+        };
+        create_delivery_receipt(message_hash, signed_receipt)?;
+    }
+
+    Ok(())
+}
+
+pub fn create_delivery_receipt(
+    message_hash: EntryHash,
+    signed_receipt: SignedDeliveryReceipt,
+) -> ExternResult<()> {
+    let receipt_hash = hash_entry(&signed_receipt)?;
+
+    create_relaxed(EntryTypes::DeliveryReceipt(signed_receipt))?;
+    create_link_relaxed(
+        message_hash,
+        receipt_hash,
+        LinkTypes::MessageToDeliveryReceipts,
+        (),
+    )
+}
+
+/// Returns the delivery receipts for the given messages, ignoring the ones not sent by `input.sender`
+#[hdk_extern]
+pub fn get_delivery_receipts_for_sender(
+    input: GetDeliveryReceiptsInput,
+) -> ExternResult<Vec<SignedDeliveryReceipt>> {
+    let mut receipts: Vec<SignedDeliveryReceipt> = vec![];
+
+    for message_hash in input.message_hashes {
+        let Some(record) = get(message_hash.clone(), GetOptions::default())? else {
+            continue;
+        };
+        let Ok(Some(message)) = record.entry().to_app_option::<MessageWithProvenance>() else {
+            continue;
+        };
+        if message.provenance.ne(&input.sender) {
+            continue;
+        }
+
+        let links = get_links(
+            GetLinksInputBuilder::try_new(message_hash, LinkTypes::MessageToDeliveryReceipts)?
+                .build(),
+        )?;
+
+        let get_inputs: Vec<GetInput> = links
+            .into_iter()
+            .filter_map(|link| link.target.into_entry_hash())
+            .map(|entry_hash| GetInput::new(entry_hash.into(), GetOptions::default()))
+            .collect();
+
+        let records = HDK.with(|h| h.borrow().get(get_inputs))?;
+
+        receipts.extend(records.into_iter().flatten().filter_map(|record| {
+            record
+                .entry()
+                .to_app_option::<SignedDeliveryReceipt>()
+                .ok()
+                .flatten()
+        }));
+    }
+
+    Ok(receipts)
+}
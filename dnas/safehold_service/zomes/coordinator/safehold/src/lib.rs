@@ -1,8 +1,11 @@
 use hdk::prelude::*;
 use safehold_integrity::*;
 
+pub mod delivery_receipts;
 pub mod message;
 pub mod migration;
+pub mod provider_proof;
+pub mod quotas;
 pub mod utils;
 
 // Called the first time a zome call is made to the cell containing this zome
@@ -1,13 +1,12 @@
 use hdk::prelude::*;
 use safehold_integrity::*;
 use safehold_service_trait::*;
+use safehold_types::{AckMessagesInput, AgentSpecificContents};
 
+use crate::delivery_receipts::create_delivery_receipts;
+use crate::provider_proof::{create_provider_proof, ACK_BATCH_SIZE};
 use crate::utils::{create_link_relaxed, create_relaxed, delete_link_relaxed, ensure_relaxed};
 
-fn agent_path(agent: AgentPubKey) -> ExternResult<TypedPath> {
-    Path::from(format!("all_agents.{}", agent)).typed(LinkTypes::AgentsPath)
-}
-
 #[hdk_extern]
 pub fn create_messages(inputs: Vec<MessageWithProvenance>) -> ExternResult<()> {
     for input in inputs {
@@ -28,35 +27,71 @@ pub fn create_message(message: MessageWithProvenance) -> ExternResult<EntryHash>
     };
 
     create_relaxed(EntryTypes::Message(message.clone()))?;
+    create_sender_link(&message, &message_hash)?;
 
     for (agent, contents) in message.message.recipients.clone() {
-        let path = agent_path(agent)?;
-
-        ensure_relaxed(&path)?;
-
-        create_link_relaxed(
-            path.path_entry_hash()?,
-            message_hash.clone(),
-            LinkTypes::RecipientToMessages,
-            contents,
-        )?;
+        create_recipient_link(&agent, &message, &message_hash, contents)?;
     }
     Ok(message_hash)
 }
 
-#[hdk_extern]
-pub fn get_messages_for_recipient(recipient: AgentPubKey) -> ExternResult<Vec<MessageOutput>> {
+/// Links the message from its sender, which counts it towards its quota in this epoch,
+/// unless it's sealed
+pub fn create_sender_link(
+    message: &MessageWithProvenance,
+    message_hash: &EntryHash,
+) -> ExternResult<()> {
+    // The ephemeral provenance of a sealed message never sends anything else
+    if message.message.sealed_sender {
+        return Ok(());
+    }
+
+    let path = sender_path(&message.provenance)?;
+    ensure_relaxed(&path)?;
+    create_link_relaxed(
+        path.path_entry_hash()?,
+        message_hash.clone(),
+        LinkTypes::SenderToMessages,
+        (),
+    )?;
+
+    Ok(())
+}
+
+/// Adds the message to the mailbox of the given recipient
+pub fn create_recipient_link(
+    recipient: &AgentPubKey,
+    message: &MessageWithProvenance,
+    message_hash: &EntryHash,
+    contents: AgentSpecificContents,
+) -> ExternResult<()> {
     let path = agent_path(recipient)?;
-    let links = get_links(
-        GetLinksInputBuilder::try_new(path.path_entry_hash()?, LinkTypes::RecipientToMessages)?
-            .build(),
+
+    ensure_relaxed(&path)?;
+
+    create_link_relaxed(
+        path.path_entry_hash()?,
+        message_hash.clone(),
+        LinkTypes::RecipientToMessages,
+        LinkTag::try_from(RecipientLinkTag::new(&message.message, contents))?,
     )?;
 
-    for link in &links {
-        get(link.create_link_hash.clone(), Default::default())?;
-        delete_link_relaxed(link.create_link_hash.clone())?;
-    }
+    Ok(())
+}
+
+/// Resolves the blinded mailbox of the recipient in this epoch, which the callers of the gateway never see
+pub fn get_recipient_links(recipient: AgentPubKey) -> ExternResult<Vec<Link>> {
+    let path = agent_path(&recipient)?;
+    get_links(
+        GetLinksInputBuilder::try_new(path.path_entry_hash()?, LinkTypes::RecipientToMessages)?
+            .build(),
+    )
+}
 
+/// Fetches the messages the given links point to, preserving their order
+///
+/// Links whose message can't be found or has expired are returned with `None`
+pub fn get_messages_for_links(links: Vec<Link>) -> ExternResult<Vec<(Link, Option<MessageOutput>)>> {
     let inputs = links
         .iter()
         .filter_map(|l| l.target.clone().into_entry_hash())
@@ -65,7 +100,7 @@ pub fn get_messages_for_recipient(recipient: AgentPubKey) -> ExternResult<Vec<Me
 
     let records = HDK.with(|hdk| hdk.borrow().get(inputs))?;
 
-    let messages: Vec<MessageOutput> = records
+    let messages_by_hash: BTreeMap<EntryHash, MessageWithProvenance> = records
         .into_iter()
         .filter_map(|r| r)
         .filter_map(|r| {
@@ -75,19 +110,155 @@ pub fn get_messages_for_recipient(recipient: AgentPubKey) -> ExternResult<Vec<Me
             let Ok(message) = MessageWithProvenance::try_from(entry) else {
                 return None;
             };
-            Some(message)
+            let entry_hash = r.action().entry_hash()?.clone();
+            Some((entry_hash, message))
         })
-        .zip(links)
-        .map(|(message, link)| MessageOutput {
-            provenance: message.provenance,
-            message_contents: message.message.contents,
-            agent_specific_contents: link.tag.0,
+        .collect();
+
+    let now = sys_time()?;
+
+    let messages = links
+        .into_iter()
+        .map(|link| {
+            let message = link
+                .target
+                .clone()
+                .into_entry_hash()
+                .and_then(|entry_hash| {
+                    let message = messages_by_hash.get(&entry_hash)?;
+                    if message.message.is_expired(now) {
+                        return None;
+                    }
+                    let tag = RecipientLinkTag::try_from(&link.tag).ok()?;
+                    Some(MessageOutput {
+                        message_hash: entry_hash,
+                        provenance: message.provenance.clone(),
+                        message_contents: message.message.contents.clone(),
+                        agent_specific_contents: tag.contents,
+                    })
+                });
+            (link, message)
         })
         .collect();
 
+    Ok(messages)
+}
+
+#[hdk_extern]
+pub fn get_messages_for_recipient(recipient: AgentPubKey) -> ExternResult<Vec<MessageOutput>> {
+    let links = get_recipient_links(recipient)?;
+
+    let messages: Vec<MessageOutput> = get_messages_for_links(links)?
+        .into_iter()
+        .filter_map(|(_link, message)| message)
+        .collect();
+
     if !messages.is_empty() {
         info!("Delived {} messages.", messages.len());
     }
 
     Ok(messages)
 }
+
+fn link_cursor(link: &Link) -> MessagesCursor {
+    MessagesCursor {
+        timestamp: link.timestamp,
+        create_link_hash: link.create_link_hash.clone(),
+    }
+}
+
+#[hdk_extern]
+pub fn get_messages_page_for_recipient(
+    input: GetMessagesPageForRecipientInput,
+) -> ExternResult<MessagesPage> {
+    let mut links = get_recipient_links(input.recipient)?;
+    links.sort_by(|a, b| {
+        (a.timestamp, &a.create_link_hash).cmp(&(b.timestamp, &b.create_link_hash))
+    });
+
+    let pending_links: Vec<Link> = links
+        .into_iter()
+        .filter(|link| match &input.page.cursor {
+            Some(cursor) => {
+                (link.timestamp, &link.create_link_hash)
+                    > (cursor.timestamp, &cursor.create_link_hash)
+            }
+            None => true,
+        })
+        .collect();
+    let pending_count = pending_links.len();
+
+    let candidate_links: Vec<Link> = pending_links
+        .into_iter()
+        .take(input.page.max_count.max(1))
+        .collect();
+
+    let mut messages: Vec<MessageOutput> = vec![];
+    let mut bytes = 0;
+    let mut last_processed_link: Option<Link> = None;
+    let mut processed_count = 0;
+
+    for (link, message) in get_messages_for_links(candidate_links)? {
+        if let Some(message) = message {
+            let size = message.message_contents.len() + message.agent_specific_contents.len();
+            if !messages.is_empty() && bytes + size > input.page.max_bytes {
+                break;
+            }
+            bytes += size;
+            messages.push(message);
+        }
+        processed_count += 1;
+        last_processed_link = Some(link);
+    }
+
+    let next_cursor = match last_processed_link {
+        Some(link) if processed_count < pending_count => Some(link_cursor(&link)),
+        _ => None,
+    };
+
+    if !messages.is_empty() {
+        info!("Delived a page of {} messages.", messages.len());
+    }
+
+    Ok(MessagesPage {
+        messages,
+        next_cursor,
+    })
+}
+
+#[hdk_extern]
+pub fn ack_messages_for_recipient(input: AckMessagesInput) -> ExternResult<()> {
+    let message_hashes: BTreeSet<EntryHash> = input.message_hashes.into_iter().collect();
+    let links = get_recipient_links(input.recipient)?;
+
+    let acked_links: Vec<Link> = links
+        .into_iter()
+        .filter(|link| {
+            link.target
+                .clone()
+                .into_entry_hash()
+                .is_some_and(|entry_hash| message_hashes.contains(&entry_hash))
+        })
+        .collect();
+
+    for batch in acked_links.chunks(ACK_BATCH_SIZE) {
+        create_provider_proof()?;
+
+        for link in batch {
+            get(link.create_link_hash.clone(), Default::default())?;
+            delete_link_relaxed(link.create_link_hash.clone())?;
+        }
+
+        let acked_messages: BTreeSet<EntryHash> = batch
+            .iter()
+            .filter_map(|link| link.target.clone().into_entry_hash())
+            .collect();
+        create_delivery_receipts(&input.recipient, acked_messages.into_iter().collect())?;
+    }
+
+    if !acked_links.is_empty() {
+        info!("Acknowledged {} messages.", acked_links.len());
+    }
+
+    Ok(())
+}
@@ -1,16 +1,62 @@
 use hdk::prelude::*;
-use safehold_integrity::LinkTypes;
-use safehold_types::MessageWithProvenance;
+use safehold_integrity::{agent_path, is_mailbox_prefix, EntryTypes, LinkTypes};
+use safehold_types::{
+    ExportMessagesCursor, ExportMessagesInput, ExportMessagesPage, ExportedDeliveryReceipts,
+    ExportedMessage, MessageWithProvenance, MigrationCounts, SignedDeliveryReceipt,
+};
 
+use crate::delivery_receipts::create_delivery_receipt;
+use crate::message::create_recipient_link;
+use crate::provider_proof::create_provider_proof;
+use crate::utils::create_relaxed;
+
+/// Number of mailboxes whose links are fetched at once while exporting a page of messages
+const EXPORT_PARALLEL_SHARDS: usize = 16;
+
+fn all_agents_path() -> ExternResult<TypedPath> {
+    Path::from(format!("all_agents")).typed(LinkTypes::AgentsPath)
+}
+
+/// Returns the hashes of the agent paths that have a mailbox in this epoch,
+/// which the providers split between them to migrate the messages
 #[hdk_extern]
-pub fn export_undeleted_messages() -> ExternResult<Vec<MessageWithProvenance>> {
-    let path = Path::from(format!("all_agents")).typed(LinkTypes::AgentsPath)?;
+pub fn get_mailbox_shards() -> ExternResult<Vec<EntryHash>> {
+    let mut prefixes: Vec<EntryHash> = vec![];
+
+    for child in all_agents_path()?.children_paths()? {
+        let is_prefix = child
+            .leaf()
+            .and_then(|component| String::try_from(component).ok())
+            .is_some_and(|component| is_mailbox_prefix(&component));
+        // Only the mailboxes under a prefix can be linked to messages, see `agent_path`
+        if is_prefix {
+            prefixes.push(child.path_entry_hash()?);
+        }
+    }
 
-    let children = path.children()?;
+    // The mailboxes under all the prefixes are fetched in parallel
+    let get_links_input = prefixes
+        .into_iter()
+        .map(|prefix| Ok(GetLinksInputBuilder::try_new(prefix, LinkTypes::AgentsPath)?.build()))
+        .collect::<ExternResult<Vec<GetLinksInput>>>()?;
+    let links = HDK.with(|h| h.borrow().get_links(get_links_input))?;
 
-    let get_links_input = children
+    let shards = links
         .into_iter()
-        .map(|link| GetLinksInputBuilder::try_new(link.target, LinkTypes::RecipientToMessages))
+        .flatten()
+        .filter_map(|link| link.target.into_entry_hash())
+        .collect();
+
+    Ok(shards)
+}
+
+#[hdk_extern]
+pub fn export_undeleted_messages() -> ExternResult<Vec<ExportedMessage>> {
+    let shards = get_mailbox_shards(())?;
+
+    let get_links_input = shards
+        .iter()
+        .map(|shard| GetLinksInputBuilder::try_new(shard.clone(), LinkTypes::RecipientToMessages))
         .collect::<ExternResult<Vec<GetLinksInputBuilder>>>()?
         .into_iter()
         .map(|b| b.build())
@@ -18,32 +64,347 @@ pub fn export_undeleted_messages() -> ExternResult<Vec<MessageWithProvenance>> {
 
     let links = HDK.with(|h| h.borrow().get_links(get_links_input))?;
 
-    let undeleted_messages_entry_hashes: BTreeSet<EntryHash> = links
-        .into_iter()
-        .flatten()
-        .filter_map(|l| l.target.into_entry_hash())
-        .collect();
+    let mut pending_shards: BTreeMap<EntryHash, BTreeSet<EntryHash>> = BTreeMap::new();
+    for (shard, shard_links) in shards.into_iter().zip(links) {
+        for message_hash in shard_links
+            .into_iter()
+            .filter_map(|l| l.target.into_entry_hash())
+        {
+            pending_shards
+                .entry(message_hash)
+                .or_default()
+                .insert(shard.clone());
+        }
+    }
 
-    let get_inputs: Vec<GetInput> = undeleted_messages_entry_hashes
-        .into_iter()
-        .map(|e| GetInput::new(e.into(), GetOptions::default()))
+    let (messages, _expired) = get_exported_messages(pending_shards)?;
+    Ok(messages)
+}
+
+/// Exports the messages that are still pending, going through the mailboxes one shard at a time
+/// so that the migration of big epochs can be split in batches and resumed from the last cursor
+#[hdk_extern]
+pub fn export_undeleted_messages_page(
+    input: ExportMessagesInput,
+) -> ExternResult<ExportMessagesPage> {
+    let mut shards = match input.shards {
+        Some(shards) => shards,
+        None => get_mailbox_shards(())?,
+    };
+    shards.sort();
+    shards.dedup();
+
+    // For each message, the shards of the mailboxes in which it's still pending
+    let mut pending_shards: BTreeMap<EntryHash, BTreeSet<EntryHash>> = BTreeMap::new();
+    let mut next_cursor: Option<ExportMessagesCursor> = None;
+
+    if let Some(cursor) = &input.cursor {
+        shards.retain(|shard| shard >= &cursor.shard);
+    }
+
+    'batches: for batch in shards.chunks(EXPORT_PARALLEL_SHARDS) {
+        let get_links_input = batch
+            .iter()
+            .map(|shard| {
+                Ok(
+                    GetLinksInputBuilder::try_new(shard.clone(), LinkTypes::RecipientToMessages)?
+                        .build(),
+                )
+            })
+            .collect::<ExternResult<Vec<GetLinksInput>>>()?;
+        let batch_links = HDK.with(|h| h.borrow().get_links(get_links_input))?;
+
+        for (shard, links) in batch.iter().zip(batch_links) {
+            let mut shard_message_hashes: Vec<EntryHash> = links
+                .into_iter()
+                .filter_map(|l| l.target.into_entry_hash())
+                .collect();
+            shard_message_hashes.sort();
+            shard_message_hashes.dedup();
+
+            for message_hash in shard_message_hashes {
+                if let Some(cursor) = &input.cursor {
+                    if shard.eq(&cursor.shard) && message_hash <= cursor.message_hash {
+                        continue;
+                    }
+                }
+
+                pending_shards
+                    .entry(message_hash.clone())
+                    .or_default()
+                    .insert(shard.clone());
+
+                if pending_shards.len() >= input.max_count {
+                    next_cursor = Some(ExportMessagesCursor {
+                        shard: shard.clone(),
+                        message_hash,
+                    });
+                    break 'batches;
+                }
+            }
+        }
+    }
+
+    let (messages, expired) = get_exported_messages(pending_shards)?;
+
+    Ok(ExportMessagesPage {
+        messages,
+        expired,
+        next_cursor,
+    })
+}
+
+/// Expired messages are left out, since they won't be delivered anymore, and only counted
+fn get_exported_messages(
+    pending_shards: BTreeMap<EntryHash, BTreeSet<EntryHash>>,
+) -> ExternResult<(Vec<ExportedMessage>, usize)> {
+    let get_inputs: Vec<GetInput> = pending_shards
+        .keys()
+        .map(|e| GetInput::new(e.clone().into(), GetOptions::default()))
         .collect();
 
     let records = HDK.with(|h| h.borrow().get(get_inputs))?;
 
-    let messages: Vec<MessageWithProvenance> = records
+    let now = sys_time()?;
+    let mut messages: Vec<ExportedMessage> = vec![];
+    let mut expired = 0;
+
+    for (record, shards) in records.into_iter().zip(pending_shards.into_values()) {
+        let Some(record) = record else {
+            continue;
+        };
+        let Some(entry) = record.entry().as_option() else {
+            continue;
+        };
+        let Ok(message) = MessageWithProvenance::try_from(entry) else {
+            continue;
+        };
+        if message.message.is_expired(now) {
+            expired += 1;
+            continue;
+        }
+
+        let mut pending_recipients: BTreeSet<AgentPubKey> = BTreeSet::new();
+        for recipient in message.message.recipients.keys() {
+            if shards.contains(&agent_path(recipient)?.path_entry_hash()?) {
+                pending_recipients.insert(recipient.clone());
+            }
+        }
+
+        messages.push(ExportedMessage {
+            message,
+            pending_recipients,
+        });
+    }
+
+    Ok((messages, expired))
+}
+
+/// Creates the given messages in this epoch, linking them only from the mailboxes
+/// of their pending recipients, and skipping the ones that were already imported
+#[hdk_extern]
+pub fn import_messages(messages: Vec<ExportedMessage>) -> ExternResult<MigrationCounts> {
+    let mut counts = MigrationCounts::default();
+
+    for message in messages {
+        match import_message(message) {
+            Ok(true) => counts.migrated += 1,
+            Ok(false) => counts.skipped_duplicates += 1,
+            Err(err) => {
+                error!("Failed to import message: {err:?}");
+                counts.failed += 1;
+            }
+        }
+    }
+
+    Ok(counts)
+}
+
+/// Returns false if the message and all the links for its pending recipients already existed
+fn import_message(exported_message: ExportedMessage) -> ExternResult<bool> {
+    let message = exported_message.message;
+    let message_hash = hash_entry(&message)?;
+    let mut imported = false;
+
+    // Not linked from its sender, so that it doesn't count again towards its quota in this epoch
+    if get(message_hash.clone(), GetOptions::default())?.is_none() {
+        create_relaxed(EntryTypes::Message(message.clone()))?;
+        imported = true;
+    }
+
+    for recipient in exported_message.pending_recipients {
+        let Some(contents) = message.message.recipients.get(&recipient) else {
+            continue;
+        };
+
+        // Also skip the links that were already deleted in this epoch,
+        // so that a message acknowledged here is not delivered again
+        if recipient_link_exists(&recipient, &message_hash)? {
+            continue;
+        }
+
+        create_recipient_link(&recipient, &message, &message_hash, contents.clone())?;
+        imported = true;
+    }
+
+    Ok(imported)
+}
+
+fn recipient_link_exists(recipient: &AgentPubKey, message_hash: &EntryHash) -> ExternResult<bool> {
+    let links_details = get_links_details(
+        GetLinksInputBuilder::try_new(
+            agent_path(recipient)?.path_entry_hash()?,
+            LinkTypes::RecipientToMessages,
+        )?
+        .build(),
+    )?;
+
+    let exists = links_details
+        .into_inner()
         .into_iter()
-        .filter_map(|r| r)
-        .filter_map(|record| {
-            let Some(entry) = record.entry().as_option() else {
-                return None;
-            };
-            let Ok(message) = MessageWithProvenance::try_from(entry) else {
-                return None;
-            };
-            Some(message)
-        })
-        .collect();
+        .any(|(create_link, _deletes)| match create_link.action() {
+            Action::CreateLink(create_link) => create_link
+                .target_address
+                .clone()
+                .into_entry_hash()
+                .is_some_and(|target| target.eq(message_hash)),
+            _ => false,
+        });
 
-    Ok(messages)
+    Ok(exists)
+}
+
+/// Exports the delivery receipts of the messages sent to the mailboxes of the given shards,
+/// including the acknowledged ones, which are not migrated with `export_undeleted_messages_page`
+///
+/// The receipts of the expired messages are left out, since their messages can't be migrated
+#[hdk_extern]
+pub fn export_delivery_receipts(
+    shards: Vec<EntryHash>,
+) -> ExternResult<Vec<ExportedDeliveryReceipts>> {
+    let mut message_hashes: BTreeSet<EntryHash> = BTreeSet::new();
+
+    for batch in shards.chunks(EXPORT_PARALLEL_SHARDS) {
+        let get_links_input = batch
+            .iter()
+            .map(|shard| {
+                Ok(
+                    GetLinksInputBuilder::try_new(shard.clone(), LinkTypes::RecipientToMessages)?
+                        .build(),
+                )
+            })
+            .collect::<ExternResult<Vec<GetLinksInput>>>()?;
+        // The links of the acknowledged messages are deleted, but their creates are still there
+        let batch_links_details = HDK.with(|h| h.borrow().get_links_details(get_links_input))?;
+
+        for links_details in batch_links_details {
+            for (create_link, _deletes) in links_details.into_inner() {
+                if let Action::CreateLink(create_link) = create_link.action() {
+                    if let Some(message_hash) = create_link.target_address.clone().into_entry_hash()
+                    {
+                        message_hashes.insert(message_hash);
+                    }
+                }
+            }
+        }
+    }
+
+    let now = sys_time()?;
+    let mut exported: Vec<ExportedDeliveryReceipts> = vec![];
+
+    for message_hash in message_hashes {
+        let links = get_links(
+            GetLinksInputBuilder::try_new(
+                message_hash.clone(),
+                LinkTypes::MessageToDeliveryReceipts,
+            )?
+            .build(),
+        )?;
+        if links.is_empty() {
+            continue;
+        }
+
+        let Some(record) = get(message_hash, GetOptions::default())? else {
+            continue;
+        };
+        let Ok(Some(message)) = record.entry().to_app_option::<MessageWithProvenance>() else {
+            continue;
+        };
+        if message.message.is_expired(now) {
+            continue;
+        }
+
+        let get_inputs: Vec<GetInput> = links
+            .into_iter()
+            .filter_map(|link| link.target.into_entry_hash())
+            .map(|entry_hash| GetInput::new(entry_hash.into(), GetOptions::default()))
+            .collect();
+        let records = HDK.with(|h| h.borrow().get(get_inputs))?;
+        let receipts = records
+            .into_iter()
+            .flatten()
+            .filter_map(|record| {
+                record
+                    .entry()
+                    .to_app_option::<SignedDeliveryReceipt>()
+                    .ok()
+                    .flatten()
+            })
+            .collect();
+
+        exported.push(ExportedDeliveryReceipts { message, receipts });
+    }
+
+    Ok(exported)
+}
+
+/// Creates the given delivery receipts in this epoch, together with their messages so that they
+/// can be validated, but without linking the messages from the mailboxes of their recipients
+#[hdk_extern]
+pub fn import_delivery_receipts(
+    exported: Vec<ExportedDeliveryReceipts>,
+) -> ExternResult<MigrationCounts> {
+    let mut counts = MigrationCounts::default();
+
+    for exported_receipts in exported {
+        // Each message can have a receipt for each of its recipients, which must all
+        // be in the proof window of the provider that imports them
+        create_provider_proof()?;
+
+        match import_delivery_receipts_for_message(&exported_receipts) {
+            Ok((migrated, skipped)) => {
+                counts.migrated += migrated;
+                counts.skipped_duplicates += skipped;
+            }
+            Err(err) => {
+                error!("Failed to import delivery receipts: {err:?}");
+                counts.failed += exported_receipts.receipts.len();
+            }
+        }
+    }
+
+    Ok(counts)
+}
+
+/// Returns the number of receipts that were imported and of those that already existed
+fn import_delivery_receipts_for_message(
+    exported_receipts: &ExportedDeliveryReceipts,
+) -> ExternResult<(usize, usize)> {
+    let message_hash = hash_entry(&exported_receipts.message)?;
+    if get(message_hash.clone(), GetOptions::default())?.is_none() {
+        create_relaxed(EntryTypes::Message(exported_receipts.message.clone()))?;
+    }
+
+    let mut migrated = 0;
+    let mut skipped = 0;
+    for signed_receipt in &exported_receipts.receipts {
+        if get(hash_entry(signed_receipt)?, GetOptions::default())?.is_some() {
+            skipped += 1;
+            continue;
+        }
+        create_delivery_receipt(message_hash.clone(), signed_receipt.clone())?;
+        migrated += 1;
+    }
+
+    Ok((migrated, skipped))
 }
@@ -0,0 +1,43 @@
+use hdk::prelude::*;
+use safehold_integrity::*;
+use safehold_types::ProviderDelegation;
+
+use crate::utils::create_relaxed;
+
+/// Number of acknowledged messages after each `ProviderProof`: every message takes a delete
+/// of its link and a delivery receipt with its link, which must all be in the proof window
+pub const ACK_BATCH_SIZE: usize = PROVIDER_PROOF_WINDOW / 3;
+
+/// Commits a `ProviderProof` if this agent joined with a delegation, so that the deletes and
+/// delivery receipts that follow it are validated without walking back its whole chain
+///
+/// Progenitors join without a delegation, and their actions don't need one
+pub fn create_provider_proof() -> ExternResult<()> {
+    let Some((agent_validation_pkg, _delegation)) = query_joining_delegation()? else {
+        return Ok(());
+    };
+
+    create_relaxed(EntryTypes::ProviderProof(ProviderProof {
+        agent_validation_pkg,
+    }))
+}
+
+/// The delegation with which this agent joined, with the hash of its `AgentValidationPkg`,
+/// or `None` if it's a progenitor
+pub fn query_joining_delegation() -> ExternResult<Option<(ActionHash, ProviderDelegation)>> {
+    let records = query(ChainQueryFilter::new().action_type(ActionType::AgentValidationPkg))?;
+    let Some(record) = records.into_iter().next() else {
+        return Ok(None);
+    };
+    let Action::AgentValidationPkg(AgentValidationPkg {
+        membrane_proof: Some(membrane_proof),
+        ..
+    }) = record.action()
+    else {
+        return Ok(None);
+    };
+
+    let delegation =
+        ProviderDelegation::try_from((**membrane_proof).clone()).map_err(|err| wasm_error!(err))?;
+    Ok(Some((record.action_address().clone(), delegation)))
+}
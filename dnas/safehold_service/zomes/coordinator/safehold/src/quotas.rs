@@ -0,0 +1,73 @@
+use hdk::prelude::*;
+use safehold_integrity::*;
+use safehold_types::{
+    epoch_start, time_epoch, QuotasUsage, QuotasUsageInput, RecordSealedMessagesInput,
+};
+
+use crate::message::get_recipient_links;
+use crate::provider_proof::{create_provider_proof, ACK_BATCH_SIZE};
+use crate::utils::{create_link_relaxed, ensure_relaxed};
+
+/// The size of each mailbox is summed from the tags of its links, without fetching its messages
+#[hdk_extern]
+pub fn get_quotas_usage(input: QuotasUsageInput) -> ExternResult<QuotasUsage> {
+    let sender_path_hash = sender_path(&input.sender)?.path_entry_hash()?;
+    let sender_links = get_links(
+        GetLinksInputBuilder::try_new(sender_path_hash.clone(), LinkTypes::SenderToMessages)?
+            .build(),
+    )?;
+    let sealed_links = get_links(
+        GetLinksInputBuilder::try_new(sender_path_hash, LinkTypes::SenderToSealedMessages)?.build(),
+    )?;
+
+    let now = sys_time()?;
+    let mut mailbox_bytes: BTreeMap<AgentPubKey, usize> = BTreeMap::new();
+
+    for recipient in input.recipients {
+        let bytes = get_recipient_links(recipient.clone())?
+            .iter()
+            .filter_map(|link| RecipientLinkTag::try_from(&link.tag).ok())
+            .filter(|tag| tag.expires_at > now)
+            .map(|tag| tag.size())
+            .sum();
+        mailbox_bytes.insert(recipient, bytes);
+    }
+
+    let properties = safehold_properties()?;
+    let epoch = time_epoch(now, properties.epoch_length());
+
+    Ok(QuotasUsage {
+        quotas: properties.quotas,
+        sender_messages_in_epoch: sender_links.len() + sealed_links.len(),
+        epoch_ends_at: epoch_start(epoch + 1, properties.epoch_length()),
+        mailbox_bytes,
+    })
+}
+
+/// Counts the sealed messages that the sender stored through this provider towards its quota,
+/// since their provenance is thrown away and they can't be linked from its path like the rest
+#[hdk_extern]
+pub fn record_sealed_messages(input: RecordSealedMessagesInput) -> ExternResult<()> {
+    let path = sender_path(&input.sender)?;
+
+    let mut remaining = input.count;
+    while remaining > 0 {
+        let batch = remaining.min(ACK_BATCH_SIZE);
+        remaining -= batch;
+
+        create_provider_proof()?;
+        ensure_relaxed(&path)?;
+        for _ in 0..batch {
+            // Random targets, so that the links can't be matched with the messages they count
+            let target = ExternalHash::from_raw_32(random_bytes(32)?.to_vec());
+            create_link_relaxed(
+                path.path_entry_hash()?,
+                target,
+                LinkTypes::SenderToSealedMessages,
+                (),
+            )?;
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,116 @@
+use hdi::prelude::*;
+use safehold_types::ProviderDelegation;
+
+use crate::{safehold_properties, ProviderProof, UnitEntryTypes, PROVIDER_PROOF_WINDOW};
+
+pub fn is_progenitor(agent: &AgentPubKey) -> ExternResult<bool> {
+    let progenitors = safehold_properties()?.progenitors;
+    Ok(progenitors.contains(&AgentPubKeyB64::from(agent.clone())))
+}
+
+/// Checks that the delegation was signed by one of the progenitors of this DNA for the given agent,
+/// in the proxy DNA of the providers of this DNA, and that it hadn't expired at the given time
+pub fn validate_provider_delegation(
+    agent: &AgentPubKey,
+    delegation: &ProviderDelegation,
+    timestamp: Timestamp,
+) -> ExternResult<ValidateCallbackResult> {
+    if delegation.delegate.ne(agent) {
+        return Ok(ValidateCallbackResult::Invalid(String::from(
+            "The delegation is not for this agent",
+        )));
+    }
+    let Some(proxy_dna_hash) = safehold_properties()?.proxy_dna_hash else {
+        return Ok(ValidateCallbackResult::Invalid(String::from(
+            "This DNA doesn't accept provider delegations",
+        )));
+    };
+    if DnaHash::from(proxy_dna_hash).ne(&delegation.proxy_dna_hash) {
+        return Ok(ValidateCallbackResult::Invalid(String::from(
+            "The delegation is for the providers of another network",
+        )));
+    }
+    if delegation.is_expired(timestamp) {
+        return Ok(ValidateCallbackResult::Invalid(String::from(
+            "The delegation had expired",
+        )));
+    }
+    if !is_progenitor(&delegation.delegator)? {
+        return Ok(ValidateCallbackResult::Invalid(String::from(
+            "The delegator is not a progenitor",
+        )));
+    }
+    let valid = verify_signature_raw(
+        delegation.delegator.clone(),
+        delegation.signature.clone(),
+        ProviderDelegation::signed_data(
+            &delegation.delegate,
+            &delegation.proxy_dna_hash,
+            delegation.expires_at,
+        ),
+    )?;
+    if !valid {
+        return Ok(ValidateCallbackResult::Invalid(String::from(
+            "Invalid delegation signature",
+        )));
+    }
+    Ok(ValidateCallbackResult::Valid)
+}
+
+/// Service providers join the safehold DHT with their delegation as their membrane proof,
+/// which is found through the last `ProviderProof` before the given chain top
+pub fn get_provider_delegation(
+    agent: &AgentPubKey,
+    chain_top: &ActionHash,
+) -> ExternResult<Option<ProviderDelegation>> {
+    let activity = must_get_agent_activity(
+        agent.clone(),
+        ChainFilter::new(chain_top.clone()).take(PROVIDER_PROOF_WINDOW as u32),
+    )?;
+    let provider_proof_type = ScopedEntryDefIndex::try_from(UnitEntryTypes::ProviderProof)?;
+
+    let provider_proof_hash =
+        activity
+            .into_iter()
+            .find_map(|activity| match activity.action.hashed.content {
+                Action::Create(Create {
+                    entry_type: EntryType::App(app_entry_def),
+                    entry_hash,
+                    ..
+                }) if app_entry_def.zome_index == provider_proof_type.zome_index
+                    && app_entry_def.entry_index == provider_proof_type.zome_type =>
+                {
+                    Some(entry_hash)
+                }
+                _ => None,
+            });
+
+    let Some(provider_proof_hash) = provider_proof_hash else {
+        return Ok(None);
+    };
+    let provider_proof = ProviderProof::try_from(must_get_entry(provider_proof_hash)?.content)?;
+    get_joining_delegation(agent, &provider_proof.agent_validation_pkg)
+}
+
+/// Delegation in the membrane proof of the given `AgentValidationPkg` action of the agent,
+/// fetching only that action of its chain
+pub fn get_joining_delegation(
+    agent: &AgentPubKey,
+    agent_validation_pkg: &ActionHash,
+) -> ExternResult<Option<ProviderDelegation>> {
+    let action = must_get_action(agent_validation_pkg.clone())?;
+    if action.action().author().ne(agent) {
+        return Ok(None);
+    }
+    let Action::AgentValidationPkg(AgentValidationPkg {
+        membrane_proof: Some(membrane_proof),
+        ..
+    }) = action.action()
+    else {
+        return Ok(None);
+    };
+
+    let delegation =
+        ProviderDelegation::try_from((**membrane_proof).clone()).map_err(|err| wasm_error!(err))?;
+    Ok(Some(delegation))
+}
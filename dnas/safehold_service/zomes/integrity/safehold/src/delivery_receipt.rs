@@ -0,0 +1,123 @@
+use hdi::prelude::*;
+pub use safehold_types::SignedDeliveryReceipt;
+
+use crate::delegation::{get_provider_delegation, is_progenitor, validate_provider_delegation};
+use crate::MessageWithProvenance;
+
+pub fn validate_create_delivery_receipt(
+    action: EntryCreationAction,
+    signed_receipt: SignedDeliveryReceipt,
+) -> ExternResult<ValidateCallbackResult> {
+    let (author, prev_action) = match &action {
+        EntryCreationAction::Create(create) => (create.author.clone(), create.prev_action.clone()),
+        EntryCreationAction::Update(update) => (update.author.clone(), update.prev_action.clone()),
+    };
+    // Only the providers can create the delivery receipts, either when they acknowledge the messages
+    // or when they migrate them from a previous epoch, signed by another provider
+    if !is_progenitor(&author)? {
+        let Some(delegation) = get_provider_delegation(&author, &prev_action)? else {
+            return Ok(ValidateCallbackResult::Invalid(String::from(
+                "Only progenitors or agents delegated by them can create delivery receipts",
+            )));
+        };
+        let result = validate_provider_delegation(&author, &delegation, *action.timestamp())?;
+        let ValidateCallbackResult::Valid = result else {
+            return Ok(result);
+        };
+    }
+
+    // The signer must have been a provider when it delivered the message
+    if !is_progenitor(&signed_receipt.provider)? {
+        let Some(delegation) = &signed_receipt.delegation else {
+            return Ok(ValidateCallbackResult::Invalid(String::from(
+                "Delivery receipts must be signed by progenitors or agents delegated by them",
+            )));
+        };
+        let result = validate_provider_delegation(
+            &signed_receipt.provider,
+            delegation,
+            signed_receipt.receipt.delivered_at,
+        )?;
+        let ValidateCallbackResult::Valid = result else {
+            return Ok(result);
+        };
+    }
+
+    let valid = verify_signature(
+        signed_receipt.provider.clone(),
+        signed_receipt.signature.clone(),
+        &signed_receipt.receipt,
+    )?;
+    if !valid {
+        return Ok(ValidateCallbackResult::Invalid(String::from(
+            "Invalid delivery receipt signature",
+        )));
+    }
+
+    let entry = must_get_entry(signed_receipt.receipt.message_hash.clone())?;
+    let message = MessageWithProvenance::try_from(entry.content)?;
+
+    if !message.message.delivery_receipt {
+        return Ok(ValidateCallbackResult::Invalid(String::from(
+            "The sender of the message did not request delivery receipts",
+        )));
+    }
+    if !message
+        .message
+        .recipients
+        .contains_key(&signed_receipt.receipt.recipient)
+    {
+        return Ok(ValidateCallbackResult::Invalid(String::from(
+            "The receipt is for an agent that is not a recipient of the message",
+        )));
+    }
+
+    Ok(ValidateCallbackResult::Valid)
+}
+
+pub fn validate_update_delivery_receipt(
+    _action: Update,
+    _signed_receipt: SignedDeliveryReceipt,
+    _original_action: EntryCreationAction,
+    _original_signed_receipt: SignedDeliveryReceipt,
+) -> ExternResult<ValidateCallbackResult> {
+    Ok(ValidateCallbackResult::Invalid(
+        "Delivery receipts cannot be updated".to_string(),
+    ))
+}
+
+pub fn validate_delete_delivery_receipt(
+    _action: Delete,
+    _original_action: EntryCreationAction,
+    _original_signed_receipt: SignedDeliveryReceipt,
+) -> ExternResult<ValidateCallbackResult> {
+    Ok(ValidateCallbackResult::Invalid(
+        "Delivery receipts cannot be deleted".to_string(),
+    ))
+}
+
+pub fn validate_create_link_message_to_delivery_receipts(
+    _action: CreateLink,
+    base_address: AnyLinkableHash,
+    target_address: AnyLinkableHash,
+    _tag: LinkTag,
+) -> ExternResult<ValidateCallbackResult> {
+    let entry_hash = target_address
+        .into_entry_hash()
+        .ok_or(wasm_error!(WasmErrorInner::Guest(
+            "No entry hash associated with link".to_string()
+        )))?;
+    let entry = must_get_entry(entry_hash)?;
+    let signed_receipt = SignedDeliveryReceipt::try_from(entry.content)?;
+
+    if base_address
+        .into_entry_hash()
+        .ne(&Some(signed_receipt.receipt.message_hash))
+    {
+        return Ok(ValidateCallbackResult::Invalid(String::from(
+            "The base of a MessageToDeliveryReceipts link must be the message of the receipt",
+        )));
+    }
+
+    Ok(ValidateCallbackResult::Valid)
+}
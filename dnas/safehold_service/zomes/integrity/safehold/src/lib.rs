@@ -1,6 +1,16 @@
 pub mod message;
 use hdi::prelude::*;
 pub use message::*;
+use safehold_types::{ProviderDelegation, SafeholdProperties};
+
+pub mod delegation;
+use delegation::validate_provider_delegation;
+
+pub mod delivery_receipt;
+pub use delivery_receipt::*;
+
+pub mod provider_proof;
+pub use provider_proof::*;
 
 #[derive(Serialize, Deserialize)]
 #[serde(tag = "type")]
@@ -8,6 +18,8 @@ pub use message::*;
 #[unit_enum(UnitEntryTypes)]
 pub enum EntryTypes {
     Message(MessageWithProvenance),
+    DeliveryReceipt(SignedDeliveryReceipt),
+    ProviderProof(ProviderProof),
 }
 
 #[derive(Serialize, Deserialize)]
@@ -15,6 +27,17 @@ pub enum EntryTypes {
 pub enum LinkTypes {
     RecipientToMessages,
     AgentsPath,
+    SenderToMessages,
+    SendersPath,
+    MessageToDeliveryReceipts,
+    /// Counts the sealed messages that an agent stored through a provider towards its quota,
+    /// without pointing to the messages themselves
+    SenderToSealedMessages,
+}
+
+pub fn safehold_properties() -> ExternResult<SafeholdProperties> {
+    let properties = dna_info()?.modifiers.properties;
+    SafeholdProperties::try_from(properties).map_err(|err| wasm_error!(err))
 }
 
 // Validation you perform during the genesis process. Nobody else on the network performs it, only you.
@@ -27,10 +50,20 @@ pub fn genesis_self_check(_data: GenesisSelfCheckData) -> ExternResult<ValidateC
 // Validation the network performs when you try to join, you can't perform this validation yourself as you are not a member yet.
 // There *is* access to network calls in this function
 pub fn validate_agent_joining(
-    _agent_pub_key: AgentPubKey,
-    _membrane_proof: &Option<MembraneProof>,
+    agent_pub_key: AgentPubKey,
+    membrane_proof: &Option<MembraneProof>,
+    timestamp: Timestamp,
 ) -> ExternResult<ValidateCallbackResult> {
-    Ok(ValidateCallbackResult::Valid)
+    // Service providers join with a delegation from a progenitor, everyone else joins without a membrane proof
+    let Some(membrane_proof) = membrane_proof else {
+        return Ok(ValidateCallbackResult::Valid);
+    };
+    let Ok(delegation) = ProviderDelegation::try_from((**membrane_proof).clone()) else {
+        return Ok(ValidateCallbackResult::Invalid(String::from(
+            "Membrane proof must be a delegation",
+        )));
+    };
+    validate_provider_delegation(&agent_pub_key, &delegation, timestamp)
 }
 
 // This is the unified validation callback for all entries and link types in this integrity zome
@@ -59,6 +92,14 @@ pub fn validate(op: Op) -> ExternResult<ValidateCallbackResult> {
                 EntryTypes::Message(message) => {
                     validate_create_message(EntryCreationAction::Create(action), message)
                 }
+                EntryTypes::DeliveryReceipt(signed_receipt) => validate_create_delivery_receipt(
+                    EntryCreationAction::Create(action),
+                    signed_receipt,
+                ),
+                EntryTypes::ProviderProof(provider_proof) => validate_create_provider_proof(
+                    EntryCreationAction::Create(action),
+                    provider_proof,
+                ),
             },
             OpEntry::UpdateEntry {
                 app_entry, action, ..
@@ -66,6 +107,14 @@ pub fn validate(op: Op) -> ExternResult<ValidateCallbackResult> {
                 EntryTypes::Message(message) => {
                     validate_create_message(EntryCreationAction::Update(action), message)
                 }
+                EntryTypes::DeliveryReceipt(signed_receipt) => validate_create_delivery_receipt(
+                    EntryCreationAction::Update(action),
+                    signed_receipt,
+                ),
+                EntryTypes::ProviderProof(provider_proof) => validate_create_provider_proof(
+                    EntryCreationAction::Update(action),
+                    provider_proof,
+                ),
             },
             _ => Ok(ValidateCallbackResult::Valid),
         },
@@ -102,6 +151,44 @@ pub fn validate(op: Op) -> ExternResult<ValidateCallbackResult> {
                             original_message,
                         )
                     }
+                    EntryTypes::DeliveryReceipt(signed_receipt) => {
+                        let original_app_entry =
+                            must_get_valid_record(action.clone().original_action_address)?;
+                        let original_signed_receipt =
+                            match SignedDeliveryReceipt::try_from(original_app_entry) {
+                                Ok(entry) => entry,
+                                Err(e) => {
+                                    return Ok(ValidateCallbackResult::Invalid(format!(
+                                        "Expected to get DeliveryReceipt from Record: {e:?}"
+                                    )));
+                                }
+                            };
+                        validate_update_delivery_receipt(
+                            action,
+                            signed_receipt,
+                            original_create_action,
+                            original_signed_receipt,
+                        )
+                    }
+                    EntryTypes::ProviderProof(provider_proof) => {
+                        let original_app_entry =
+                            must_get_valid_record(action.clone().original_action_address)?;
+                        let original_provider_proof =
+                            match ProviderProof::try_from(original_app_entry) {
+                                Ok(entry) => entry,
+                                Err(e) => {
+                                    return Ok(ValidateCallbackResult::Invalid(format!(
+                                        "Expected to get ProviderProof from Record: {e:?}"
+                                    )));
+                                }
+                            };
+                        validate_update_provider_proof(
+                            action,
+                            provider_proof,
+                            original_create_action,
+                            original_provider_proof,
+                        )
+                    }
                 }
             }
             _ => Ok(ValidateCallbackResult::Valid),
@@ -151,6 +238,20 @@ pub fn validate(op: Op) -> ExternResult<ValidateCallbackResult> {
                     original_action,
                     original_message,
                 ),
+                EntryTypes::DeliveryReceipt(original_signed_receipt) => {
+                    validate_delete_delivery_receipt(
+                        delete_entry.clone().action,
+                        original_action,
+                        original_signed_receipt,
+                    )
+                }
+                EntryTypes::ProviderProof(original_provider_proof) => {
+                    validate_delete_provider_proof(
+                        delete_entry.clone().action,
+                        original_action,
+                        original_provider_proof,
+                    )
+                }
             }
         }
         FlatOp::RegisterCreateLink {
@@ -167,6 +268,27 @@ pub fn validate(op: Op) -> ExternResult<ValidateCallbackResult> {
                 tag,
             ),
             LinkTypes::AgentsPath => Ok(ValidateCallbackResult::Valid),
+            LinkTypes::SenderToMessages => validate_create_link_sender_to_messages(
+                action,
+                base_address,
+                target_address,
+                tag,
+            ),
+            LinkTypes::SendersPath => Ok(ValidateCallbackResult::Valid),
+            LinkTypes::MessageToDeliveryReceipts => {
+                validate_create_link_message_to_delivery_receipts(
+                    action,
+                    base_address,
+                    target_address,
+                    tag,
+                )
+            }
+            LinkTypes::SenderToSealedMessages => validate_create_link_sender_to_sealed_messages(
+                action,
+                base_address,
+                target_address,
+                tag,
+            ),
         },
         FlatOp::RegisterDeleteLink {
             link_type,
@@ -186,6 +308,18 @@ pub fn validate(op: Op) -> ExternResult<ValidateCallbackResult> {
             LinkTypes::AgentsPath => Ok(ValidateCallbackResult::Invalid(String::from(
                 "AgentsPath links cannot be deleted",
             ))),
+            LinkTypes::SenderToMessages => Ok(ValidateCallbackResult::Invalid(String::from(
+                "SenderToMessages links cannot be deleted",
+            ))),
+            LinkTypes::SendersPath => Ok(ValidateCallbackResult::Invalid(String::from(
+                "SendersPath links cannot be deleted",
+            ))),
+            LinkTypes::MessageToDeliveryReceipts => Ok(ValidateCallbackResult::Invalid(
+                String::from("MessageToDeliveryReceipts links cannot be deleted"),
+            )),
+            LinkTypes::SenderToSealedMessages => Ok(ValidateCallbackResult::Invalid(String::from(
+                "SenderToSealedMessages links cannot be deleted",
+            ))),
         },
         FlatOp::StoreRecord(store_record) => {
             match store_record {
@@ -196,6 +330,16 @@ pub fn validate(op: Op) -> ExternResult<ValidateCallbackResult> {
                     EntryTypes::Message(message) => {
                         validate_create_message(EntryCreationAction::Create(action), message)
                     }
+                    EntryTypes::DeliveryReceipt(signed_receipt) => {
+                        validate_create_delivery_receipt(
+                            EntryCreationAction::Create(action),
+                            signed_receipt,
+                        )
+                    }
+                    EntryTypes::ProviderProof(provider_proof) => validate_create_provider_proof(
+                        EntryCreationAction::Create(action),
+                        provider_proof,
+                    ),
                 },
                 // Complementary validation to the `RegisterUpdate` Op, in which the record itself is validated
                 // If you want to optimize performance, you can remove the validation for an entry type here and keep it in `StoreEntry` and in `RegisterUpdate`
@@ -251,6 +395,70 @@ pub fn validate(op: Op) -> ExternResult<ValidateCallbackResult> {
                                 Ok(result)
                             }
                         }
+                        EntryTypes::DeliveryReceipt(signed_receipt) => {
+                            let result = validate_create_delivery_receipt(
+                                EntryCreationAction::Update(action.clone()),
+                                signed_receipt.clone(),
+                            )?;
+                            if let ValidateCallbackResult::Valid = result {
+                                let original_signed_receipt: Option<SignedDeliveryReceipt> =
+                                    original_record
+                                        .entry()
+                                        .to_app_option()
+                                        .map_err(|e| wasm_error!(e))?;
+                                let original_signed_receipt = match original_signed_receipt {
+                                    Some(signed_receipt) => signed_receipt,
+                                    None => {
+                                        return Ok(
+                                            ValidateCallbackResult::Invalid(
+                                                "The updated entry type must be the same as the original entry type"
+                                                    .to_string(),
+                                            ),
+                                        );
+                                    }
+                                };
+                                validate_update_delivery_receipt(
+                                    action,
+                                    signed_receipt,
+                                    original_action,
+                                    original_signed_receipt,
+                                )
+                            } else {
+                                Ok(result)
+                            }
+                        }
+                        EntryTypes::ProviderProof(provider_proof) => {
+                            let result = validate_create_provider_proof(
+                                EntryCreationAction::Update(action.clone()),
+                                provider_proof.clone(),
+                            )?;
+                            if let ValidateCallbackResult::Valid = result {
+                                let original_provider_proof: Option<ProviderProof> =
+                                    original_record
+                                        .entry()
+                                        .to_app_option()
+                                        .map_err(|e| wasm_error!(e))?;
+                                let original_provider_proof = match original_provider_proof {
+                                    Some(provider_proof) => provider_proof,
+                                    None => {
+                                        return Ok(
+                                            ValidateCallbackResult::Invalid(
+                                                "The updated entry type must be the same as the original entry type"
+                                                    .to_string(),
+                                            ),
+                                        );
+                                    }
+                                };
+                                validate_update_provider_proof(
+                                    action,
+                                    provider_proof,
+                                    original_action,
+                                    original_provider_proof,
+                                )
+                            } else {
+                                Ok(result)
+                            }
+                        }
                     }
                 }
                 // Complementary validation to the `RegisterDelete` Op, in which the record itself is validated
@@ -306,6 +514,20 @@ pub fn validate(op: Op) -> ExternResult<ValidateCallbackResult> {
                         EntryTypes::Message(original_message) => {
                             validate_delete_message(action, original_action, original_message)
                         }
+                        EntryTypes::DeliveryReceipt(original_signed_receipt) => {
+                            validate_delete_delivery_receipt(
+                                action,
+                                original_action,
+                                original_signed_receipt,
+                            )
+                        }
+                        EntryTypes::ProviderProof(original_provider_proof) => {
+                            validate_delete_provider_proof(
+                                action,
+                                original_action,
+                                original_provider_proof,
+                            )
+                        }
                     }
                 }
                 // Complementary validation to the `RegisterCreateLink` Op, in which the record itself is validated
@@ -325,6 +547,29 @@ pub fn validate(op: Op) -> ExternResult<ValidateCallbackResult> {
                         tag,
                     ),
                     LinkTypes::AgentsPath => Ok(ValidateCallbackResult::Valid),
+                    LinkTypes::SenderToMessages => validate_create_link_sender_to_messages(
+                        action,
+                        base_address,
+                        target_address,
+                        tag,
+                    ),
+                    LinkTypes::SendersPath => Ok(ValidateCallbackResult::Valid),
+                    LinkTypes::MessageToDeliveryReceipts => {
+                        validate_create_link_message_to_delivery_receipts(
+                            action,
+                            base_address,
+                            target_address,
+                            tag,
+                        )
+                    }
+                    LinkTypes::SenderToSealedMessages => {
+                        validate_create_link_sender_to_sealed_messages(
+                            action,
+                            base_address,
+                            target_address,
+                            tag,
+                        )
+                    }
                 },
                 // Complementary validation to the `RegisterDeleteLink` Op, in which the record itself is validated
                 // If you want to optimize performance, you can remove the validation for an entry type here and keep it in `RegisterDeleteLink`
@@ -366,6 +611,22 @@ pub fn validate(op: Op) -> ExternResult<ValidateCallbackResult> {
                         LinkTypes::AgentsPath => Ok(ValidateCallbackResult::Invalid(String::from(
                             "AgentsPath links cannot be deleted",
                         ))),
+                        LinkTypes::SenderToMessages => {
+                            Ok(ValidateCallbackResult::Invalid(String::from(
+                                "SenderToMessages links cannot be deleted",
+                            )))
+                        }
+                        LinkTypes::SendersPath => Ok(ValidateCallbackResult::Invalid(String::from(
+                            "SendersPath links cannot be deleted",
+                        ))),
+                        LinkTypes::MessageToDeliveryReceipts => {
+                            Ok(ValidateCallbackResult::Invalid(String::from(
+                                "MessageToDeliveryReceipts links cannot be deleted",
+                            )))
+                        }
+                        LinkTypes::SenderToSealedMessages => Ok(ValidateCallbackResult::Invalid(
+                            String::from("SenderToSealedMessages links cannot be deleted"),
+                        )),
                     }
                 }
                 OpRecord::CreatePrivateEntry { .. } => Ok(ValidateCallbackResult::Valid),
@@ -386,8 +647,8 @@ pub fn validate(op: Op) -> ExternResult<ValidateCallbackResult> {
                 let previous_action = must_get_action(action.prev_action)?;
                 match previous_action.action() {
                         Action::AgentValidationPkg(
-                            AgentValidationPkg { membrane_proof, .. },
-                        ) => validate_agent_joining(agent, membrane_proof),
+                            AgentValidationPkg { membrane_proof, timestamp, .. },
+                        ) => validate_agent_joining(agent, membrane_proof, *timestamp),
                         _ => {
                             Ok(
                                 ValidateCallbackResult::Invalid(
@@ -1,11 +1,43 @@
 use hdi::prelude::*;
 pub use safehold_types::MessageWithProvenance;
+use safehold_types::{AgentSpecificContents, Message, MAX_MESSAGE_SIZE};
+
+use crate::delegation::{get_provider_delegation, is_progenitor, validate_provider_delegation};
+use crate::{safehold_properties, LinkTypes};
 
 pub fn validate_create_message(
-    _action: EntryCreationAction,
+    action: EntryCreationAction,
     message: MessageWithProvenance,
 ) -> ExternResult<ValidateCallbackResult> {
+    let timestamp = match &action {
+        EntryCreationAction::Create(create) => create.timestamp,
+        EntryCreationAction::Update(update) => update.timestamp,
+    };
+    if message.message.is_expired(timestamp) {
+        return Ok(ValidateCallbackResult::Invalid(String::from(
+            "Message is already expired",
+        )));
+    }
+    let max_ttl_micros = safehold_properties()?.max_message_ttl_secs as i64 * 1_000_000;
+    if message.message.expires_at.as_micros() - timestamp.as_micros() > max_ttl_micros {
+        return Ok(ValidateCallbackResult::Invalid(String::from(
+            "Message time to live exceeds the maximum allowed by the DNA properties",
+        )));
+    }
+
+    // Their receipts are fetched by the provenance, which for sealed messages is thrown away after signing
+    if message.message.sealed_sender && message.message.delivery_receipt {
+        return Ok(ValidateCallbackResult::Invalid(String::from(
+            "Sealed sender messages can't request delivery receipts",
+        )));
+    }
+
     let bytes = SerializedBytes::try_from(message.message).map_err(|err| wasm_error!(err))?;
+    if bytes.bytes().len() > MAX_MESSAGE_SIZE {
+        return Ok(ValidateCallbackResult::Invalid(format!(
+            "Message exceeds the maximum size of {MAX_MESSAGE_SIZE} bytes"
+        )));
+    }
 
     let hash = hash_blake2b(bytes.bytes().to_vec(), 32)?;
     let Ok(true) = verify_signature(message.provenance.clone(), message.signature.clone(), &hash)
@@ -39,9 +71,107 @@ pub fn validate_delete_message(
     ))
 }
 
-pub fn validate_create_link_recipient_to_messages(
+/// Identifier of the mailbox of the given agent, derived from its key and the network seed
+/// so that it changes every epoch and the paths don't reveal which agents have a mailbox
+pub fn blinded_mailbox_id(agent: &AgentPubKey) -> ExternResult<ExternalHash> {
+    let network_seed = dna_info()?.modifiers.network_seed;
+
+    let mut bytes = agent.get_raw_39().to_vec();
+    bytes.extend(network_seed.into_bytes());
+
+    let hash = hash_blake2b(bytes, 32)?;
+    Ok(ExternalHash::from_raw_32(hash))
+}
+
+/// Intermediate component of the path of a mailbox, the first byte of its blinded id in hex,
+/// which spreads the mailboxes among 256 paths instead of hanging all of them from the root
+pub fn mailbox_prefix(mailbox_id: &ExternalHash) -> String {
+    format!("{:02x}", mailbox_id.get_raw_32()[0])
+}
+
+pub fn is_mailbox_prefix(component: &str) -> bool {
+    component.len() == 2 && component.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+/// Path of the mailbox of the given agent, which only whoever knows the agent can compute,
+/// e.g. the provider that serves its `get_messages` calls
+pub fn agent_path(agent: &AgentPubKey) -> ExternResult<TypedPath> {
+    let mailbox_id = blinded_mailbox_id(agent)?;
+    Path::from(format!(
+        "all_agents.{}.{}",
+        mailbox_prefix(&mailbox_id),
+        mailbox_id
+    ))
+    .typed(LinkTypes::AgentsPath)
+}
+
+/// Tag of the links from the mailboxes to the messages, from which the size of a mailbox
+/// is computed without fetching all of its messages
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, SerializedBytes)]
+pub struct RecipientLinkTag {
+    pub contents: AgentSpecificContents,
+    /// Size of the contents of the message, which are the same for all its recipients
+    pub message_size: usize,
+    pub expires_at: Timestamp,
+}
+
+impl RecipientLinkTag {
+    pub fn new(message: &Message, contents: AgentSpecificContents) -> Self {
+        Self {
+            contents,
+            message_size: message.contents.len(),
+            expires_at: message.expires_at,
+        }
+    }
+
+    /// Bytes that the message takes in the mailbox, the same as `Message::size_for_recipient`
+    pub fn size(&self) -> usize {
+        self.message_size + self.contents.len()
+    }
+}
+
+impl TryFrom<RecipientLinkTag> for LinkTag {
+    type Error = WasmError;
+    fn try_from(tag: RecipientLinkTag) -> ExternResult<Self> {
+        let bytes = SerializedBytes::try_from(tag).map_err(|err| wasm_error!(err))?;
+        Ok(LinkTag::new(bytes.bytes().to_vec()))
+    }
+}
+
+impl TryFrom<&LinkTag> for RecipientLinkTag {
+    type Error = WasmError;
+    fn try_from(tag: &LinkTag) -> ExternResult<Self> {
+        RecipientLinkTag::try_from(SerializedBytes::from(UnsafeBytes::from(tag.0.clone())))
+            .map_err(|err| wasm_error!(err))
+    }
+}
+
+/// Identifier of the messages sent by the given agent in this epoch, derived like the mailbox id
+/// but with a different prefix, so that neither of them reveals the other
+pub fn blinded_sender_id(sender: &AgentPubKey) -> ExternResult<ExternalHash> {
+    let network_seed = dna_info()?.modifiers.network_seed;
+
+    let mut bytes = b"sender".to_vec();
+    bytes.extend(sender.get_raw_39());
+    bytes.extend(network_seed.into_bytes());
+
+    let hash = hash_blake2b(bytes, 32)?;
+    Ok(ExternalHash::from_raw_32(hash))
+}
+
+/// Path from which the messages sent by the given agent are linked to count them towards its quota,
+/// which only whoever knows the agent can compute
+///
+/// This doesn't hide who sends each message from whoever reads the messages themselves:
+/// the provenance of the messages that aren't sealed and the keys of all the recipients
+/// are in plaintext in the `Message` entries
+pub fn sender_path(sender: &AgentPubKey) -> ExternResult<TypedPath> {
+    Path::from(format!("all_senders.{}", blinded_sender_id(sender)?)).typed(LinkTypes::SendersPath)
+}
+
+pub fn validate_create_link_sender_to_messages(
     _action: CreateLink,
-    _base_address: AnyLinkableHash,
+    base_address: AnyLinkableHash,
     target_address: AnyLinkableHash,
     _tag: LinkTag,
 ) -> ExternResult<ValidateCallbackResult> {
@@ -51,17 +181,224 @@ pub fn validate_create_link_recipient_to_messages(
             "No action hash associated with link".to_string()
         )))?;
     let entry = must_get_entry(entry_hash)?;
-    let _message = crate::MessageWithProvenance::try_from(entry.content)?;
-    // TODO: add the appropriate validation rules
+    let message = crate::MessageWithProvenance::try_from(entry.content)?;
+
+    let sender_path_hash = sender_path(&message.provenance)?.path_entry_hash()?;
+    if base_address.into_entry_hash().ne(&Some(sender_path_hash)) {
+        return Ok(ValidateCallbackResult::Invalid(String::from(
+            "The base of a SenderToMessages link must be the path of the provenance of the message",
+        )));
+    }
+
     Ok(ValidateCallbackResult::Valid)
 }
 
+/// Sealed messages don't reveal their sender, so the providers that store them count them
+/// towards the quota of the agent that called them with links to random targets,
+/// which can't be traced back to the messages
+pub fn validate_create_link_sender_to_sealed_messages(
+    action: CreateLink,
+    _base_address: AnyLinkableHash,
+    _target_address: AnyLinkableHash,
+    _tag: LinkTag,
+) -> ExternResult<ValidateCallbackResult> {
+    if is_progenitor(&action.author)? {
+        return Ok(ValidateCallbackResult::Valid);
+    }
+    let Some(delegation) = get_provider_delegation(&action.author, &action.prev_action)? else {
+        return Ok(ValidateCallbackResult::Invalid(String::from(
+            "Only progenitors or agents delegated by them can create SenderToSealedMessages links",
+        )));
+    };
+    validate_provider_delegation(&action.author, &delegation, action.timestamp)
+}
+
+pub fn validate_create_link_recipient_to_messages(
+    _action: CreateLink,
+    base_address: AnyLinkableHash,
+    target_address: AnyLinkableHash,
+    tag: LinkTag,
+) -> ExternResult<ValidateCallbackResult> {
+    let entry_hash = target_address
+        .into_entry_hash()
+        .ok_or(wasm_error!(WasmErrorInner::Guest(
+            "No action hash associated with link".to_string()
+        )))?;
+    let entry = must_get_entry(entry_hash)?;
+    let message = crate::MessageWithProvenance::try_from(entry.content)?;
+
+    let Some(base_entry_hash) = base_address.into_entry_hash() else {
+        return Ok(ValidateCallbackResult::Invalid(String::from(
+            "The base of a RecipientToMessages link must be an agent path",
+        )));
+    };
+
+    let recipients_paths = message
+        .message
+        .recipients
+        .iter()
+        .map(|(agent, contents)| {
+            Ok((
+                agent_path(agent)?.path_entry_hash()?,
+                RecipientLinkTag::new(&message.message, contents.clone()),
+            ))
+        })
+        .collect::<ExternResult<Vec<(EntryHash, RecipientLinkTag)>>>()?;
+
+    Ok(validate_recipient_link(
+        &base_entry_hash,
+        &tag,
+        recipients_paths,
+    ))
+}
+
+/// Checks that the link goes from the path of one of the recipients of the message,
+/// and that its tag is exactly the one for that recipient
+pub fn validate_recipient_link(
+    base_entry_hash: &EntryHash,
+    tag: &LinkTag,
+    recipients_paths: Vec<(EntryHash, RecipientLinkTag)>,
+) -> ValidateCallbackResult {
+    let Some((_, expected_tag)) = recipients_paths
+        .into_iter()
+        .find(|(path_hash, _)| path_hash.eq(base_entry_hash))
+    else {
+        return ValidateCallbackResult::Invalid(String::from(
+            "The base of a RecipientToMessages link must be the path of one of the recipients of the message",
+        ));
+    };
+
+    if RecipientLinkTag::try_from(tag).ok().ne(&Some(expected_tag)) {
+        return ValidateCallbackResult::Invalid(String::from(
+            "The tag of a RecipientToMessages link must be the contents and the size of the message for its recipient",
+        ));
+    }
+
+    ValidateCallbackResult::Valid
+}
+
+/// Only the service providers, which delete the links when the recipient acknowledges
+/// the messages through the gateway, can delete them
+///
+/// Trust assumption: the recipient doesn't sign its acknowledgements, so nothing ties a delete
+/// to one of them and any provider delegated by a progenitor can delete any mailbox link at any
+/// time. Recipients must trust every delegated provider not to drop their messages before
+/// delivering them, and progenitors should only delegate providers that they trust with that
 pub fn validate_delete_link_recipient_to_messages(
-    _action: DeleteLink,
+    action: DeleteLink,
     _original_action: CreateLink,
     _base: AnyLinkableHash,
     _target: AnyLinkableHash,
     _tag: LinkTag,
 ) -> ExternResult<ValidateCallbackResult> {
-    Ok(ValidateCallbackResult::Valid)
+    if is_progenitor(&action.author)? {
+        return Ok(ValidateCallbackResult::Valid);
+    }
+    let Some(delegation) = get_provider_delegation(&action.author, &action.prev_action)? else {
+        return Ok(ValidateCallbackResult::Invalid(String::from(
+            "Only progenitors or agents delegated by them can delete RecipientToMessages links",
+        )));
+    };
+    validate_provider_delegation(&action.author, &delegation, action.timestamp)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fake_entry_hash(byte: u8) -> EntryHash {
+        EntryHash::from_raw_36(vec![byte; 36])
+    }
+
+    fn recipient_tag(contents: AgentSpecificContents) -> RecipientLinkTag {
+        RecipientLinkTag {
+            contents,
+            message_size: 10,
+            expires_at: Timestamp::from_micros(1_000),
+        }
+    }
+
+    fn link_tag(tag: RecipientLinkTag) -> LinkTag {
+        LinkTag::try_from(tag).unwrap()
+    }
+
+    fn recipients_paths() -> Vec<(EntryHash, RecipientLinkTag)> {
+        vec![
+            (fake_entry_hash(1), recipient_tag(vec![1, 1, 1])),
+            (fake_entry_hash(2), recipient_tag(vec![2, 2, 2])),
+        ]
+    }
+
+    #[test]
+    fn link_from_recipient_path_with_its_contents_is_valid() {
+        let result = validate_recipient_link(
+            &fake_entry_hash(2),
+            &link_tag(recipient_tag(vec![2, 2, 2])),
+            recipients_paths(),
+        );
+        assert!(matches!(result, ValidateCallbackResult::Valid));
+    }
+
+    #[test]
+    fn link_from_non_recipient_path_is_invalid() {
+        let result = validate_recipient_link(
+            &fake_entry_hash(3),
+            &link_tag(recipient_tag(vec![1, 1, 1])),
+            recipients_paths(),
+        );
+        assert!(matches!(result, ValidateCallbackResult::Invalid(_)));
+    }
+
+    #[test]
+    fn link_with_contents_of_another_recipient_is_invalid() {
+        let result = validate_recipient_link(
+            &fake_entry_hash(1),
+            &link_tag(recipient_tag(vec![2, 2, 2])),
+            recipients_paths(),
+        );
+        assert!(matches!(result, ValidateCallbackResult::Invalid(_)));
+    }
+
+    #[test]
+    fn link_with_tampered_message_size_is_invalid() {
+        let mut tag = recipient_tag(vec![1, 1, 1]);
+        tag.message_size = 0;
+        let result =
+            validate_recipient_link(&fake_entry_hash(1), &link_tag(tag), recipients_paths());
+        assert!(matches!(result, ValidateCallbackResult::Invalid(_)));
+    }
+
+    #[test]
+    fn link_with_raw_contents_tag_is_invalid() {
+        let result = validate_recipient_link(
+            &fake_entry_hash(1),
+            &LinkTag::new(vec![1, 1, 1]),
+            recipients_paths(),
+        );
+        assert!(matches!(result, ValidateCallbackResult::Invalid(_)));
+    }
+
+    #[test]
+    fn mailbox_prefixes_are_two_hex_digits() {
+        let mailbox_id = ExternalHash::from_raw_32(vec![0xab; 32]);
+        assert_eq!(mailbox_prefix(&mailbox_id), "ab");
+        assert!(is_mailbox_prefix(&mailbox_prefix(&mailbox_id)));
+    }
+
+    #[test]
+    fn mailbox_ids_are_not_prefixes() {
+        let mailbox_id = ExternalHash::from_raw_32(vec![0xab; 32]);
+        assert!(!is_mailbox_prefix(&mailbox_id.to_string()));
+        assert!(!is_mailbox_prefix("zz"));
+    }
+
+    #[test]
+    fn link_with_tampered_contents_is_invalid() {
+        let result = validate_recipient_link(
+            &fake_entry_hash(1),
+            &link_tag(recipient_tag(vec![1, 1])),
+            recipients_paths(),
+        );
+        assert!(matches!(result, ValidateCallbackResult::Invalid(_)));
+    }
 }
@@ -0,0 +1,52 @@
+use hdi::prelude::*;
+
+use crate::delegation::{get_joining_delegation, validate_provider_delegation};
+
+/// Maximum number of actions that a provider can commit after its last `ProviderProof`
+/// for them to be validated as the actions of a delegated provider
+pub const PROVIDER_PROOF_WINDOW: usize = 300;
+
+/// Points to the action in which a provider joined the safehold DHT with its delegation
+///
+/// Providers commit one before each batch of deletes and delivery receipts, so that validating them
+/// only fetches the last actions of their chain and their `AgentValidationPkg`, however long their chain is
+#[derive(Clone, PartialEq)]
+#[hdk_entry_helper]
+pub struct ProviderProof {
+    pub agent_validation_pkg: ActionHash,
+}
+
+pub fn validate_create_provider_proof(
+    action: EntryCreationAction,
+    provider_proof: ProviderProof,
+) -> ExternResult<ValidateCallbackResult> {
+    let Some(delegation) =
+        get_joining_delegation(action.author(), &provider_proof.agent_validation_pkg)?
+    else {
+        return Ok(ValidateCallbackResult::Invalid(String::from(
+            "A ProviderProof must point to the action in which its author joined with a delegation",
+        )));
+    };
+    validate_provider_delegation(action.author(), &delegation, *action.timestamp())
+}
+
+pub fn validate_update_provider_proof(
+    _action: Update,
+    _provider_proof: ProviderProof,
+    _original_action: EntryCreationAction,
+    _original_provider_proof: ProviderProof,
+) -> ExternResult<ValidateCallbackResult> {
+    Ok(ValidateCallbackResult::Invalid(
+        "Provider proofs cannot be updated".to_string(),
+    ))
+}
+
+pub fn validate_delete_provider_proof(
+    _action: Delete,
+    _original_action: EntryCreationAction,
+    _original_provider_proof: ProviderProof,
+) -> ExternResult<ValidateCallbackResult> {
+    Ok(ValidateCallbackResult::Invalid(
+        "Provider proofs cannot be deleted".to_string(),
+    ))
+}
@@ -1,13 +1,24 @@
 use hc_zome_traits::*;
 use hdk::prelude::*;
+use notifications::notify_recipients;
+use quotas::{check_quotas, record_sealed_messages};
 use safehold_service_trait::*;
 use safehold_types::*;
 
+mod notifications;
+mod quotas;
+
 #[hdk_extern]
 pub fn init(_: ()) -> ExternResult<InitCallbackResult> {
     let mut fns: BTreeSet<GrantedFunction> = BTreeSet::new();
     fns.insert((zome_info()?.name, FunctionName::from("get_messages")));
+    fns.insert((zome_info()?.name, FunctionName::from("get_messages_page")));
     fns.insert((zome_info()?.name, FunctionName::from("store_messages")));
+    fns.insert((zome_info()?.name, FunctionName::from("ack_messages")));
+    fns.insert((
+        zome_info()?.name,
+        FunctionName::from("get_delivery_receipts"),
+    ));
     let functions = GrantedFunctions::Listed(fns);
     let cap_grant = ZomeCallCapGrant {
         tag: String::from("store_and_get_messages"),
@@ -16,6 +27,21 @@ pub fn init(_: ()) -> ExternResult<InitCallbackResult> {
     };
     create_cap_grant(cap_grant)?;
 
+    let mut fns: BTreeSet<GrantedFunction> = BTreeSet::new();
+    fns.insert((zome_info()?.name, FunctionName::from("recv_remote_signal")));
+    let functions = GrantedFunctions::Listed(fns);
+    let cap_grant = ZomeCallCapGrant {
+        tag: String::from("recv_remote_signal"),
+        access: CapAccess::Unrestricted,
+        functions,
+    };
+    create_cap_grant(cap_grant)?;
+
+    // End users also install the gateway to receive notifications, but only providers run the proxy role
+    if !is_provider()? {
+        return Ok(InitCallbackResult::Pass);
+    }
+
     let response = call(
         CallTargetCell::Local,
         ZomeName::from("service_providers"),
@@ -32,6 +58,17 @@ pub fn init(_: ()) -> ExternResult<InitCallbackResult> {
     Ok(InitCallbackResult::Pass)
 }
 
+fn is_provider() -> ExternResult<bool> {
+    let response = call(
+        CallTargetCell::OtherRole(RoleName::from("proxy")),
+        ZomeName::from("proxy"),
+        FunctionName::from("query_proxied_dna"),
+        None,
+        (),
+    );
+    Ok(matches!(response, Ok(ZomeCallResponse::Ok(_))))
+}
+
 #[implemented_zome_traits]
 pub enum ZomeTraits {
     SafeholdService(SafeholdGateway),
@@ -41,24 +78,45 @@ pub struct SafeholdGateway;
 
 #[implement_zome_trait_as_externs]
 impl SafeholdService for SafeholdGateway {
-    fn store_messages(messages: Vec<MessageWithProvenance>) -> ExternResult<()> {
+    fn store_messages(mut messages: Vec<MessageWithProvenance>) -> ExternResult<()> {
         let sender = call_info()?.provenance;
 
-        for message in &messages {
-            if message.provenance.ne(&sender) {
-                return Err(wasm_error!(
-                    "Message provenance is not the caller of store_messages."
-                ));
-            }
+        // Messages count towards the quota of the sender they are linked from
+        let mut messages_by_sender: BTreeMap<AgentPubKey, Vec<MessageWithProvenance>> =
+            BTreeMap::new();
 
-            // For now this check is redundant because of the previous check
+        for message in &mut messages {
+            check_message_signature(message)?;
+            // The message is stored as its provenance signed it, with the same hash
+            let delegation = message.delegation.take();
+
+            // The provenance of sealed messages is an ephemeral key, so they count towards
+            // the quota of the caller, which is recorded without linking it to them once they are stored
             //
-            // let valid =
-            //     verify_signature(message.provenance.clone(), message.signature.clone(), &message.message)?;
+            // This provider still sees who the caller is: sealed messages only hide their sender
+            // from the agents reading the safehold DHT, not from the provider that stores them
+            if message.message.sealed_sender {
+                messages_by_sender
+                    .entry(sender.clone())
+                    .or_default()
+                    .push(message.clone());
+                continue;
+            }
 
-            // if !valid {
-            //     return Err(wasm_error!("Invalid signature."));
-            // }
+            if message.provenance.ne(&sender) {
+                let Some(delegation) = delegation else {
+                    return Err(SafeholdError::ProvenanceMismatch.into());
+                };
+                check_delegation(&delegation, &message.provenance, &sender)?;
+            }
+            messages_by_sender
+                .entry(message.provenance.clone())
+                .or_default()
+                .push(message.clone());
+        }
+
+        for (sender, messages) in messages_by_sender {
+            check_quotas(&sender, &messages)?;
         }
 
         let proxied_call = ProxiedCall {
@@ -73,34 +131,207 @@ impl SafeholdService for SafeholdGateway {
             FunctionName::from("proxied_call"),
             None,
             proxied_call,
-        )?;
+        )
+        .map_err(SafeholdError::from_wasm_error)?;
         let ZomeCallResponse::Ok(_) = response else {
-            return Err(wasm_error!("Failed to store message: {response:?}"));
+            return Err(call_response_error("Failed to store message", response).into());
         };
+
+        let sealed_count = messages
+            .iter()
+            .filter(|message| message.message.sealed_sender)
+            .count();
+        if sealed_count > 0 {
+            record_sealed_messages(&sender, sealed_count)?;
+        }
+
+        notify_recipients(&messages)?;
+
         Ok(())
     }
 
     fn get_messages(_: ()) -> ExternResult<Vec<MessageOutput>> {
         let agent = call_info()?.provenance;
 
-        let proxied_call = ProxiedCall {
-            zome_name: ZomeName::from("safehold"),
-            fn_name: FunctionName::from("get_messages_for_recipient"),
-            payload: ExternIO::encode(agent).map_err(|err| wasm_error!(err))?,
+        let results: Vec<Vec<MessageOutput>> =
+            call_all_safehold_cells("get_messages_for_recipient", agent)?;
+
+        // Messages migrated to the new epoch are also still in the old one
+        let mut message_hashes: BTreeSet<EntryHash> = BTreeSet::new();
+        let messages = results
+            .into_iter()
+            .flatten()
+            .filter(|message| message_hashes.insert(message.message_hash.clone()))
+            .collect();
+        Ok(messages)
+    }
+
+    fn get_messages_page(input: GetMessagesPageInput) -> ExternResult<MessagesPage> {
+        let agent = call_info()?.provenance;
+
+        // Each active safehold cell gets an equal share of the budget of the page
+        let cells_count = query_safehold_cells_count()?.max(1);
+        let page = GetMessagesPageInput {
+            cursor: input.cursor,
+            max_count: (input.max_count / cells_count).max(1),
+            max_bytes: input.max_bytes / cells_count,
         };
 
-        let response = call(
-            CallTargetCell::OtherRole(RoleName::from("proxy")),
-            ZomeName::from("proxy"),
-            FunctionName::from("proxied_call"),
-            None,
-            proxied_call,
+        let pages: Vec<MessagesPage> = call_all_safehold_cells(
+            "get_messages_page_for_recipient",
+            GetMessagesPageForRecipientInput {
+                recipient: agent,
+                page,
+            },
         )?;
-        let ZomeCallResponse::Ok(result) = response else {
-            return Err(wasm_error!("Failed to get messages: {:?}"));
-        };
-        let result: ExternIO = result.decode().map_err(|err| wasm_error!("{}", err))?;
-        let messages: Vec<MessageOutput> = result.decode().map_err(|err| wasm_error!("{}", err))?;
-        Ok(messages)
+
+        // Continue from the cell that is the furthest behind, which can return again
+        // some messages from the other cells, but never skips any
+        let next_cursor = pages
+            .iter()
+            .filter_map(|page| page.next_cursor.clone())
+            .min_by(|a, b| {
+                (a.timestamp, &a.create_link_hash).cmp(&(b.timestamp, &b.create_link_hash))
+            });
+
+        let mut message_hashes: BTreeSet<EntryHash> = BTreeSet::new();
+        let messages = pages
+            .into_iter()
+            .flat_map(|page| page.messages)
+            .filter(|message| message_hashes.insert(message.message_hash.clone()))
+            .collect();
+
+        Ok(MessagesPage {
+            messages,
+            next_cursor,
+        })
+    }
+
+    fn ack_messages(message_hashes: Vec<EntryHash>) -> ExternResult<()> {
+        let agent = call_info()?.provenance;
+
+        let _results: Vec<()> = call_all_safehold_cells(
+            "ack_messages_for_recipient",
+            AckMessagesInput {
+                recipient: agent,
+                message_hashes,
+            },
+        )?;
+        Ok(())
+    }
+
+    fn get_delivery_receipts(
+        message_hashes: Vec<EntryHash>,
+    ) -> ExternResult<Vec<SignedDeliveryReceipt>> {
+        let agent = call_info()?.provenance;
+
+        let results: Vec<Vec<SignedDeliveryReceipt>> = call_all_safehold_cells(
+            "get_delivery_receipts_for_sender",
+            GetDeliveryReceiptsInput {
+                sender: agent,
+                message_hashes,
+            },
+        )?;
+
+        let mut receipts: Vec<SignedDeliveryReceipt> = vec![];
+        for receipt in results.into_iter().flatten() {
+            if !receipts.contains(&receipt) {
+                receipts.push(receipt);
+            }
+        }
+        Ok(receipts)
+    }
+}
+
+fn check_message_signature(message: &MessageWithProvenance) -> ExternResult<()> {
+    let bytes =
+        SerializedBytes::try_from(message.message.clone()).map_err(|err| wasm_error!(err))?;
+    let hash = hash_blake2b(bytes.bytes().to_vec(), 32)?;
+    let valid = verify_signature(message.provenance.clone(), message.signature.clone(), &hash)?;
+    if !valid {
+        return Err(SafeholdError::InvalidSignature.into());
+    }
+    Ok(())
+}
+
+/// Checks that the delegation was signed by the provenance of the message for the caller,
+/// as a sender delegation for this services DNA that hasn't expired
+fn check_delegation(
+    delegation: &SenderDelegation,
+    provenance: &AgentPubKey,
+    caller: &AgentPubKey,
+) -> ExternResult<()> {
+    if delegation.delegator.ne(provenance) || delegation.delegate.ne(caller) {
+        return Err(SafeholdError::InvalidDelegation.into());
+    }
+    if delegation.services_dna_hash.ne(&dna_info()?.hash) || delegation.is_expired(sys_time()?) {
+        return Err(SafeholdError::InvalidDelegation.into());
+    }
+    let valid = verify_signature_raw(
+        delegation.delegator.clone(),
+        delegation.signature.clone(),
+        SenderDelegation::signed_data(
+            &delegation.delegate,
+            &delegation.services_dna_hash,
+            delegation.expires_at,
+        ),
+    )?;
+    if !valid {
+        return Err(SafeholdError::InvalidDelegation.into());
+    }
+    Ok(())
+}
+
+fn query_safehold_cells_count() -> ExternResult<usize> {
+    let response = call(
+        CallTargetCell::OtherRole(RoleName::from("proxy")),
+        ZomeName::from("proxy"),
+        FunctionName::from("query_proxied_dnas"),
+        None,
+        (),
+    )?;
+    let ZomeCallResponse::Ok(result) = response else {
+        return Err(wasm_error!("Failed to query safehold cells: {response:?}"));
+    };
+    let dna_hashes: Vec<DnaHash> = result.decode().map_err(|err| wasm_error!("{}", err))?;
+    Ok(dna_hashes.len())
+}
+
+/// Makes the call in all the active safehold cells, which are the cells for the old
+/// and the new epoch while they overlap, and returns their results the newest first
+fn call_all_safehold_cells<I, O>(fn_name: &str, payload: I) -> ExternResult<Vec<O>>
+where
+    I: serde::Serialize + std::fmt::Debug,
+    O: serde::de::DeserializeOwned + std::fmt::Debug,
+{
+    let proxied_call = ProxiedCall {
+        zome_name: ZomeName::from("safehold"),
+        fn_name: FunctionName::from(fn_name),
+        payload: ExternIO::encode(payload).map_err(|err| wasm_error!(err))?,
+    };
+
+    let response = call(
+        CallTargetCell::OtherRole(RoleName::from("proxy")),
+        ZomeName::from("proxy"),
+        FunctionName::from("proxied_call_all"),
+        None,
+        proxied_call,
+    )
+    .map_err(SafeholdError::from_wasm_error)?;
+    let ZomeCallResponse::Ok(result) = response else {
+        return Err(call_response_error(&format!("Failed to call {fn_name}"), response).into());
+    };
+    let results: Vec<ExternIO> = result.decode().map_err(|err| wasm_error!("{}", err))?;
+    results
+        .into_iter()
+        .map(|result| result.decode().map_err(|err| wasm_error!("{}", err)))
+        .collect()
+}
+
+/// Keeps the `SafeholdError` returned by the proxied safehold cell, if any
+pub fn call_response_error(context: &str, response: ZomeCallResponse) -> SafeholdError {
+    match response {
+        ZomeCallResponse::NetworkError(err) => SafeholdError::Network(err),
+        response => SafeholdError::from_message(format!("{context}: {response:?}")),
     }
 }
@@ -0,0 +1,45 @@
+use hdk::prelude::*;
+use safehold_service_trait::SAFEHOLD_SERVICE_HASH;
+use safehold_types::*;
+
+/// Notifies the recipients of the given messages that are currently online that they have new messages
+///
+/// The signals are sent without waiting for the recipients, so offline recipients don't delay the caller
+pub fn notify_recipients(messages: &[MessageWithProvenance]) -> ExternResult<()> {
+    let recipients: BTreeSet<AgentPubKey> = messages
+        .iter()
+        .flat_map(|m| m.message.recipients.keys().cloned())
+        .collect();
+
+    send_remote_signal(
+        SafeholdNotification::NewMessages,
+        recipients.into_iter().collect(),
+    )
+}
+
+/// Received in the services cell of the recipients, only notifications from safehold providers
+/// are forwarded to the UI as signals
+#[hdk_extern]
+pub fn recv_remote_signal(notification: SafeholdNotification) -> ExternResult<()> {
+    let provider = call_info()?.provenance;
+
+    let response = call(
+        CallTargetCell::Local,
+        ZomeName::from("service_providers"),
+        "get_providers_for_service".into(),
+        None,
+        SAFEHOLD_SERVICE_HASH,
+    )?;
+    let ZomeCallResponse::Ok(result) = response else {
+        return Err(wasm_error!("Failed to get providers: {response:?}"));
+    };
+    let providers: Vec<AgentPubKey> = result.decode().map_err(|err| wasm_error!(err))?;
+
+    if !providers.contains(&provider) {
+        return Err(wasm_error!(
+            "Received safehold notification from an agent that is not a safehold provider"
+        ));
+    }
+
+    emit_signal(notification)
+}
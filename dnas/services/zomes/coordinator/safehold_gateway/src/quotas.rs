@@ -0,0 +1,110 @@
+use hdk::prelude::*;
+use safehold_types::*;
+
+use crate::call_response_error;
+
+/// Checks that storing the given messages would not exceed the quotas set in the properties of the safehold DNA
+pub fn check_quotas(sender: &AgentPubKey, messages: &[MessageWithProvenance]) -> ExternResult<()> {
+    let recipients: BTreeSet<AgentPubKey> = messages
+        .iter()
+        .flat_map(|m| m.message.recipients.keys().cloned())
+        .collect();
+
+    let proxied_call = ProxiedCall {
+        zome_name: ZomeName::from("safehold"),
+        fn_name: FunctionName::from("get_quotas_usage"),
+        payload: ExternIO::encode(QuotasUsageInput {
+            sender: sender.clone(),
+            recipients,
+        })
+        .map_err(|err| wasm_error!(err))?,
+    };
+
+    let response = call(
+        CallTargetCell::OtherRole(RoleName::from("proxy")),
+        ZomeName::from("proxy"),
+        FunctionName::from("proxied_call"),
+        None,
+        proxied_call,
+    )
+    .map_err(SafeholdError::from_wasm_error)?;
+    let ZomeCallResponse::Ok(result) = response else {
+        return Err(call_response_error("Failed to get quotas usage", response).into());
+    };
+    let result: ExternIO = result.decode().map_err(|err| wasm_error!("{}", err))?;
+    let usage: QuotasUsage = result.decode().map_err(|err| wasm_error!("{}", err))?;
+
+    if let Err(quota_exceeded) = check_usage(&usage, messages) {
+        return Err(SafeholdError::QuotaExceeded(quota_exceeded).into());
+    }
+
+    Ok(())
+}
+
+fn check_usage(
+    usage: &QuotasUsage,
+    messages: &[MessageWithProvenance],
+) -> Result<(), QuotaExceeded> {
+    let quotas = &usage.quotas;
+    for message in messages {
+        if message.message.recipients.len() > quotas.max_recipients_per_message {
+            return Err(QuotaExceeded::TooManyRecipients {
+                max: quotas.max_recipients_per_message,
+                recipients: message.message.recipients.len(),
+            });
+        }
+    }
+
+    let mut mailbox_bytes = usage.mailbox_bytes.clone();
+    for message in messages {
+        for recipient in message.message.recipients.keys() {
+            let bytes = mailbox_bytes.entry(recipient.clone()).or_default();
+            *bytes += message.message.size_for_recipient(recipient);
+
+            if *bytes > quotas.max_mailbox_bytes {
+                return Err(QuotaExceeded::MailboxFull {
+                    recipient: recipient.clone(),
+                    max_bytes: quotas.max_mailbox_bytes,
+                });
+            }
+        }
+    }
+
+    let sender_messages = usage.sender_messages_in_epoch + messages.len();
+    if sender_messages > quotas.max_messages_per_sender_per_epoch {
+        return Err(QuotaExceeded::TooManyMessagesInEpoch {
+            max: quotas.max_messages_per_sender_per_epoch,
+            retry_at: usage.epoch_ends_at,
+        });
+    }
+
+    Ok(())
+}
+
+/// Counts the sealed messages that the caller stored towards its quota in the later calls,
+/// since they are not linked from its sender path like the rest of its messages
+pub fn record_sealed_messages(sender: &AgentPubKey, count: usize) -> ExternResult<()> {
+    let proxied_call = ProxiedCall {
+        zome_name: ZomeName::from("safehold"),
+        fn_name: FunctionName::from("record_sealed_messages"),
+        payload: ExternIO::encode(RecordSealedMessagesInput {
+            sender: sender.clone(),
+            count,
+        })
+        .map_err(|err| wasm_error!(err))?,
+    };
+
+    let response = call(
+        CallTargetCell::OtherRole(RoleName::from("proxy")),
+        ZomeName::from("proxy"),
+        FunctionName::from("proxied_call"),
+        None,
+        proxied_call,
+    )
+    .map_err(SafeholdError::from_wasm_error)?;
+    let ZomeCallResponse::Ok(_) = response else {
+        return Err(call_response_error("Failed to record sealed messages", response).into());
+    };
+
+    Ok(())
+}